@@ -0,0 +1,53 @@
+//! Scaling of the parallel sort with the number of threads.
+//!
+//! Run once as is and once with `--features locked-permutation` to compare the lock-free block
+//! permutation against one that locks the bucket for every block access:
+//!
+//! ```text
+//! cargo bench --bench permutation -- --save-baseline lock-free
+//! cargo bench --bench permutation --features locked-permutation -- --baseline lock-free
+//! ```
+#[allow(dead_code)]
+mod distributions;
+
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use distributions::{exponential, uniform};
+use rayon::ThreadPoolBuilder;
+
+const LEN: usize = 1 << 24;
+
+fn permutation_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("permutation");
+    let max_threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let threads = (0..)
+        .map(|i| 1 << i)
+        .take_while(|&t| t < max_threads)
+        .chain([max_threads]);
+    for num_threads in threads {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .build()
+            .unwrap();
+        // Most exponentially distributed keys fall into few buckets, so the threads compete for
+        // the same bucket pointers
+        let distributions: [fn(usize) -> Vec<u32>; 2] = [uniform, exponential];
+        for (d, d_name) in distributions.iter().zip(["uniform", "exponential"]) {
+            group.bench_function(BenchmarkId::new(d_name, num_threads), |b| {
+                b.iter_batched_ref(
+                    || d(LEN),
+                    |v| pool.install(|| ips4o_rs::sort_par(v)),
+                    BatchSize::LargeInput,
+                )
+            });
+        }
+    }
+}
+
+criterion_group!(
+    name = benches;
+    config = Criterion::default().warm_up_time(Duration::from_secs(1)).sample_size(10);
+    targets = permutation_bench,
+);
+criterion_main!(benches);
@@ -0,0 +1,14 @@
+/// Sorts `v` using binary insertion sort, which is stable.
+///
+/// Every element is inserted behind all elements it is not less than, so equal elements keep
+/// their order. Elements are only moved with rotations, which never call the comparison function.
+pub(crate) fn binary_insertion_sort<T, F>(v: &mut [T], is_less: &F)
+where
+    F: Fn(&T, &T) -> bool,
+{
+    for i in 1..v.len() {
+        let (sorted, rest) = v.split_at(i);
+        let pos = sorted.partition_point(|x| !is_less(&rest[0], x));
+        v[pos..=i].rotate_right(1);
+    }
+}
@@ -0,0 +1,59 @@
+/// Sorts `v` using heapsort, which is *O*(*n* \* log(*n*)) worst-case.
+///
+/// Only uses bounds checked swaps, so it terminates and leaves a permutation of `v` behind for
+/// any comparison function. Used as a fallback if partitioning makes no progress.
+pub(crate) fn heapsort<T, F>(v: &mut [T], is_less: &F)
+where
+    F: Fn(&T, &T) -> bool,
+{
+    let len = v.len();
+    heapsort_by_index(
+        v,
+        len,
+        |v, a, b| is_less(&v[a], &v[b]),
+        |v, a, b| v.swap(a, b),
+    );
+}
+
+/// Like [heapsort], but compares and swaps the first `len` elements of `v` by their indices, so
+/// it also sorts elements spread over several slices.
+pub(crate) fn heapsort_by_index<S, F, W>(v: &mut S, len: usize, is_less: F, swap: W)
+where
+    S: ?Sized,
+    F: Fn(&S, usize, usize) -> bool,
+    W: Fn(&mut S, usize, usize),
+{
+    // This binary heap respects the invariant `parent >= child`.
+    let sift_down = |v: &mut S, len: usize, mut node: usize| loop {
+        // Children of `node`.
+        let mut child = 2 * node + 1;
+        if child >= len {
+            break;
+        }
+
+        // Choose the greater child.
+        if child + 1 < len && is_less(v, child, child + 1) {
+            child += 1;
+        }
+
+        // Stop if the invariant holds at `node`.
+        if !is_less(v, node, child) {
+            break;
+        }
+
+        // Swap `node` with the greater child, move one step down, and continue sifting.
+        swap(v, node, child);
+        node = child;
+    };
+
+    // Build the heap in linear time.
+    for i in (0..len / 2).rev() {
+        sift_down(v, len, i);
+    }
+
+    // Pop maximal elements from the heap.
+    for i in (1..len).rev() {
+        swap(v, 0, i);
+        sift_down(v, i, 0);
+    }
+}
@@ -1,5 +1,7 @@
 use crate::{is_less_to_compare, Less};
 
+pub(crate) mod binary_insertion_sort;
+pub(crate) mod heapsort;
 pub(crate) mod insertion_sort;
 
 pub(crate) fn sort_simple_cases<T, F>(v: &mut [T], is_less: &F) -> bool
@@ -36,3 +38,12 @@ where
     insertion_sort::insertion_sort(v, is_less);
     debug_assert!(v.is_sorted_by(is_less_to_compare!(is_less)));
 }
+
+/// Like [base_case_sort], but keeps equal elements in their order
+pub(crate) fn stable_base_case_sort<T, F>(v: &mut [T], is_less: &F)
+where
+    F: Less<T>,
+{
+    binary_insertion_sort::binary_insertion_sort(v, is_less);
+    debug_assert!(v.is_sorted_by(is_less_to_compare!(is_less)));
+}
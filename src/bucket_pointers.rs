@@ -1,8 +1,17 @@
-use std::{cmp::max, fmt::Debug};
+#[cfg(not(feature = "locked-permutation"))]
+use std::marker::PhantomData;
+#[cfg(feature = "locked-permutation")]
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::{
+    cmp::max,
+    fmt::Debug,
+    hint,
+    sync::atomic::{self, AtomicBool, AtomicUsize},
+};
 
-use portable_atomic::AtomicU128;
+use portable_atomic::{AtomicU128, AtomicU64};
 
-use crate::constants::{BLOCK_SIZE, MAX_BUCKETS};
+use crate::constants::MAX_BUCKETS;
 
 pub(crate) type BucketPointers = [BucketPointer; MAX_BUCKETS]; // maybe replace with newtype
 
@@ -74,60 +83,257 @@ pub(crate) type BucketPointers = [BucketPointer; MAX_BUCKETS]; // maybe replace
 //     }
 // }
 
+/// How the write and read pointers of the buckets are stored during block permutation.
+///
+/// Threads claim blocks by changing both pointers of a bucket together, which is cheapest if
+/// they fit into one atomic the platform supports natively.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AtomicPointers {
+    /// [AtomicPointers::U64] if the slice has less than 2^32 elements and the platform has
+    /// native 64-bit atomics, otherwise [AtomicPointers::U128] if the platform has a native
+    /// 128-bit compare-and-swap, otherwise [AtomicPointers::Locked].
+    #[default]
+    Auto,
+    /// Both pointers as 32-bit halves of one 64-bit atomic. Longer slices use
+    /// [AtomicPointers::U128] instead. Without native 64-bit atomics, every access takes a lock
+    /// shared with unrelated atomics.
+    U64,
+    /// Both pointers as 64-bit halves of one 128-bit atomic. Without a native 128-bit
+    /// compare-and-swap, every access takes a lock shared with unrelated atomics.
+    U128,
+    /// Two plain counters, which are only accessed under a spin lock of the bucket. This is not
+    /// lock-free, but works on every platform and never waits for threads in other buckets.
+    Locked,
+}
+
+impl AtomicPointers {
+    /// Returns the representation used for a slice of `len` elements
+    pub(crate) fn resolve(self, len: usize) -> Self {
+        let fits_u32 = u32::try_from(len).is_ok();
+        match self {
+            Self::Auto if fits_u32 && AtomicU64::is_lock_free() => Self::U64,
+            Self::Auto if AtomicU128::is_lock_free() => Self::U128,
+            Self::Auto => Self::Locked,
+            Self::U64 if !fits_u32 => Self::U128,
+            kind => kind,
+        }
+    }
+}
+
+/// Write and read pointer of a bucket during block permutation.
+///
+/// A thread claims a block to write or to read by changing both pointers together, see
+/// [AtomicPointers]. Blocks between write and read have not been permuted yet, blocks before
+/// write are finished, and blocks from read on were read into a swap buffer. `reading` counts
+/// the threads which decreased the read pointer but may not have finished moving the block out,
+/// see [BucketPointer::start_read].
 #[derive(Default)]
 pub(crate) struct BucketPointer {
-    data: AtomicU128,
+    pointers: Pointers,
+    reading: AtomicUsize,
+    #[cfg(feature = "locked-permutation")]
+    lock: Mutex<()>,
 }
 
-impl BucketPointer {
-    const SHIFT: u128 = 64;
-    const WRITE_MASK: u128 = -1_i64 as u128;
+/// Held while a block of the bucket is accessed in parallel block permutation.
+///
+/// Without the `locked-permutation` feature this is a no-op, with it every access of a block
+/// locks its bucket. The feature only exists to compare both in `benches/permutation.rs`.
+#[cfg(feature = "locked-permutation")]
+pub(crate) type BucketGuard<'a> = MutexGuard<'a, ()>;
+#[cfg(not(feature = "locked-permutation"))]
+pub(crate) struct BucketGuard<'a>(PhantomData<&'a ()>);
+
+enum Pointers {
+    U64(AtomicU64),
+    U128(AtomicU128),
+    Locked(LockedPointers),
+}
+
+impl Default for Pointers {
+    fn default() -> Self {
+        Self::U64(AtomicU64::new(0))
+    }
+}
+
+/// Both pointers of [AtomicPointers::Locked]
+#[derive(Default)]
+struct LockedPointers {
+    write: AtomicUsize,
+    read: AtomicUsize,
+    lock: AtomicBool,
+}
 
-    pub(crate) fn new(write: usize, read: usize) -> Self {
-        debug_assert_eq!(write % BLOCK_SIZE, 0);
-        debug_assert_eq!(read % BLOCK_SIZE, 0);
-        let data = ((read as u128) << Self::SHIFT) + write as u128;
-        let data = AtomicU128::new(data);
-        Self { data }
+impl LockedPointers {
+    /// Calls `f` with the write and read pointer while no other thread can change them.
+    ///
+    /// A reader and a writer meeting in the middle of the bucket must see each other's update,
+    /// otherwise both could take the block for an empty one. That cannot be guaranteed by two
+    /// independent atomic operations, so the pointers are only accessed under the lock.
+    fn locked<R>(&self, f: impl FnOnce(&AtomicUsize, &AtomicUsize) -> R) -> R {
+        while self
+            .lock
+            .compare_exchange_weak(
+                false,
+                true,
+                atomic::Ordering::Acquire,
+                atomic::Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            hint::spin_loop();
+        }
+        let result = f(&self.write, &self.read);
+        self.lock.store(false, atomic::Ordering::Release);
+        result
+    }
+}
+
+impl BucketPointer {
+    pub(crate) fn new(write: usize, read: usize, block_size: usize, kind: AtomicPointers) -> Self {
+        debug_assert_eq!(write % block_size, 0);
+        debug_assert_eq!(read % block_size, 0);
+        let pointers = match kind {
+            AtomicPointers::Auto => unreachable!("resolve() the kind first"),
+            AtomicPointers::U64 => Pointers::U64(AtomicU64::new(0)),
+            AtomicPointers::U128 => Pointers::U128(AtomicU128::new(0)),
+            AtomicPointers::Locked => Pointers::Locked(LockedPointers::default()),
+        };
+        let bp = Self {
+            pointers,
+            ..Default::default()
+        };
+        bp.set(write, read);
+        bp
     }
 
     pub(crate) fn set(&self, write: usize, read: usize) {
-        let val = ((read as u128) << Self::SHIFT) + write as u128;
-        self.data.store(val, portable_atomic::Ordering::Relaxed);
+        let relaxed = atomic::Ordering::Relaxed;
+        match &self.pointers {
+            Pointers::U64(data) => {
+                debug_assert!(u32::try_from(write.max(read)).is_ok());
+                data.store(((read as u64) << 32) + write as u64, relaxed);
+            }
+            Pointers::U128(data) => data.store(((read as u128) << 64) + write as u128, relaxed),
+            Pointers::Locked(pointers) => pointers.locked(|w, r| {
+                w.store(write, relaxed);
+                r.store(read, relaxed);
+            }),
+        }
+    }
+
+    fn unpack_u64(data: u64) -> (usize, usize) {
+        (data as u32 as usize, (data >> 32) as usize)
     }
 
-    fn write_read_from_u128(data: u128) -> (usize, usize) {
-        let read = data >> Self::SHIFT;
-        let write = data & Self::WRITE_MASK;
-        (write as usize, max(0, read as isize) as usize)
+    fn unpack_u128(data: u128) -> (usize, usize) {
+        let read = data >> 64;
+        let write = data as u64;
+        // A read pointer below zero, see dec_read()
+        (write as usize, max(0, read as i64) as usize)
     }
 
     pub(crate) fn fetch(&self) -> (usize, usize) {
-        let data = self.data.load(portable_atomic::Ordering::Relaxed);
-        Self::write_read_from_u128(data)
-    }
-
-    pub fn inc_write(&self) -> (usize, usize) {
-        let data = self
-            .data
-            .fetch_add(BLOCK_SIZE as u128, portable_atomic::Ordering::Relaxed);
-        let (write, read) = Self::write_read_from_u128(data);
-        (write + BLOCK_SIZE, read)
-    }
-
-    pub(crate) fn dec_read(&self) -> Result<(usize, usize), ()> {
-        let data = self.data.fetch_sub(
-            (BLOCK_SIZE as u128) << Self::SHIFT,
-            portable_atomic::Ordering::Relaxed,
-        );
-        let (write, mut read) = Self::write_read_from_u128(data);
-        if read >= BLOCK_SIZE {
-            read -= BLOCK_SIZE;
-            Ok((write, read))
+        let relaxed = atomic::Ordering::Relaxed;
+        match &self.pointers {
+            Pointers::U64(data) => Self::unpack_u64(data.load(relaxed)),
+            Pointers::U128(data) => Self::unpack_u128(data.load(relaxed)),
+            Pointers::Locked(pointers) => {
+                pointers.locked(|write, read| (write.load(relaxed), read.load(relaxed)))
+            }
+        }
+    }
+
+    /// Increments the write pointer by one block, unless it would move past `limit`.
+    /// Returns `None` if the bucket is already full.
+    pub fn inc_write(&self, limit: usize, block_size: usize) -> Option<(usize, usize)> {
+        let seq_cst = atomic::Ordering::SeqCst;
+        let (write, read) = match &self.pointers {
+            Pointers::U64(data) => Self::unpack_u64(
+                data.fetch_update(seq_cst, seq_cst, |data| {
+                    let (write, _read) = Self::unpack_u64(data);
+                    (write + block_size <= limit).then(|| data + block_size as u64)
+                })
+                .ok()?,
+            ),
+            Pointers::U128(data) => Self::unpack_u128(
+                data.fetch_update(seq_cst, seq_cst, |data| {
+                    let (write, _read) = Self::unpack_u128(data);
+                    (write + block_size <= limit).then(|| data + block_size as u128)
+                })
+                .ok()?,
+            ),
+            Pointers::Locked(pointers) => pointers.locked(|write, read| {
+                let relaxed = atomic::Ordering::Relaxed;
+                let old = write.load(relaxed);
+                (old + block_size <= limit).then(|| {
+                    write.store(old + block_size, relaxed);
+                    (old, read.load(relaxed))
+                })
+            })?,
+        };
+        Some((write + block_size, read))
+    }
+
+    pub(crate) fn dec_read(&self, block_size: usize) -> Result<(usize, usize), ()> {
+        let seq_cst = atomic::Ordering::SeqCst;
+        let (write, read) = match &self.pointers {
+            // The read pointer may be up to u32::MAX, so it cannot go below zero like below
+            Pointers::U64(data) => Self::unpack_u64(
+                data.fetch_update(seq_cst, seq_cst, |data| {
+                    let (_write, read) = Self::unpack_u64(data);
+                    (read >= block_size).then(|| data - ((block_size as u64) << 32))
+                })
+                .map_err(|_| ())?,
+            ),
+            // Wraps to a negative read pointer if the bucket has no blocks left
+            Pointers::U128(data) => {
+                Self::unpack_u128(data.fetch_sub((block_size as u128) << 64, seq_cst))
+            }
+            Pointers::Locked(pointers) => pointers.locked(|write, read| {
+                let relaxed = atomic::Ordering::Relaxed;
+                let old = read.load(relaxed);
+                read.store(old.saturating_sub(block_size), relaxed);
+                (write.load(relaxed), old)
+            }),
+        };
+        if read >= block_size {
+            Ok((write, read - block_size))
         } else {
             Err(())
         }
     }
+
+    /// Announces that this thread is about to decrease the read pointer and move the block out.
+    ///
+    /// A thread which then increases the write pointer past the read pointer writes into the
+    /// block that is being read, so it has to wait in [BucketPointer::wait_for_readers] until
+    /// [BucketPointer::stop_read] was called.
+    pub(crate) fn start_read(&self) {
+        self.reading.fetch_add(1, atomic::Ordering::SeqCst);
+    }
+
+    pub(crate) fn stop_read(&self) {
+        self.reading.fetch_sub(1, atomic::Ordering::SeqCst);
+    }
+
+    pub(crate) fn wait_for_readers(&self) {
+        while self.reading.load(atomic::Ordering::SeqCst) != 0 {
+            hint::spin_loop();
+        }
+    }
+
+    #[cfg(feature = "locked-permutation")]
+    pub(crate) fn lock(&self) -> BucketGuard<'_> {
+        // A poisoned lock only means that another thread panicked while comparing, the blocks
+        // are always in a consistent state while the lock is held
+        self.lock.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    #[cfg(not(feature = "locked-permutation"))]
+    pub(crate) fn lock(&self) -> BucketGuard<'_> {
+        BucketGuard(PhantomData)
+    }
 }
 
 impl Debug for BucketPointer {
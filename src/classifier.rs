@@ -1,21 +1,80 @@
-use std::{fmt::Debug, ops::Range};
+use std::{
+    mem::{self, MaybeUninit},
+    ops::Range,
+    ptr, slice,
+};
 
 use crate::{
-    constants::{BATCH_SIZE, BLOCK_SIZE, LOG_MAX_BUCKETS, MAX_BUCKETS},
+    constants::{BATCH_SIZE, LOG_MAX_BUCKETS, MAX_BUCKETS},
+    payload::Payload,
+    restore::HoleFiller,
     storage::BucketBuffers,
+    util::{debug_assertions::test_stripe_classification, move_from_slice},
     Less, Sortable,
 };
 
 const SPLITTERS_LEN: usize = 1 << LOG_MAX_BUCKETS;
 
-#[derive(Debug)]
-pub(crate) struct Classifier<'a, T, F>
+/// Assigns elements to buckets during block partitioning. Implemented by the comparison based
+/// [Classifier] and by the [RadixClassifier], which uses the bits of a key instead.
+///
+/// [RadixClassifier]: crate::radix::RadixClassifier
+pub(crate) trait Classify<T> {
+    fn classify_single_element(&self, val: &T) -> usize;
+
+    /// Moves the elements of `stripe` into full blocks at the start of the stripe, see
+    /// [classify_stripe]. Returns the number of elements written.
+    fn classify_locally<P: Payload>(
+        &self,
+        stripe: &mut [T],
+        rows: P,
+        buckets: &mut BucketBuffers<T>,
+        payload_buffers: &mut P::Buffers,
+        elements_per_bucket: &mut [usize; MAX_BUCKETS],
+        num_buckets: usize,
+    ) -> usize;
+}
+
+impl<'a, T, F> Classify<T> for Classifier<'a, T, F>
 where
+    T: Sortable,
     F: Less<T>,
 {
-    tree: [T; SPLITTERS_LEN],
-    splitters: [T; SPLITTERS_LEN],
+    fn classify_single_element(&self, val: &T) -> usize {
+        Classifier::classify_single_element(self, val)
+    }
+
+    fn classify_locally<P: Payload>(
+        &self,
+        stripe: &mut [T],
+        rows: P,
+        buckets: &mut BucketBuffers<T>,
+        payload_buffers: &mut P::Buffers,
+        elements_per_bucket: &mut [usize; MAX_BUCKETS],
+        num_buckets: usize,
+    ) -> usize {
+        Classifier::classify_locally(
+            self,
+            stripe,
+            rows,
+            buckets,
+            payload_buffers,
+            elements_per_bucket,
+            num_buckets,
+        )
+    }
+}
+
+/// Holds bitwise copies of the splitters, so that the classified slice can be modified freely.
+/// The copies are never dropped, they stay valid because the sort only moves elements around.
+/// Only the first `splitter_len` splitters (and tree nodes `1..splitter_len`, once built) are
+/// initialized.
+#[derive(Debug)]
+pub(crate) struct Classifier<'a, T, F> {
+    tree: [MaybeUninit<T>; SPLITTERS_LEN],
+    splitters: [MaybeUninit<T>; SPLITTERS_LEN],
     splitter_len: usize,
+    tree_built: bool,
     pub equal_buckets: bool,
     is_less: &'a F,
 }
@@ -26,27 +85,36 @@ where
     F: Less<T>,
 {
     pub(crate) fn new(is_less: &'a F) -> Self {
-        let splitters = core::array::from_fn(|_| T::default());
-        let tree = core::array::from_fn(|_| T::default());
+        // SAFETY: an array of MaybeUninit does not need initialization
+        let (splitters, tree) = unsafe {
+            (
+                MaybeUninit::uninit().assume_init(),
+                MaybeUninit::uninit().assume_init(),
+            )
+        };
         Self {
             splitters,
             tree,
             splitter_len: 0,
+            tree_built: false,
             is_less,
             equal_buckets: Default::default(),
         }
     }
 
     pub(crate) fn build(&mut self) {
+        debug_assert!(!self.tree_built);
         // increase size of tree by one, so it can be 1-indexed
         self.build_recurse(0..self.splitter_len - 1, 1);
+        self.tree_built = true;
     }
 
     fn build_recurse(&mut self, range: Range<usize>, pos: usize) {
         debug_assert!((range.len() + 1).is_power_of_two());
         if !range.is_empty() {
             let mid = range.start + range.len() / 2;
-            self.tree[pos] = self.splitters[mid].clone();
+            // SAFETY: see the comment on the struct
+            self.tree[pos].write(unsafe { ptr::read(&self.get_splitters()[mid]) });
 
             self.build_recurse(range.start..mid, pos * 2);
             self.build_recurse(mid + 1..range.end, pos * 2 + 1);
@@ -54,15 +122,41 @@ where
     }
 
     pub fn get_splitters(&self) -> &[T] {
-        &self.splitters[..self.splitter_len]
+        // SAFETY: the first splitter_len splitters are initialized in push_splitter()
+        unsafe { &*(&self.splitters[..self.splitter_len] as *const [MaybeUninit<T>] as *const [T]) }
     }
 
-    pub fn get_all_splitters_mut(&mut self) -> &mut [T; SPLITTERS_LEN] {
-        &mut self.splitters
+    /// Appends a copy of `splitter`, must not be called after [Self::build] without calling
+    /// [Self::clear] first
+    pub fn push_splitter(&mut self, splitter: &T) {
+        debug_assert!(!self.tree_built);
+        // SAFETY: see the comment on the struct
+        self.splitters[self.splitter_len].write(unsafe { ptr::read(splitter) });
+        self.splitter_len += 1;
     }
 
-    pub fn set_splitter_len(&mut self, splitter_len: usize) {
-        self.splitter_len = splitter_len;
+    /// Pads the splitters with copies of the last splitter, until there are `len` splitters
+    pub fn fill_splitters(&mut self, len: usize) {
+        debug_assert!(!self.tree_built && self.splitter_len > 0);
+        for i in self.splitter_len..len {
+            // SAFETY: see the comment on the struct
+            let last = unsafe { ptr::read(self.splitters[self.splitter_len - 1].as_ptr()) };
+            self.splitters[i].write(last);
+        }
+        self.splitter_len = self.splitter_len.max(len);
+    }
+
+    /// Forgets all splitters and the tree, so that new splitters can be pushed
+    pub fn clear(&mut self) {
+        self.splitter_len = 0;
+        self.tree_built = false;
+    }
+
+    #[inline]
+    fn tree_node(&self, index: usize) -> &T {
+        debug_assert!(self.tree_built && index > 0 && index < self.splitter_len);
+        // SAFETY: tree nodes 1..splitter_len are initialized in build()
+        unsafe { self.tree.get_unchecked(index).assume_init_ref() }
     }
 
     // returns bucket indices
@@ -81,14 +175,15 @@ where
             for i in 0..BATCH_SIZE {
                 let value = &v[i];
                 let index = bucket_indices[i];
-                bucket_indices[i] = 2 * index + (self.is_less)(&self.tree[index], value) as usize;
+                bucket_indices[i] =
+                    2 * index + (self.is_less)(self.tree_node(index), value) as usize;
             }
         }
         if EQUAL_BUCKETS {
             for i in 0..BATCH_SIZE {
                 let value = &v[i];
                 let index = bucket_indices[i];
-                let is_equal = !(self.is_less)(value, &self.splitters[index - len]);
+                let is_equal = !(self.is_less)(value, &self.get_splitters()[index - len]);
                 bucket_indices[i] = 2 * index + is_equal as usize;
             }
         }
@@ -105,112 +200,293 @@ where
         let num_buckets = len << self.equal_buckets as u32;
         let mut b = 1;
         for _i in 0..log_buckets {
-            b = 2 * b + (self.is_less)(&self.tree[b], val) as usize
+            b = 2 * b + (self.is_less)(self.tree_node(b), val) as usize
         }
         if self.equal_buckets {
-            let is_equal = !(self.is_less)(val, &self.splitters[b - len]);
+            let is_equal = !(self.is_less)(val, &self.get_splitters()[b - len]);
             b = 2 * b + is_equal as usize;
         }
         b - num_buckets
     }
 
-    pub(crate) fn classify_locally(
+    pub(crate) fn classify_locally<P: Payload>(
         &self,
         stripe: &mut [T],
+        rows: P,
         buckets: &mut BucketBuffers<T>,
+        payload_buffers: &mut P::Buffers,
         elements_per_bucket: &mut [usize; MAX_BUCKETS],
         num_buckets: usize,
     ) -> usize {
         let elements_per_bucket_slice = &mut elements_per_bucket[..num_buckets];
         if self.equal_buckets {
-            self.classify_locally_helper::<true>(stripe, buckets, elements_per_bucket_slice)
+            self.classify_locally_helper::<true, P>(
+                stripe,
+                rows,
+                buckets,
+                payload_buffers,
+                elements_per_bucket_slice,
+            )
         } else {
-            self.classify_locally_helper::<false>(stripe, buckets, elements_per_bucket_slice)
+            self.classify_locally_helper::<false, P>(
+                stripe,
+                rows,
+                buckets,
+                payload_buffers,
+                elements_per_bucket_slice,
+            )
         }
     }
 
     #[rustfmt::skip]
-    pub(crate) fn classify_locally_helper<const EQUAL_BUCKETS: bool>(
+    pub(crate) fn classify_locally_helper<const EQUAL_BUCKETS: bool, P: Payload>(
         &self,
         stripe: &mut [T],
+        rows: P,
         buckets: &mut BucketBuffers<T>,
+        pb: &mut P::Buffers,
         elements_per_bucket: &mut [usize],
     ) -> usize
     {
         let log_buckets = self.splitter_len.ilog2();
         match log_buckets {
-            1 => self.classify_locally_inner::<EQUAL_BUCKETS, 1>(stripe, buckets, elements_per_bucket),
-            2 => self.classify_locally_inner::<EQUAL_BUCKETS, 2>(stripe, buckets, elements_per_bucket),
-            3 => self.classify_locally_inner::<EQUAL_BUCKETS, 3>(stripe, buckets, elements_per_bucket),
-            4 => self.classify_locally_inner::<EQUAL_BUCKETS, 4>(stripe, buckets, elements_per_bucket),
-            5 => self.classify_locally_inner::<EQUAL_BUCKETS, 5>(stripe, buckets, elements_per_bucket),
-            6 => self.classify_locally_inner::<EQUAL_BUCKETS, 6>(stripe, buckets, elements_per_bucket),
-            7 => self.classify_locally_inner::<EQUAL_BUCKETS, 7>(stripe, buckets, elements_per_bucket),
-            8 => self.classify_locally_inner::<EQUAL_BUCKETS, 8>(stripe, buckets, elements_per_bucket),
-            9 => self.classify_locally_inner::<EQUAL_BUCKETS, 9>(stripe, buckets, elements_per_bucket),
+            1 => self.classify_locally_inner::<EQUAL_BUCKETS, 1, P>(stripe, rows, buckets, pb, elements_per_bucket),
+            2 => self.classify_locally_inner::<EQUAL_BUCKETS, 2, P>(stripe, rows, buckets, pb, elements_per_bucket),
+            3 => self.classify_locally_inner::<EQUAL_BUCKETS, 3, P>(stripe, rows, buckets, pb, elements_per_bucket),
+            4 => self.classify_locally_inner::<EQUAL_BUCKETS, 4, P>(stripe, rows, buckets, pb, elements_per_bucket),
+            5 => self.classify_locally_inner::<EQUAL_BUCKETS, 5, P>(stripe, rows, buckets, pb, elements_per_bucket),
+            6 => self.classify_locally_inner::<EQUAL_BUCKETS, 6, P>(stripe, rows, buckets, pb, elements_per_bucket),
+            7 => self.classify_locally_inner::<EQUAL_BUCKETS, 7, P>(stripe, rows, buckets, pb, elements_per_bucket),
+            8 => self.classify_locally_inner::<EQUAL_BUCKETS, 8, P>(stripe, rows, buckets, pb, elements_per_bucket),
+            9 => self.classify_locally_inner::<EQUAL_BUCKETS, 9, P>(stripe, rows, buckets, pb, elements_per_bucket),
             _ => unreachable!("Maximum number of log buckets, declared in constants.rs is 9"),
         }
     }
 
-    fn classify_locally_inner<const EQUAL_BUCKETS: bool, const LOG_BUCKETS: usize>(
+    /// Stores the bucket of every element of `v` in `oracle` and counts the elements per bucket.
+    /// Unlike [Self::classify_locally], no element is moved.
+    pub(crate) fn classify_oracle(
+        &self,
+        v: &[T],
+        oracle: &mut [u8],
+        elements_per_bucket: &mut [usize],
+    ) {
+        debug_assert_eq!(v.len(), oracle.len());
+        if self.equal_buckets {
+            self.classify_oracle_helper::<true>(v, oracle, elements_per_bucket)
+        } else {
+            self.classify_oracle_helper::<false>(v, oracle, elements_per_bucket)
+        }
+    }
+
+    #[rustfmt::skip]
+    fn classify_oracle_helper<const EQUAL_BUCKETS: bool>(
+        &self,
+        v: &[T],
+        oracle: &mut [u8],
+        elements_per_bucket: &mut [usize],
+    ) {
+        let log_buckets = self.splitter_len.ilog2();
+        match log_buckets {
+            1 => self.classify_oracle_inner::<EQUAL_BUCKETS, 1>(v, oracle, elements_per_bucket),
+            2 => self.classify_oracle_inner::<EQUAL_BUCKETS, 2>(v, oracle, elements_per_bucket),
+            3 => self.classify_oracle_inner::<EQUAL_BUCKETS, 3>(v, oracle, elements_per_bucket),
+            4 => self.classify_oracle_inner::<EQUAL_BUCKETS, 4>(v, oracle, elements_per_bucket),
+            5 => self.classify_oracle_inner::<EQUAL_BUCKETS, 5>(v, oracle, elements_per_bucket),
+            6 => self.classify_oracle_inner::<EQUAL_BUCKETS, 6>(v, oracle, elements_per_bucket),
+            7 => self.classify_oracle_inner::<EQUAL_BUCKETS, 7>(v, oracle, elements_per_bucket),
+            8 => self.classify_oracle_inner::<EQUAL_BUCKETS, 8>(v, oracle, elements_per_bucket),
+            9 => self.classify_oracle_inner::<EQUAL_BUCKETS, 9>(v, oracle, elements_per_bucket),
+            _ => unreachable!("Maximum number of log buckets, declared in constants.rs is 9"),
+        }
+    }
+
+    fn classify_oracle_inner<const EQUAL_BUCKETS: bool, const LOG_BUCKETS: usize>(
+        &self,
+        v: &[T],
+        oracle: &mut [u8],
+        elements_per_bucket: &mut [usize],
+    ) {
+        elements_per_bucket.iter_mut().for_each(|it| *it = 0);
+
+        let mut batches = v.chunks_exact(BATCH_SIZE);
+        let mut oracle_batches = oracle.chunks_exact_mut(BATCH_SIZE);
+        for (batch, oracle_batch) in (&mut batches).zip(&mut oracle_batches) {
+            let bucket_indices = self.classify_batch::<EQUAL_BUCKETS, LOG_BUCKETS, BATCH_SIZE>(
+                batch.try_into().unwrap(),
+            );
+            for (o, bucket_index) in oracle_batch.iter_mut().zip(bucket_indices) {
+                *o = bucket_index as u8;
+                elements_per_bucket[bucket_index] += 1;
+            }
+        }
+        for (elem, o) in batches
+            .remainder()
+            .iter()
+            .zip(oracle_batches.into_remainder())
+        {
+            let [bucket_index] = self.classify_batch::<EQUAL_BUCKETS, LOG_BUCKETS, 1>(
+                slice::from_ref(elem).try_into().unwrap(),
+            );
+            *o = bucket_index as u8;
+            elements_per_bucket[bucket_index] += 1;
+        }
+    }
+
+    fn classify_locally_inner<const EQUAL_BUCKETS: bool, const LOG_BUCKETS: usize, P: Payload>(
         &self,
         stripe: &mut [T],
+        rows: P,
         buckets: &mut BucketBuffers<T>,
+        payload_buffers: &mut P::Buffers,
         elements_per_bucket: &mut [usize],
     ) -> usize {
-        buckets.clear_buckets();
+        classify_stripe(
+            stripe,
+            rows,
+            buckets,
+            payload_buffers,
+            elements_per_bucket,
+            self,
+            |batch| self.classify_batch::<EQUAL_BUCKETS, LOG_BUCKETS, BATCH_SIZE>(batch),
+            |elem| {
+                let [bucket_index] = self.classify_batch::<EQUAL_BUCKETS, LOG_BUCKETS, 1>(
+                    slice::from_ref(elem).try_into().unwrap(),
+                );
+                bucket_index
+            },
+        )
+    }
+}
 
-        elements_per_bucket.iter_mut().for_each(|it| *it = 0);
+/// Classifies the elements of `stripe` in batches and moves them into the bucket buffers. Every
+/// full buffer is written back to the start of the stripe as a block, so the stripe ends up as
+/// a sequence of classified blocks followed by empty ones. Returns the number of elements
+/// written, `elements_per_bucket` also counts the elements left in the buffers.
+///
+/// The rows of the stripe in `rows` are moved into `payload_buffers` along with the elements.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn classify_stripe<T, C, P>(
+    stripe: &mut [T],
+    rows: P,
+    buckets: &mut BucketBuffers<T>,
+    payload_buffers: &mut P::Buffers,
+    elements_per_bucket: &mut [usize],
+    classifier: &C,
+    classify_batch: impl Fn(&[T; BATCH_SIZE]) -> [usize; BATCH_SIZE],
+    classify_single: impl Fn(&T) -> usize,
+) -> usize
+where
+    C: Classify<T>,
+    P: Payload,
+{
+    buckets.clear_buckets();
+    P::clear(payload_buffers);
+    let block_size = buckets.block_size();
 
-        let mut elements_written = 0;
+    elements_per_bucket.iter_mut().for_each(|it| *it = 0);
 
-        let mut insert_into_bucket = |stripe: &mut [T], offset: usize, bucket_index: usize| {
+    let mut guard = ClassificationGuard {
+        stripe,
+        rows,
+        buckets,
+        payload_buffers,
+        elements_written: 0,
+    };
+
+    let mut insert_into_bucket =
+        |guard: &mut ClassificationGuard<T, P>, offset: usize, bucket_index: usize| {
+            let ClassificationGuard {
+                stripe,
+                rows,
+                buckets,
+                payload_buffers,
+                elements_written,
+            } = guard;
             let new_len = unsafe {
                 // SAFETY: caller must ensure that bucket_index <= MAX_BUCKETS,
                 // bucket flushing below ensures not calling uncheck_push() too often
-                buckets.unchecked_push(bucket_index, stripe.get_unchecked(offset).clone())
+                rows.push(payload_buffers, bucket_index, offset);
+                buckets.unchecked_push(bucket_index, stripe.get_unchecked(offset))
             };
 
             // if buffer is full, write buffer contents back into stripe
-            if new_len >= BLOCK_SIZE {
-                {
-                    stripe[elements_written..elements_written + BLOCK_SIZE]
-                        .clone_from_slice(buckets.get(bucket_index));
-                    buckets.clear(bucket_index);
+            if new_len >= block_size {
+                // SAFETY: all elements up to offset were already moved into the buffers,
+                // so only moved-out elements are overwritten
+                unsafe {
+                    move_from_slice(
+                        &mut stripe[*elements_written..*elements_written + block_size],
+                        buckets.get(bucket_index),
+                    );
+                    rows.write_bucket(payload_buffers, bucket_index, *elements_written);
                 }
-                elements_per_bucket[bucket_index] += BLOCK_SIZE;
-                elements_written += BLOCK_SIZE;
+                buckets.clear(bucket_index);
+                P::clear_bucket(payload_buffers, bucket_index);
+                elements_per_bucket[bucket_index] += block_size;
+                *elements_written += block_size;
             }
         };
 
-        let mut i = 0;
-        if stripe.len() > BATCH_SIZE {
-            let cutoff = stripe.len() - BATCH_SIZE;
-            while i <= cutoff {
-                let batch = (&stripe[i..i + BATCH_SIZE]).try_into().unwrap();
-                let bucket_indices =
-                    self.classify_batch::<EQUAL_BUCKETS, LOG_BUCKETS, BATCH_SIZE>(batch);
-                for (j, bucket_index) in bucket_indices.iter().copied().enumerate() {
-                    insert_into_bucket(stripe, i + j, bucket_index);
-                }
-                i += BATCH_SIZE;
+    let len = guard.stripe.len();
+    let mut i = 0;
+    if len > BATCH_SIZE {
+        let cutoff = len - BATCH_SIZE;
+        while i <= cutoff {
+            let batch = (&guard.stripe[i..i + BATCH_SIZE]).try_into().unwrap();
+            let bucket_indices = classify_batch(batch);
+            for (j, bucket_index) in bucket_indices.iter().copied().enumerate() {
+                insert_into_bucket(&mut guard, i + j, bucket_index);
             }
+            i += BATCH_SIZE;
         }
-        for i in i..stripe.len() {
-            let batch = (&stripe[i..i + 1]).try_into().unwrap();
-            let [bucket_index] = self.classify_batch::<EQUAL_BUCKETS, LOG_BUCKETS, 1>(batch);
-            insert_into_bucket(stripe, i, bucket_index);
-        }
+    }
+    for i in i..len {
+        let bucket_index = classify_single(&guard.stripe[i]);
+        insert_into_bucket(&mut guard, i, bucket_index);
+    }
 
-        for (i, elements) in elements_per_bucket.iter_mut().enumerate() {
-            *elements += buckets.len(i);
-        }
-        debug_assert!(self.test_stripe_classification(
-            stripe,
-            elements_per_bucket,
-            elements_written,
-        ));
+    for (i, elements) in elements_per_bucket.iter_mut().enumerate() {
+        *elements += guard.buckets.len(i);
+    }
+    debug_assert!(test_stripe_classification(
+        classifier,
+        guard.stripe,
+        elements_per_bucket,
+        guard.elements_written,
+        block_size,
+    ));
 
-        elements_written
+    let elements_written = guard.elements_written;
+    // The buffered elements are moved back into the stripe later on
+    mem::forget(guard);
+    elements_written
+}
+
+/// Moves the buffered elements and rows back into the stripe, if classification is aborted by
+/// a panic of the comparison function.
+struct ClassificationGuard<'b, T, P: Payload> {
+    stripe: &'b mut [T],
+    rows: P,
+    buckets: &'b mut BucketBuffers<T>,
+    payload_buffers: &'b mut P::Buffers,
+    elements_written: usize,
+}
+
+impl<'b, T, P: Payload> Drop for ClassificationGuard<'b, T, P> {
+    fn drop(&mut self) {
+        let hole = self.elements_written..self.stripe.len();
+        let mut filler = HoleFiller::suffix(self.stripe, self.elements_written);
+        // SAFETY: the elements behind elements_written were moved into the buffers, up to the
+        // first element that was not classified yet. So exactly as many holes follow
+        // elements_written as elements are buffered, the same holds for the rows.
+        unsafe {
+            filler.fill_from_bucket_buffers(self.buckets);
+            self.rows.fill_holes(
+                slice::from_ref(&hole),
+                vec![&mut *self.payload_buffers],
+                false,
+            );
+        }
     }
 }
@@ -0,0 +1,492 @@
+//! Parameters of the algorithm.
+//!
+//! [Config] holds the parameters of one sort and is passed along in the [LocalStorage]. It is
+//! created from an [Ips4o] builder, the defaults are the constants in [crate::constants].
+//!
+//! [LocalStorage]: crate::storage::LocalStorage
+
+use std::{
+    cmp::{max, Ordering},
+    mem::size_of,
+};
+
+use rayon::current_num_threads;
+
+use crate::{
+    bucket_pointers::AtomicPointers,
+    constants::{
+        BASE_CASE_SIZE, BLOCK_SIZE_BYTES, EQUAL_BUCKET_THRESHOLD, LOG_MAX_BUCKETS,
+        MIN_PARALLEL_BLOCKS_PER_THREAD, OVERSAMPLING_FACTOR_PERCENT, SORTED_CHECK_THRESHOLD,
+    },
+    ips2ra, ips2ra_par, ips4o, ips4o_par, ips4o_reduce, ips4o_reduce_par, ips4o_stable,
+    ips4o_stable_par, ips4o_zip, ips4o_zip_par, is_less_to_compare, Columns, RadixKey, Sorter,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Config {
+    /// Number of elements per block
+    pub block_size: usize,
+    /// Maximum logarithm of the number of buckets, without equal buckets
+    pub log_buckets: usize,
+    pub base_case_size: usize,
+    pub oversampling_factor_percent: f64,
+    pub equal_bucket_threshold: usize,
+    pub min_parallel_blocks_per_thread: usize,
+    /// Number of threads of parallel sorts, all threads of the current pool if `None`
+    pub num_threads: Option<usize>,
+    pub atomic_pointers: AtomicPointers,
+    /// Buckets shorter than this are partitioned without checking whether they are sorted
+    pub sorted_check_threshold: usize,
+}
+
+impl Config {
+    /// Inputs up to this size are sorted with a single partitioning step
+    pub fn single_level_threshold(&self) -> usize {
+        self.base_case_size << self.log_buckets
+    }
+
+    pub fn two_level_threshold(&self) -> usize {
+        self.single_level_threshold() << self.log_buckets
+    }
+
+    /// Logarithm of the number of buckets for partitioning `n` elements, without equal buckets
+    pub fn log_buckets(&self, n: usize) -> usize {
+        if n <= self.single_level_threshold() {
+            let res = (n / self.base_case_size).ilog2();
+            max(1, res as usize)
+        } else if n <= self.two_level_threshold() {
+            let res = ((n / self.base_case_size).ilog2() + 1) / 2;
+            max(1, res as usize)
+        } else {
+            self.log_buckets
+        }
+    }
+
+    pub fn oversampling_factor(&self, n: usize) -> usize {
+        max(
+            1,
+            (self.oversampling_factor_percent / 100_f64 * n.ilog2() as f64) as usize,
+        )
+    }
+
+    pub fn num_threads(&self) -> usize {
+        self.num_threads.unwrap_or_else(current_num_threads)
+    }
+
+    /// Inputs up to this size are sorted sequentially, even if more threads are available
+    pub fn min_parallel_len(&self, num_threads: usize) -> usize {
+        num_threads * self.min_parallel_blocks_per_thread * self.block_size
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum BlockSize {
+    Elements(usize),
+    Bytes(usize),
+}
+
+/// Sorts with custom parameters.
+///
+/// ```
+/// let mut v = vec![5, 2, 7, 1];
+/// ips4o_rs::Ips4o::new()
+///     .block_size_bytes(4096)
+///     .log_buckets(6)
+///     .sort(&mut v);
+/// assert_eq!(v, [1, 2, 5, 7]);
+/// ```
+///
+/// The free functions like [crate::sort] use [Ips4o::new].
+#[derive(Debug, Clone, Copy)]
+pub struct Ips4o {
+    block_size: BlockSize,
+    log_buckets: usize,
+    base_case_size: usize,
+    oversampling_factor_percent: f64,
+    equal_bucket_threshold: usize,
+    min_parallel_blocks_per_thread: usize,
+    num_threads: Option<usize>,
+    atomic_pointers: AtomicPointers,
+    sorted_check_threshold: usize,
+}
+
+impl Default for Ips4o {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ips4o {
+    pub fn new() -> Self {
+        Self {
+            block_size: BlockSize::Bytes(BLOCK_SIZE_BYTES),
+            log_buckets: LOG_MAX_BUCKETS,
+            base_case_size: BASE_CASE_SIZE,
+            oversampling_factor_percent: OVERSAMPLING_FACTOR_PERCENT,
+            equal_bucket_threshold: EQUAL_BUCKET_THRESHOLD,
+            min_parallel_blocks_per_thread: MIN_PARALLEL_BLOCKS_PER_THREAD,
+            num_threads: None,
+            atomic_pointers: AtomicPointers::Auto,
+            sorted_check_threshold: SORTED_CHECK_THRESHOLD,
+        }
+    }
+
+    /// Number of elements that are moved around together during partitioning. By default,
+    /// blocks have 2048 bytes, see [Self::block_size_bytes].
+    pub fn block_size(mut self, elements: usize) -> Self {
+        assert!(elements > 0, "block size must be positive");
+        self.block_size = BlockSize::Elements(elements);
+        self
+    }
+
+    /// Like [Self::block_size], but in bytes, so large elements get shorter blocks and the
+    /// buffers of each thread stay small. Blocks hold at least one element.
+    pub fn block_size_bytes(mut self, bytes: usize) -> Self {
+        assert!(bytes > 0, "block size must be positive");
+        self.block_size = BlockSize::Bytes(bytes);
+        self
+    }
+
+    /// Partitions into at most `2^log_buckets` buckets per step, twice as many if equal buckets
+    /// are used.
+    pub fn log_buckets(mut self, log_buckets: usize) -> Self {
+        assert!(
+            (1..=LOG_MAX_BUCKETS).contains(&log_buckets),
+            "log_buckets must be between 1 and {LOG_MAX_BUCKETS}"
+        );
+        self.log_buckets = log_buckets;
+        self
+    }
+
+    /// Buckets of up to `2 * base_case_size` elements are sorted with insertion sort.
+    pub fn base_case_size(mut self, base_case_size: usize) -> Self {
+        assert!(base_case_size > 0, "base case size must be positive");
+        self.base_case_size = base_case_size;
+        self
+    }
+
+    /// The sample has `oversampling_factor_percent / 100 * log2(n)` elements per bucket.
+    pub fn oversampling_factor_percent(mut self, percent: f64) -> Self {
+        assert!(percent >= 0.0, "oversampling factor must not be negative");
+        self.oversampling_factor_percent = percent;
+        self
+    }
+
+    /// Equal buckets are used if at least this many splitters are duplicates. They are always
+    /// used if the sample has a single splitter, which otherwise may not split the input at all.
+    pub fn equal_bucket_threshold(mut self, threshold: usize) -> Self {
+        self.equal_bucket_threshold = threshold;
+        self
+    }
+
+    /// Parallel sorts only use multiple threads if every thread gets at least this many blocks.
+    pub fn min_parallel_blocks_per_thread(mut self, blocks: usize) -> Self {
+        self.min_parallel_blocks_per_thread = blocks;
+        self
+    }
+
+    /// Parallel sorts split the work between `num_threads` threads instead of one per thread of
+    /// the current pool. The pool is not changed, so with fewer threads than the pool has, the
+    /// other threads stay free for other work.
+    pub fn num_threads(mut self, num_threads: usize) -> Self {
+        assert!(num_threads > 0, "number of threads must be positive");
+        self.num_threads = Some(num_threads);
+        self
+    }
+
+    /// How the threads share the bucket pointers during block permutation. By default, the
+    /// representation is chosen by the length of the slice and the atomics of the platform.
+    pub fn atomic_pointers(mut self, kind: AtomicPointers) -> Self {
+        self.atomic_pointers = kind;
+        self
+    }
+
+    /// Buckets with at least `threshold` elements are checked for sortedness before they are
+    /// partitioned. Buckets made of a few sorted runs are merged instead, which is common for
+    /// nearly sorted inputs. Pass `usize::MAX` to never check.
+    pub fn sorted_check_threshold(mut self, threshold: usize) -> Self {
+        self.sorted_check_threshold = threshold;
+        self
+    }
+
+    /// Returns a [Sorter] with these parameters, which keeps its buffers between sorts.
+    pub fn sorter<T>(&self) -> Sorter<T> {
+        Sorter::with_params(*self)
+    }
+
+    pub(crate) fn config<T>(&self) -> Config {
+        let block_size = match self.block_size {
+            BlockSize::Elements(elements) => elements,
+            BlockSize::Bytes(bytes) => max(1, bytes / max(1, size_of::<T>())),
+        };
+        Config {
+            block_size,
+            log_buckets: self.log_buckets,
+            base_case_size: self.base_case_size,
+            oversampling_factor_percent: self.oversampling_factor_percent,
+            equal_bucket_threshold: self.equal_bucket_threshold,
+            min_parallel_blocks_per_thread: self.min_parallel_blocks_per_thread,
+            num_threads: self.num_threads,
+            atomic_pointers: self.atomic_pointers,
+            sorted_check_threshold: self.sorted_check_threshold,
+        }
+    }
+
+    #[inline]
+    pub fn sort<T>(&self, v: &mut [T])
+    where
+        T: Ord,
+    {
+        ips4o(v, T::lt, &self.config::<T>(), &mut Vec::new());
+        debug_assert!(v.is_sorted());
+    }
+
+    #[inline]
+    pub fn sort_by<T, F>(&self, v: &mut [T], compare: F)
+    where
+        F: Fn(&T, &T) -> Ordering,
+    {
+        ips4o(
+            v,
+            |a, b| compare(a, b) == Ordering::Less,
+            &self.config::<T>(),
+            &mut Vec::new(),
+        );
+        debug_assert!(v.is_sorted_by(|a, b| Some(compare(a, b))));
+    }
+
+    #[inline]
+    pub fn sort_by_key<T, K, F>(&self, v: &mut [T], f: F)
+    where
+        F: Fn(&T) -> K,
+        K: Ord,
+    {
+        ips4o(
+            v,
+            |a, b| f(a).lt(&f(b)),
+            &self.config::<T>(),
+            &mut Vec::new(),
+        );
+        let is_less = |a, b| f(a).lt(&f(b));
+        debug_assert!(v.is_sorted_by(is_less_to_compare!(is_less)));
+    }
+
+    #[inline]
+    pub fn sort_par<T>(&self, v: &mut [T])
+    where
+        T: Ord + Send + Sync,
+    {
+        ips4o_par(v, T::lt, &self.config::<T>(), &mut Vec::new());
+        debug_assert!(v.is_sorted());
+    }
+
+    #[inline]
+    pub fn sort_par_by<T, F>(&self, v: &mut [T], compare: F)
+    where
+        T: Send + Sync,
+        F: Fn(&T, &T) -> Ordering + Sync,
+    {
+        ips4o_par(
+            v,
+            |a, b| compare(a, b) == Ordering::Less,
+            &self.config::<T>(),
+            &mut Vec::new(),
+        );
+        debug_assert!(v.is_sorted_by(|a, b| Some(compare(a, b))));
+    }
+
+    #[inline]
+    pub fn sort_par_by_key<T, K, F>(&self, v: &mut [T], f: F)
+    where
+        T: Send + Sync,
+        F: Fn(&T) -> K + Sync,
+        K: Ord,
+    {
+        ips4o_par(
+            v,
+            |a, b| f(a).lt(&f(b)),
+            &self.config::<T>(),
+            &mut Vec::new(),
+        );
+        let is_less = |a, b| f(a).lt(&f(b));
+        debug_assert!(v.is_sorted_by(is_less_to_compare!(is_less)));
+    }
+
+    /// Sorts `v` like [Self::sort], but keeps equal elements in their original order.
+    #[inline]
+    pub fn stable_sort<T>(&self, v: &mut [T])
+    where
+        T: Ord,
+    {
+        ips4o_stable(v, T::lt, &self.config::<T>());
+        debug_assert!(v.is_sorted());
+    }
+
+    #[inline]
+    pub fn stable_sort_by<T, F>(&self, v: &mut [T], compare: F)
+    where
+        F: Fn(&T, &T) -> Ordering,
+    {
+        ips4o_stable(
+            v,
+            |a, b| compare(a, b) == Ordering::Less,
+            &self.config::<T>(),
+        );
+        debug_assert!(v.is_sorted_by(|a, b| Some(compare(a, b))));
+    }
+
+    #[inline]
+    pub fn stable_sort_by_key<T, K, F>(&self, v: &mut [T], f: F)
+    where
+        F: Fn(&T) -> K,
+        K: Ord,
+    {
+        ips4o_stable(v, |a, b| f(a).lt(&f(b)), &self.config::<T>());
+        let is_less = |a, b| f(a).lt(&f(b));
+        debug_assert!(v.is_sorted_by(is_less_to_compare!(is_less)));
+    }
+
+    /// Sorts `v` like [Self::sort_par], but keeps equal elements in their original order.
+    #[inline]
+    pub fn stable_sort_par<T>(&self, v: &mut [T])
+    where
+        T: Ord + Send + Sync,
+    {
+        ips4o_stable_par(v, T::lt, &self.config::<T>());
+        debug_assert!(v.is_sorted());
+    }
+
+    #[inline]
+    pub fn stable_sort_par_by<T, F>(&self, v: &mut [T], compare: F)
+    where
+        T: Send + Sync,
+        F: Fn(&T, &T) -> Ordering + Sync,
+    {
+        ips4o_stable_par(
+            v,
+            |a, b| compare(a, b) == Ordering::Less,
+            &self.config::<T>(),
+        );
+        debug_assert!(v.is_sorted_by(|a, b| Some(compare(a, b))));
+    }
+
+    #[inline]
+    pub fn stable_sort_par_by_key<T, K, F>(&self, v: &mut [T], f: F)
+    where
+        T: Send + Sync,
+        F: Fn(&T) -> K + Sync,
+        K: Ord,
+    {
+        ips4o_stable_par(v, |a, b| f(a).lt(&f(b)), &self.config::<T>());
+        let is_less = |a, b| f(a).lt(&f(b));
+        debug_assert!(v.is_sorted_by(is_less_to_compare!(is_less)));
+    }
+
+    /// Sorts `v` by the keys that `key` extracts and folds every group of elements with equal
+    /// keys into its first element, see [crate::sort_and_reduce_by_key].
+    #[inline]
+    pub fn sort_and_reduce_by_key<T, K, F, R>(&self, v: &mut [T], key: F, reduce: R) -> usize
+    where
+        F: Fn(&T) -> K,
+        K: Ord,
+        R: FnMut(&mut T, &mut T),
+    {
+        let len = ips4o_reduce(v, |a, b| key(a).lt(&key(b)), reduce, &self.config::<T>());
+        debug_assert!(v[..len].windows(2).all(|w| key(&w[0]) < key(&w[1])));
+        len
+    }
+
+    #[inline]
+    pub fn sort_par_and_reduce_by_key<T, K, F, R>(&self, v: &mut [T], key: F, reduce: R) -> usize
+    where
+        T: Send + Sync,
+        F: Fn(&T) -> K + Sync,
+        K: Ord,
+        R: Fn(&mut T, &mut T) + Sync,
+    {
+        let len = ips4o_reduce_par(v, |a, b| key(a).lt(&key(b)), reduce, &self.config::<T>());
+        debug_assert!(v[..len].windows(2).all(|w| key(&w[0]) < key(&w[1])));
+        len
+    }
+
+    /// Sorts `keys` like [Self::sort] and moves the rows of `values` along, see
+    /// [crate::sort_zip].
+    #[inline]
+    pub fn sort_zip<K, V>(&self, keys: &mut [K], values: &mut [V])
+    where
+        K: Ord,
+    {
+        ips4o_zip(keys, values, K::lt, &self.config::<K>());
+        debug_assert!(keys.is_sorted());
+    }
+
+    #[inline]
+    pub fn sort_par_zip<K, V>(&self, keys: &mut [K], values: &mut [V])
+    where
+        K: Ord + Send + Sync,
+        V: Send,
+    {
+        ips4o_zip_par(keys, values, K::lt, &self.config::<K>());
+        debug_assert!(keys.is_sorted());
+    }
+
+    /// Sorts `keys` like [Self::sort] and moves the rows of `columns` along, see
+    /// [crate::sort_zip_columns].
+    #[inline]
+    pub fn sort_zip_columns<K, C>(&self, keys: &mut [K], columns: C)
+    where
+        K: Ord,
+        C: Columns,
+    {
+        ips4o_zip(keys, columns, K::lt, &self.config::<K>());
+        debug_assert!(keys.is_sorted());
+    }
+
+    #[inline]
+    pub fn sort_par_zip_columns<K, C>(&self, keys: &mut [K], columns: C)
+    where
+        K: Ord + Send + Sync,
+        C: Columns + Send,
+    {
+        ips4o_zip_par(keys, columns, K::lt, &self.config::<K>());
+        debug_assert!(keys.is_sorted());
+    }
+
+    /// Sorts `v` with an in-place radix sort, which looks at the bits of the elements instead
+    /// of comparing them.
+    #[inline]
+    pub fn radix_sort<T>(&self, v: &mut [T])
+    where
+        T: RadixKey,
+    {
+        ips2ra(v, |x| *x, &self.config::<T>());
+    }
+
+    #[inline]
+    pub fn radix_sort_by_key<T, K, F>(&self, v: &mut [T], f: F)
+    where
+        F: Fn(&T) -> K,
+        K: RadixKey,
+    {
+        ips2ra(v, f, &self.config::<T>());
+    }
+
+    /// Parallel version of [Self::radix_sort]
+    #[inline]
+    pub fn radix_sort_par<T>(&self, v: &mut [T])
+    where
+        T: RadixKey + Send + Sync,
+    {
+        ips2ra_par(v, |x| *x, &self.config::<T>());
+    }
+
+    #[inline]
+    pub fn radix_sort_par_by_key<T, K, F>(&self, v: &mut [T], f: F)
+    where
+        T: Send + Sync,
+        F: Fn(&T) -> K + Sync,
+        K: RadixKey,
+    {
+        ips2ra_par(v, f, &self.config::<T>());
+    }
+}
@@ -1,30 +1,29 @@
-use std::cmp::max;
+//! Default parameters of the algorithm, see [crate::Ips4o]
 
 pub const LOG_MAX_BUCKETS: usize = 7;
-pub const LOG_BLOCK_SIZE: usize = 9;
 pub const BASE_CASE_SIZE: usize = 16;
 pub const BASE_CASE_MULTIPLIER: usize = 8;
 pub const EQUAL_BUCKET_THRESHOLD: usize = 5;
 pub const ALLOW_EQUAL_BUCKETS: bool = true;
 pub const OVERSAMPLING_FACTOR_PERCENT: f64 = 25.0;
-pub const SINGLE_LEVEL_THRESHOLD: usize = BASE_CASE_SIZE * (1 << LOG_MAX_BUCKETS);
-pub const TWO_LEVEL_THRESHOLD: usize = SINGLE_LEVEL_THRESHOLD * (1 << LOG_MAX_BUCKETS);
 pub const BATCH_SIZE: usize = 6;
 pub const MIN_PARALLEL_BLOCKS_PER_THREAD: usize = 4;
 
-pub const BLOCK_SIZE: usize = 1usize << LOG_BLOCK_SIZE;
+/// Buckets with at least this many elements are checked for sortedness before recursing
+pub const SORTED_CHECK_THRESHOLD: usize = 256;
+
+/// Size of a block in bytes, blocks hold `max(1, BLOCK_SIZE_BYTES / size_of::<T>())` elements
+pub const BLOCK_SIZE_BYTES: usize = 2048;
 
 /// Maximum number of buckets, with equal buckets
 pub const MAX_BUCKETS: usize = 1usize << (LOG_MAX_BUCKETS + ALLOW_EQUAL_BUCKETS as usize);
 
-pub(crate) fn log_buckets(n: usize) -> usize {
-    if n <= SINGLE_LEVEL_THRESHOLD {
-        let res = (n / BASE_CASE_SIZE).ilog2();
-        max(1, res as usize)
-    } else if n <= TWO_LEVEL_THRESHOLD {
-        let res = ((n / BASE_CASE_SIZE).ilog2() + 1) / 2;
-        max(1, res as usize)
-    } else {
-        LOG_MAX_BUCKETS
-    }
-}
+/// Inputs with more presorted runs are sorted instead of merged, see [crate::merge_sorted_runs]
+pub const MAX_MERGE_RUNS: usize = 16;
+
+/// Number of elements that are checked for presorted runs before partitioning, see
+/// [crate::sort]
+pub const PRESORTED_SAMPLE_SIZE: usize = 128;
+
+/// Buckets made of at most this many sorted runs are merged instead of partitioned
+pub const MAX_PRESORTED_BUCKET_RUNS: usize = 8;
@@ -0,0 +1,189 @@
+//! Removal of duplicates while sorting, optionally folding them into one element.
+//!
+//! Collapsing duplicates while classifying would move elements between buckets, whose
+//! boundaries are fixed by then. So the sequential version partitions like
+//! [seq_recurse](crate::sequential::seq_recurse) and removes the duplicates bucket by bucket on
+//! the way back up: all elements of an equal bucket equal its splitter and are folded into one
+//! without any comparison, and sorted buckets are reduced in one pass over their elements. The
+//! unique elements of the buckets are then swapped together.
+//!
+//! The parallel version sorts first and removes the duplicates of the sorted slice afterwards.
+
+use std::mem::MaybeUninit;
+
+use rayon::prelude::*;
+
+use crate::{
+    base_case::{base_case_sort, heapsort::heapsort},
+    config::Config,
+    constants::MAX_BUCKETS,
+    is_less_to_compare,
+    sequential::{merge_presorted_bucket, partition, recursion_depth_limit},
+    storage::{BufferPool, LocalStorage},
+    Less, PLess, Sortable,
+};
+
+/// Sorts `v`, folds every run of equal elements into its first element with `reduce` and moves
+/// the runs to the front of `v`. Returns their number.
+pub(crate) fn sequential_reduce<T, F, R>(
+    v: &mut [T],
+    is_less: &F,
+    mut reduce: R,
+    config: &Config,
+    pool: &mut BufferPool<T>,
+) -> usize
+where
+    T: Sortable,
+    F: Less<T>,
+    R: FnMut(&mut T, &mut T),
+{
+    if v.len() <= 2 * config.base_case_size {
+        base_case_sort(v, is_less);
+        return reduce_sorted(v, is_less, reduce);
+    }
+    let mut ls = LocalStorage::<T, F>::new_in(is_less, config, pool);
+    let len = reduce_recurse(
+        v,
+        &mut ls,
+        is_less,
+        &mut reduce,
+        recursion_depth_limit(v.len()),
+    );
+    pool.push(ls.into_buffers());
+    len
+}
+
+/// Recursion of [sequential_reduce], see [seq_recurse](crate::sequential::seq_recurse).
+fn reduce_recurse<T, F, R>(
+    v: &mut [T],
+    ls: &mut LocalStorage<T, F>,
+    is_less: &F,
+    reduce: &mut R,
+    depth_limit: usize,
+) -> usize
+where
+    T: Sortable,
+    F: Less<T>,
+    R: FnMut(&mut T, &mut T),
+{
+    debug_assert!(v.len() > 2 * ls.config.base_case_size);
+    if depth_limit == 0 || !partition(v, ls, is_less) {
+        heapsort(v, is_less);
+        return reduce_sorted(v, is_less, reduce);
+    }
+
+    let mut bucket_boundaries: [MaybeUninit<usize>; MAX_BUCKETS + 1] =
+        [MaybeUninit::uninit(); MAX_BUCKETS + 1];
+    let bucket_boundaries = MaybeUninit::write_slice(
+        &mut bucket_boundaries[..ls.num_buckets + 1],
+        &ls.bucket_boundaries[..ls.num_buckets + 1],
+    );
+    let is_last_level = v.len() <= ls.config.single_level_threshold();
+    let equal_buckets = ls.classifier.equal_buckets;
+    let num_buckets = ls.num_buckets;
+    let base_case_size = ls.config.base_case_size;
+
+    let mut len = 0;
+    for bucket in 0..num_buckets {
+        let range = bucket_boundaries[bucket]..bucket_boundaries[bucket + 1];
+        let start = range.start;
+        let elements = &mut v[range];
+        let unique = if elements.is_empty() {
+            0
+        } else if equal_buckets && bucket % 2 == 1 && bucket != num_buckets - 1 {
+            let (first, rest) = elements.split_first_mut().unwrap();
+            for element in rest {
+                reduce(first, element);
+            }
+            1
+        } else if is_last_level
+            || elements.len() <= 2 * base_case_size
+            || merge_presorted_bucket(elements, ls, is_less)
+        {
+            // Sorted in cleanup_margins() or just now
+            debug_assert!(elements.is_sorted_by(is_less_to_compare!(is_less)));
+            reduce_sorted(elements, is_less, &mut *reduce)
+        } else {
+            reduce_recurse(elements, ls, is_less, reduce, depth_limit - 1)
+        };
+        // `len <= start`, so swapping from the front also works if the ranges overlap
+        for i in 0..unique {
+            v.swap(len + i, start + i);
+        }
+        len += unique;
+    }
+    len
+}
+
+/// Moves the first element of every run of equal elements of the sorted slice `v` to its front
+/// and returns their number. Every other element of a run is folded into the first one with
+/// `reduce`, which must not change the order of the first one, and follows in unspecified
+/// order.
+///
+/// Elements are only swapped, so a panicking comparison leaves every element in `v`.
+pub(crate) fn reduce_sorted<T, F, R>(v: &mut [T], is_less: &F, mut reduce: R) -> usize
+where
+    F: Less<T>,
+    R: FnMut(&mut T, &mut T),
+{
+    if v.is_empty() {
+        return 0;
+    }
+    // `v[len - 1]` is the last unique element so far, everything in `v[len..i]` equals it
+    let mut len = 1;
+    for i in 1..v.len() {
+        if is_less(&v[len - 1], &v[i]) {
+            v.swap(len, i);
+            len += 1;
+        } else {
+            let (unique, rest) = v.split_at_mut(i);
+            reduce(&mut unique[len - 1], &mut rest[0]);
+        }
+    }
+    len
+}
+
+/// Parallel version of [reduce_sorted], with one chunk of `v` per thread.
+pub(crate) fn reduce_sorted_par<T, F, R>(
+    v: &mut [T],
+    is_less: &F,
+    reduce: R,
+    num_threads: usize,
+) -> usize
+where
+    T: Send,
+    F: PLess<T>,
+    R: Fn(&mut T, &mut T) + Sync,
+{
+    let chunk_len = (v.len() + num_threads - 1) / num_threads;
+    let lens: Vec<usize> = v
+        .par_chunks_mut(chunk_len)
+        .map(|chunk| reduce_sorted(chunk, is_less, &reduce))
+        .collect();
+
+    // Move the unique elements of the chunks together. Only the first one of a chunk can
+    // equal the last unique element of the chunks before it.
+    //
+    // This stays on one thread: the target range of a chunk can overlap the unique elements
+    // of the chunk before it, which are only out of the way once that one is done, so moving
+    // the chunks at the same time would need a buffer for all unique elements. Each unique
+    // element is swapped at most once, and not at all while no duplicate was found yet.
+    let mut len = 0;
+    for (chunk, &chunk_unique) in lens.iter().enumerate() {
+        let start = chunk * chunk_len;
+        let skip = (len > 0 && !is_less(&v[len - 1], &v[start])) as usize;
+        if skip == 1 {
+            let (unique, rest) = v.split_at_mut(start);
+            reduce(&mut unique[len - 1], &mut rest[0]);
+        } else if len == start {
+            len += chunk_unique;
+            continue;
+        }
+        // Swapping from the front also works if the ranges overlap
+        for i in skip..chunk_unique {
+            v.swap(len, start + i);
+            len += 1;
+        }
+    }
+    len
+}
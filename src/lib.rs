@@ -1,274 +1,2441 @@
 #![feature(is_sorted, let_chains, new_uninit, maybe_uninit_write_slice)]
 use base_case::sort_simple_cases;
-use constants::{BASE_CASE_MULTIPLIER, BASE_CASE_SIZE, BLOCK_SIZE, MIN_PARALLEL_BLOCKS_PER_THREAD};
+use config::Config;
+use constants::{BASE_CASE_MULTIPLIER, MAX_MERGE_RUNS};
+use dedup::{reduce_sorted, reduce_sorted_par, sequential_reduce};
+use merge::{merge_presorted, merge_runs};
 use parallel::parallel_ips4o;
-use rayon::current_num_threads;
+use payload::base_case_sort_rows;
+use radix::{parallel::radix_parallel, radix_sequential};
+use rayon::{prelude::*, ThreadPool};
+use select::select_sequential;
 use sequential::sequential_ips4o;
-use std::{cmp::Ordering, fmt::Debug, mem::size_of};
+use stable::{parallel::stable_parallel_ips4o, stable_sequential_ips4o};
+use std::{cmp::Ordering, mem::size_of, ops::Range};
+use storage::BufferPool;
+use util::permutation_to_swaps;
+use zip::{parallel::zip_parallel_ips4o, zip_sequential_ips4o};
 
 mod base_case;
 mod bucket_pointers;
 mod classifier;
+mod config;
 mod constants;
+mod dedup;
+mod merge;
 mod parallel;
+mod payload;
 mod permute_blocks;
+mod radix;
+mod restore;
+mod select;
 mod sequential;
+mod sorter;
+mod stable;
 mod storage;
 mod util;
+mod zip;
 
-pub(crate) trait Sortable: Clone + Debug + Default {}
-impl<T: Clone + Debug + Default> Sortable for T {}
+pub use bucket_pointers::AtomicPointers;
+pub use config::Ips4o;
+pub use merge::Presortedness;
+pub use radix::RadixKey;
+pub use sorter::Sorter;
+pub use zip::Columns;
+
+pub(crate) trait Sortable {}
+impl<T> Sortable for T {}
 
 pub(crate) trait Less<T>: Fn(&T, &T) -> bool {}
 impl<T, F: Fn(&T, &T) -> bool> Less<T> for F {}
 
-pub(crate) trait PSortable: Sortable + Send + Sync + Copy {}
-impl<T: Sortable + Send + Sync + Copy> PSortable for T {}
+pub(crate) trait PSortable: Sortable + Send + Sync {}
+impl<T: Sortable + Send + Sync> PSortable for T {}
 
 pub(crate) trait PLess<T>: Less<T> + Sync {}
 impl<T, F: Less<T> + Sync> PLess<T> for F {}
 
+/// Sorts `v` in place.
+///
+/// If a sample of the input looks presorted, its long runs are merged instead of partitioned,
+/// which needs a buffer of up to half the input.
 #[inline]
 pub fn sort<T>(v: &mut [T])
 where
-    T: Ord + Debug + Default + Clone,
+    T: Ord,
 {
-    ips4o(v, T::lt);
-    debug_assert!(v.is_sorted());
+    Ips4o::new().sort(v);
 }
 
 #[inline]
 pub fn sort_by<T, F>(v: &mut [T], compare: F)
 where
-    T: Debug + Default + Clone,
     F: Fn(&T, &T) -> Ordering,
 {
-    ips4o(v, |a, b| compare(a, b) == Ordering::Less);
-    debug_assert!(v.is_sorted_by(|a, b| Some(compare(a, b))));
+    Ips4o::new().sort_by(v, compare);
 }
 
 #[inline]
 pub fn sort_by_key<T, K, F>(v: &mut [T], f: F)
 where
-    T: Debug + Default + Clone,
     F: Fn(&T) -> K,
     K: Ord,
 {
-    ips4o(v, |a, b| f(a).lt(&f(b)));
-    let is_less = |a, b| f(a).lt(&f(b));
-    debug_assert!(v.is_sorted_by(is_less_to_compare!(is_less)));
+    Ips4o::new().sort_by_key(v, f);
 }
 
 #[inline]
 pub fn sort_par<T>(v: &mut [T])
 where
-    T: Ord + Debug + Default + Clone + Copy + Send + Sync,
+    T: Ord + Send + Sync,
 {
-    ips4o_par(v, T::lt);
-    debug_assert!(v.is_sorted());
+    Ips4o::new().sort_par(v);
 }
 
-fn ips4o<T, F>(v: &mut [T], is_less: F)
+#[inline]
+pub fn sort_par_by<T, F>(v: &mut [T], compare: F)
 where
-    T: Sortable,
-    F: Less<T>,
+    T: Send + Sync,
+    F: Fn(&T, &T) -> Ordering + Sync,
 {
-    // Sorting has no meaningful behavior on zero-sized types. Do nothing.
-    if size_of::<T>() == 0 {
-        return;
-    }
-    if sort_simple_cases(v, &is_less) {
-        return;
-    }
-    if v.len() <= BASE_CASE_MULTIPLIER * BASE_CASE_SIZE {
-        base_case::base_case_sort(v, &is_less);
-        return;
-    }
-    sequential_ips4o(v, &is_less);
+    Ips4o::new().sort_par_by(v, compare);
 }
 
 #[inline]
-#[allow(unused)]
-fn ips4o_par<T, F>(v: &mut [T], mut is_less: F)
+pub fn sort_par_by_key<T, K, F>(v: &mut [T], f: F)
 where
-    T: PSortable,
-    F: Fn(&T, &T) -> bool + Sync,
+    T: Send + Sync,
+    F: Fn(&T) -> K + Sync,
+    K: Ord,
 {
-    // Sorting has no meaningful behavior on zero-sized types. Do nothing.
-    if size_of::<T>() == 0 {
-        return;
-    }
-    if sort_simple_cases(v, &is_less) {
-        return;
-    }
-    if v.len() <= BASE_CASE_MULTIPLIER * BASE_CASE_SIZE {
-        base_case::base_case_sort(v, &is_less);
-        return;
-    }
-    // Sorting in parallel makes no sense with only one thread
-    if current_num_threads() == 1 {
-        ips4o(v, is_less);
+    Ips4o::new().sort_par_by_key(v, f);
+}
+
+/// Like [sort_par], but uses the threads of `pool` instead of the current one.
+///
+/// The other parallel sorts run in the pool of the calling thread, or in the global pool if the
+/// calling thread is not part of one. They can be moved to any pool with
+/// [ThreadPool::install] as well.
+#[inline]
+pub fn sort_par_in<T>(pool: &ThreadPool, v: &mut [T])
+where
+    T: Ord + Send + Sync,
+{
+    pool.install(|| sort_par(v));
+}
+
+#[inline]
+pub fn sort_par_by_in<T, F>(pool: &ThreadPool, v: &mut [T], compare: F)
+where
+    T: Send + Sync,
+    F: Fn(&T, &T) -> Ordering + Send + Sync,
+{
+    pool.install(|| sort_par_by(v, compare));
+}
+
+#[inline]
+pub fn sort_par_by_key_in<T, K, F>(pool: &ThreadPool, v: &mut [T], f: F)
+where
+    T: Send + Sync,
+    F: Fn(&T) -> K + Send + Sync,
+    K: Ord,
+{
+    pool.install(|| sort_par_by_key(v, f));
+}
+
+/// Like [sort_par_by_key], but calls the key function only once per element.
+///
+/// The keys are computed in parallel, then a permutation of indices is sorted by them
+/// and finally applied to `v`. Ties are broken by the indices, so elements with equal keys keep
+/// their original order and the result does not depend on the sampling or the threads.
+#[inline]
+pub fn sort_par_by_cached_key<T, K, F>(v: &mut [T], f: F)
+where
+    T: Send + Sync,
+    F: Fn(&T) -> K + Sync,
+    K: Ord + Send + Sync,
+{
+    let len = v.len();
+    if len < 2 {
         return;
     }
-    if v.len() <= current_num_threads() * MIN_PARALLEL_BLOCKS_PER_THREAD * BLOCK_SIZE {
-        sequential_ips4o(v, &is_less);
-        return;
+    let keys: Vec<K> = v.par_iter().map(&f).collect();
+    let mut indices: Vec<usize> = (0..len).collect();
+    ips4o_par(
+        &mut indices,
+        |&a, &b| (&keys[a], a) < (&keys[b], b),
+        &Ips4o::new().config::<usize>(),
+        &mut Vec::new(),
+    );
+
+    permutation_to_swaps(&mut indices);
+    for (i, &index) in indices.iter().enumerate() {
+        v.swap(i, index);
     }
-    parallel_ips4o(v, &is_less);
+    let is_less = |a, b| f(a).lt(&f(b));
+    debug_assert!(v.is_sorted_by(is_less_to_compare!(is_less)));
 }
 
-#[cfg(test)]
-mod tests {
-    use std::{
-        cmp::{max, min},
-        fs, panic,
-    };
+/// Returns the permutation that sorts `v`, without changing `v`.
+///
+/// `v[indices[0]], v[indices[1]], ...` is sorted. Like [sort], equal elements are not kept in
+/// their original order.
+///
+/// ```
+/// let v = [30, 10, 20];
+/// assert_eq!(ips4o_rs::argsort(&v), [1, 2, 0]);
+/// ```
+#[inline]
+pub fn argsort<T>(v: &[T]) -> Vec<usize>
+where
+    T: Ord,
+{
+    let mut indices: Vec<usize> = (0..v.len()).collect();
+    ips4o(
+        &mut indices,
+        |&a, &b| v[a].lt(&v[b]),
+        &Ips4o::new().config::<usize>(),
+        &mut Vec::new(),
+    );
+    indices
+}
 
-    use rand::{distributions::Uniform, rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+#[inline]
+pub fn argsort_by<T, F>(v: &[T], compare: F) -> Vec<usize>
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    let mut indices: Vec<usize> = (0..v.len()).collect();
+    ips4o(
+        &mut indices,
+        |&a, &b| compare(&v[a], &v[b]) == Ordering::Less,
+        &Ips4o::new().config::<usize>(),
+        &mut Vec::new(),
+    );
+    indices
+}
 
-    use crate::{debug, sort, sort_par, PSortable};
+#[inline]
+pub fn argsort_by_key<T, K, F>(v: &[T], f: F) -> Vec<usize>
+where
+    F: Fn(&T) -> K,
+    K: Ord,
+{
+    argsort_by(v, |a, b| f(a).cmp(&f(b)))
+}
 
-    const TEST_PARALLEL: bool = false;
+/// Parallel version of [argsort]
+#[inline]
+pub fn argsort_par<T>(v: &[T]) -> Vec<usize>
+where
+    T: Ord + Sync,
+{
+    let mut indices: Vec<usize> = (0..v.len()).into_par_iter().collect();
+    ips4o_par(
+        &mut indices,
+        |&a, &b| v[a].lt(&v[b]),
+        &Ips4o::new().config::<usize>(),
+        &mut Vec::new(),
+    );
+    indices
+}
 
-    const FAILING_INPUT: &str = "./target/failing_input.json";
+#[inline]
+pub fn argsort_par_by<T, F>(v: &[T], compare: F) -> Vec<usize>
+where
+    T: Sync,
+    F: Fn(&T, &T) -> Ordering + Sync,
+{
+    let mut indices: Vec<usize> = (0..v.len()).into_par_iter().collect();
+    ips4o_par(
+        &mut indices,
+        |&a, &b| compare(&v[a], &v[b]) == Ordering::Less,
+        &Ips4o::new().config::<usize>(),
+        &mut Vec::new(),
+    );
+    indices
+}
 
-    fn sort_test<T>(input: &mut [T])
-    where
-        T: Ord + PSortable,
-    {
-        if TEST_PARALLEL {
-            sort_par(input)
-        } else {
-            sort(input)
-        }
-    }
+#[inline]
+pub fn argsort_par_by_key<T, K, F>(v: &[T], f: F) -> Vec<usize>
+where
+    T: Sync,
+    F: Fn(&T) -> K + Sync,
+    K: Ord,
+{
+    argsort_par_by(v, |a, b| f(a).cmp(&f(b)))
+}
 
-    fn sort_and_save_to_file_if_failed(mut input: Vec<u64>) {
-        let clone = input.clone();
-        let result = panic::catch_unwind(move || {
-            sort_test(&mut input);
-            input
-        });
-        match result {
-            Ok(sorted_input) => {
-                let mut sorted = clone.clone();
-                sorted.sort();
-                if sorted != sorted_input {
-                    let data =
-                        serde_json::to_string(&clone).expect("unable to serialize failing slice");
-                    fs::write(FAILING_INPUT, data).expect("unable to write failing slice to file");
-                    panic!("result is not a sorted permutation of its input")
-                }
-            }
-            Err(_e) => {
-                let data =
-                    serde_json::to_string(&clone).expect("unable to serialize failing slice");
-                fs::write(FAILING_INPUT, data).expect("unable to write failing slice to file");
-                panic!()
-            }
-        }
-    }
+/// Reorders `v` so that the element at `index` is the one that would be there if `v` was
+/// sorted, with no greater element before and no smaller element after it.
+///
+/// Returns the elements before `index`, the element at `index` and the elements after it, like
+/// [slice::select_nth_unstable]. Only the buckets that contain `index` are partitioned further,
+/// so this is faster than sorting.
+///
+/// # Panics
+/// If `index >= v.len()`.
+#[inline]
+pub fn select_nth_unstable<T>(v: &mut [T], index: usize) -> (&mut [T], &mut T, &mut [T])
+where
+    T: Ord,
+{
+    select_nth_unstable_by(v, index, T::cmp)
+}
 
-    #[test]
-    fn simple_test1() {
-        let mut input = some_vec();
-        input.append(&mut some_vec());
-        input.append(&mut some_vec());
-        input.append(&mut some_vec());
-        input.append(&mut some_vec());
-        debug!(input);
-        sort_test(&mut input);
-        debug!(input);
-    }
+#[inline]
+pub fn select_nth_unstable_by<T, F>(
+    v: &mut [T],
+    index: usize,
+    compare: F,
+) -> (&mut [T], &mut T, &mut [T])
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    assert!(
+        index < v.len(),
+        "index {index} out of range for slice of length {}",
+        v.len()
+    );
+    ips4o_select(
+        v,
+        index..index + 1,
+        |a, b| compare(a, b) == Ordering::Less,
+        &Ips4o::new().config::<T>(),
+    );
+    let (left, rest) = v.split_at_mut(index);
+    let (nth, right) = rest.split_first_mut().unwrap();
+    (left, nth, right)
+}
 
-    #[test]
-    fn simple_test2() {
-        let mut input = [
-            1, 9, 26, 29, 1, 2, 3, 4, 5, 6, 7, 8, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21,
-            22, 23, 24, 25, 27, 28,
-        ];
-        debug!(input);
-        sort_test(&mut input);
-        debug!(input);
-        assert!(input.is_sorted());
-    }
+#[inline]
+pub fn select_nth_unstable_by_key<T, K, F>(
+    v: &mut [T],
+    index: usize,
+    f: F,
+) -> (&mut [T], &mut T, &mut [T])
+where
+    F: Fn(&T) -> K,
+    K: Ord,
+{
+    select_nth_unstable_by(v, index, |a, b| f(a).cmp(&f(b)))
+}
 
-    #[test]
-    fn simple_test3() {
-        let mut input = [4, 4, 4, 4, 4, 4, 1, 2];
-        debug!(input);
-        sort_test(&mut input);
-        debug!(input);
-        assert!(input.is_sorted());
-    }
+/// Moves the `k` smallest elements of `v` to its front in sorted order, the other elements
+/// follow in unspecified order.
+///
+/// # Panics
+/// If `k > v.len()`.
+///
+/// ```
+/// let mut v = [5, 1, 4, 2, 3];
+/// ips4o_rs::partial_sort(&mut v, 2);
+/// assert_eq!(v[..2], [1, 2]);
+/// ```
+#[inline]
+pub fn partial_sort<T>(v: &mut [T], k: usize)
+where
+    T: Ord,
+{
+    partial_sort_by(v, k, T::cmp);
+}
 
-    #[test]
-    fn simple_test4() {
-        let mut input = some_vec();
-        input.append(&mut some_vec());
-        debug!(input);
-        sort_test(&mut input);
-        debug!(input);
-        assert!(input.is_sorted());
-    }
+#[inline]
+pub fn partial_sort_by<T, F>(v: &mut [T], k: usize, compare: F)
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    assert!(
+        k <= v.len(),
+        "k = {k} is larger than the slice of length {}",
+        v.len()
+    );
+    ips4o_select(
+        v,
+        0..k,
+        |a, b| compare(a, b) == Ordering::Less,
+        &Ips4o::new().config::<T>(),
+    );
+}
 
-    #[test]
-    fn fuzz() {
-        // let mut rng = rand::thread_rng();
-        let mut rng = StdRng::seed_from_u64(0);
-        for _ in 0..5000 {
-            let len: usize = rng.gen_range(0..10000);
-            let (a, b) = (
-                rng.gen_range(u64::MIN..u64::MAX),
-                rng.gen_range(u64::MIN..u64::MAX),
-            );
-            let (lower, upper) = (min(a, b), max(a, b));
-            let input: Vec<_> = (0..len).map(|_| rng.gen_range(lower..upper)).collect();
-            sort_and_save_to_file_if_failed(input);
-        }
-    }
+#[inline]
+pub fn partial_sort_by_key<T, K, F>(v: &mut [T], k: usize, f: F)
+where
+    F: Fn(&T) -> K,
+    K: Ord,
+{
+    partial_sort_by(v, k, |a, b| f(a).cmp(&f(b)));
+}
 
-    #[ignore = "only used to reproduce failing test"]
-    #[test]
-    fn test_json_input() {
-        let input = fs::read_to_string(FAILING_INPUT).expect("no file found at given path");
-        let mut input: Vec<u64> = serde_json::from_str(&input).unwrap();
-        let mut sorted = input.clone();
-        sorted.sort();
-        sort_test(&mut input);
-        assert!(input.is_sorted());
-        assert!(input == sorted);
-    }
+/// Moves the `k` largest elements of `v` to its front, from largest to smallest, and returns
+/// them.
+///
+/// # Panics
+/// If `k > v.len()`.
+#[inline]
+pub fn top_k<T>(v: &mut [T], k: usize) -> &mut [T]
+where
+    T: Ord,
+{
+    partial_sort_by(v, k, |a, b| b.cmp(a));
+    &mut v[..k]
+}
 
-    #[test]
-    fn test_block_aligned_buckets() {
-        // blocks of size 8
-        let mut v = vec![];
-        for i in 0..200 {
-            v.append(&mut vec![i, i, i, i, i, i, i, i]);
-        }
+/// Sorts `v`, which consists of sorted runs that start at the indices in `run_boundaries`.
+///
+/// The runs are merged pairwise with a buffer as long as the longest of them, which takes
+/// *O*(*n* \* log(*k*)) comparisons for `k` runs. Inputs with many runs are sorted with [sort]
+/// instead. Boundaries `0` and `v.len()` are optional, repeated boundaries are ignored. If the
+/// runs are not sorted, `v` is left in an unspecified order.
+///
+/// # Panics
+/// If `run_boundaries` is not ascending or a boundary is larger than `v.len()`.
+///
+/// ```
+/// let mut v = [1, 4, 7, 2, 5, 8, 3, 6, 9];
+/// ips4o_rs::merge_sorted_runs(&mut v, &[3, 6]);
+/// assert_eq!(v, [1, 2, 3, 4, 5, 6, 7, 8, 9]);
+/// ```
+#[inline]
+pub fn merge_sorted_runs<T>(v: &mut [T], run_boundaries: &[usize])
+where
+    T: Ord,
+{
+    merge_sorted_runs_by(v, run_boundaries, T::cmp);
+}
 
-        let mut rng = StdRng::seed_from_u64(0);
-        for _ in 0..1 {
-            v.shuffle(&mut rng);
-            sort_test(&mut v);
-            assert!(v.is_sorted());
-        }
-    }
+#[inline]
+pub fn merge_sorted_runs_by<T, F>(v: &mut [T], run_boundaries: &[usize], compare: F)
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    ips4o_merge(
+        v,
+        run_boundaries,
+        |a, b| compare(a, b) == Ordering::Less,
+        &Ips4o::new().config::<T>(),
+    );
+}
 
-    #[test]
-    fn big_test() {
-        let len = 1usize << 20;
-        let mut rng = StdRng::seed_from_u64(0);
-        let range = Uniform::from(0..10_000);
-        let mut v: Vec<u32> = (0..len).map(|_| rng.sample(&range)).collect();
-        let mut sorted = v.clone();
-        sorted.sort();
-        sort_test(&mut v);
+#[inline]
+pub fn merge_sorted_runs_by_key<T, K, F>(v: &mut [T], run_boundaries: &[usize], f: F)
+where
+    F: Fn(&T) -> K,
+    K: Ord,
+{
+    merge_sorted_runs_by(v, run_boundaries, |a, b| f(a).cmp(&f(b)));
+}
+
+/// Sorts `v` like [sort] and returns the presorted runs that were merged instead of
+/// partitioned.
+///
+/// All fields of the result are zero if the sample of `v` did not look presorted or `v` has too
+/// many runs to merge them.
+///
+/// ```
+/// let mut v: Vec<u32> = (0..1000).chain((0..1000).rev()).collect();
+/// let stats = ips4o_rs::sort_adaptive(&mut v);
+/// assert!(v.windows(2).all(|w| w[0] <= w[1]));
+/// assert_eq!((stats.runs, stats.reversed_runs), (2, 1));
+/// ```
+#[inline]
+pub fn sort_adaptive<T>(v: &mut [T]) -> Presortedness
+where
+    T: Ord,
+{
+    sort_adaptive_by(v, T::cmp)
+}
+
+#[inline]
+pub fn sort_adaptive_by<T, F>(v: &mut [T], compare: F) -> Presortedness
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    ips4o(
+        v,
+        |a, b| compare(a, b) == Ordering::Less,
+        &Ips4o::new().config::<T>(),
+        &mut Vec::new(),
+    )
+}
+
+#[inline]
+pub fn sort_adaptive_by_key<T, K, F>(v: &mut [T], f: F) -> Presortedness
+where
+    F: Fn(&T) -> K,
+    K: Ord,
+{
+    sort_adaptive_by(v, |a, b| f(a).cmp(&f(b)))
+}
+
+/// Sorts `v`, moves one element of every group of equal elements to its front and returns
+/// their number, like [sort] followed by [Vec::dedup].
+///
+/// The duplicates follow the unique elements in unspecified order, so with a `Vec` they can be
+/// removed with [Vec::truncate].
+///
+/// ```
+/// let mut v = vec![3, 1, 3, 2, 1];
+/// let len = ips4o_rs::sort_dedup(&mut v);
+/// v.truncate(len);
+/// assert_eq!(v, [1, 2, 3]);
+/// ```
+#[inline]
+pub fn sort_dedup<T>(v: &mut [T]) -> usize
+where
+    T: Ord,
+{
+    sort_dedup_by(v, T::cmp)
+}
+
+/// Like [sort_dedup], elements are duplicates if `compare` returns [Ordering::Equal].
+#[inline]
+pub fn sort_dedup_by<T, F>(v: &mut [T], compare: F) -> usize
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    ips4o_dedup(
+        v,
+        |a, b| compare(a, b) == Ordering::Less,
+        &Ips4o::new().config::<T>(),
+    )
+}
+
+#[inline]
+pub fn sort_dedup_by_key<T, K, F>(v: &mut [T], f: F) -> usize
+where
+    F: Fn(&T) -> K,
+    K: Ord,
+{
+    sort_dedup_by(v, |a, b| f(a).cmp(&f(b)))
+}
+
+/// Parallel version of [sort_dedup]
+#[inline]
+pub fn sort_par_dedup<T>(v: &mut [T]) -> usize
+where
+    T: Ord + Send + Sync,
+{
+    sort_par_dedup_by(v, T::cmp)
+}
+
+#[inline]
+pub fn sort_par_dedup_by<T, F>(v: &mut [T], compare: F) -> usize
+where
+    T: Send + Sync,
+    F: Fn(&T, &T) -> Ordering + Sync,
+{
+    ips4o_dedup_par(
+        v,
+        |a, b| compare(a, b) == Ordering::Less,
+        &Ips4o::new().config::<T>(),
+    )
+}
+
+#[inline]
+pub fn sort_par_dedup_by_key<T, K, F>(v: &mut [T], f: F) -> usize
+where
+    T: Send + Sync,
+    F: Fn(&T) -> K + Sync,
+    K: Ord,
+{
+    sort_par_dedup_by(v, |a, b| f(a).cmp(&f(b)))
+}
+
+/// Sorts `v` by the keys that `key` extracts, folds every group of elements with equal keys
+/// into its first element and returns the number of groups.
+///
+/// `reduce(a, b)` folds `b` into `a` and must not change the key of `a`. Afterwards the groups
+/// are at the front of `v` in sorted order, followed by the folded elements in unspecified
+/// order. Buckets of equal keys are folded while partitioning, without sorting them.
+///
+/// ```
+/// let mut words = vec![("b", 1), ("a", 1), ("b", 1), ("b", 1)];
+/// let len = ips4o_rs::sort_and_reduce_by_key(&mut words, |w| w.0, |a, b| a.1 += b.1);
+/// assert_eq!(words[..len], [("a", 1), ("b", 3)]);
+/// ```
+#[inline]
+pub fn sort_and_reduce_by_key<T, K, F, R>(v: &mut [T], key: F, reduce: R) -> usize
+where
+    F: Fn(&T) -> K,
+    K: Ord,
+    R: FnMut(&mut T, &mut T),
+{
+    Ips4o::new().sort_and_reduce_by_key(v, key, reduce)
+}
+
+/// Parallel version of [sort_and_reduce_by_key], which reduces the groups after sorting.
+#[inline]
+pub fn sort_par_and_reduce_by_key<T, K, F, R>(v: &mut [T], key: F, reduce: R) -> usize
+where
+    T: Send + Sync,
+    F: Fn(&T) -> K + Sync,
+    K: Ord,
+    R: Fn(&mut T, &mut T) + Sync,
+{
+    Ips4o::new().sort_par_and_reduce_by_key(v, key, reduce)
+}
+
+/// Sorts `v` like [sort], but keeps equal elements in their original order.
+#[inline]
+pub fn stable_sort<T>(v: &mut [T])
+where
+    T: Ord,
+{
+    Ips4o::new().stable_sort(v);
+}
+
+#[inline]
+pub fn stable_sort_by<T, F>(v: &mut [T], compare: F)
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    Ips4o::new().stable_sort_by(v, compare);
+}
+
+#[inline]
+pub fn stable_sort_by_key<T, K, F>(v: &mut [T], f: F)
+where
+    F: Fn(&T) -> K,
+    K: Ord,
+{
+    Ips4o::new().stable_sort_by_key(v, f);
+}
+
+/// Sorts `v` like [sort_par], but keeps equal elements in their original order.
+#[inline]
+pub fn stable_sort_par<T>(v: &mut [T])
+where
+    T: Ord + Send + Sync,
+{
+    Ips4o::new().stable_sort_par(v);
+}
+
+#[inline]
+pub fn stable_sort_par_by<T, F>(v: &mut [T], compare: F)
+where
+    T: Send + Sync,
+    F: Fn(&T, &T) -> Ordering + Sync,
+{
+    Ips4o::new().stable_sort_par_by(v, compare);
+}
+
+#[inline]
+pub fn stable_sort_par_by_key<T, K, F>(v: &mut [T], f: F)
+where
+    T: Send + Sync,
+    F: Fn(&T) -> K + Sync,
+    K: Ord,
+{
+    Ips4o::new().stable_sort_par_by_key(v, f);
+}
+
+/// Sorts `keys` and moves the elements of `values` along, so `keys[i]` and `values[i]` stay
+/// together. Like [sort], the sort is unstable, i.e. it may reorder rows with equal keys.
+///
+/// The rows are moved in place, by the same block permutation as the keys. Besides the buffers
+/// of [sort], this only takes a block of values per bucket, see [sort_zip_columns] for more
+/// than one payload column.
+///
+/// # Panics
+/// If `keys` and `values` have different lengths.
+///
+/// ```
+/// let mut keys = [3, 1, 2];
+/// let mut values = ["c", "a", "b"];
+/// ips4o_rs::sort_zip(&mut keys, &mut values);
+/// assert_eq!(values, ["a", "b", "c"]);
+/// ```
+#[inline]
+pub fn sort_zip<K, V>(keys: &mut [K], values: &mut [V])
+where
+    K: Ord,
+{
+    Ips4o::new().sort_zip(keys, values);
+}
+
+/// Parallel version of [sort_zip]
+#[inline]
+pub fn sort_par_zip<K, V>(keys: &mut [K], values: &mut [V])
+where
+    K: Ord + Send + Sync,
+    V: Send,
+{
+    Ips4o::new().sort_par_zip(keys, values);
+}
+
+/// Sorts `keys` like [sort_zip] and moves the rows of any number of payload columns along, see
+/// [Columns].
+///
+/// Every column is moved in place like the values of [sort_zip], with buffers of its own.
+///
+/// # Panics
+/// If a column has a different length than `keys`.
+///
+/// ```
+/// let mut ids = [3, 1, 2];
+/// let mut names = ["c", "a", "b"];
+/// let mut ages = [30, 10, 20];
+/// ips4o_rs::sort_zip_columns(&mut ids, (&mut names[..], &mut ages[..]));
+/// assert_eq!((names, ages), (["a", "b", "c"], [10, 20, 30]));
+/// ```
+#[inline]
+pub fn sort_zip_columns<K, C>(keys: &mut [K], columns: C)
+where
+    K: Ord,
+    C: Columns,
+{
+    Ips4o::new().sort_zip_columns(keys, columns);
+}
+
+/// Parallel version of [sort_zip_columns]
+#[inline]
+pub fn sort_par_zip_columns<K, C>(keys: &mut [K], columns: C)
+where
+    K: Ord + Send + Sync,
+    C: Columns + Send,
+{
+    Ips4o::new().sort_par_zip_columns(keys, columns);
+}
+
+/// Sorts `v` with an in-place radix sort, which looks at the bits of the elements instead of
+/// comparing them.
+#[inline]
+pub fn radix_sort<T>(v: &mut [T])
+where
+    T: RadixKey,
+{
+    Ips4o::new().radix_sort(v);
+}
+
+#[inline]
+pub fn radix_sort_by_key<T, K, F>(v: &mut [T], f: F)
+where
+    F: Fn(&T) -> K,
+    K: RadixKey,
+{
+    Ips4o::new().radix_sort_by_key(v, f);
+}
+
+/// Parallel version of [radix_sort]
+#[inline]
+pub fn radix_sort_par<T>(v: &mut [T])
+where
+    T: RadixKey + Send + Sync,
+{
+    Ips4o::new().radix_sort_par(v);
+}
+
+#[inline]
+pub fn radix_sort_par_by_key<T, K, F>(v: &mut [T], f: F)
+where
+    T: Send + Sync,
+    F: Fn(&T) -> K + Sync,
+    K: RadixKey,
+{
+    Ips4o::new().radix_sort_par_by_key(v, f);
+}
+
+/// Sorts `v` and returns the presorted runs that were merged instead of partitioned
+fn ips4o<T, F>(v: &mut [T], is_less: F, config: &Config, pool: &mut BufferPool<T>) -> Presortedness
+where
+    T: Sortable,
+    F: Less<T>,
+{
+    // Sorting has no meaningful behavior on zero-sized types. Do nothing.
+    if size_of::<T>() == 0 {
+        return Presortedness::default();
+    }
+    let sort_gap = |gap: &mut [T]| partition_sort(gap, &is_less, config, pool);
+    if let Some(stats) = merge_presorted(v, &is_less, config, sort_gap) {
+        return stats;
+    }
+    partition_sort(v, &is_less, config, pool);
+    Presortedness::default()
+}
+
+/// Sorts `v` without looking for presorted runs
+fn partition_sort<T, F>(v: &mut [T], is_less: &F, config: &Config, pool: &mut BufferPool<T>)
+where
+    T: Sortable,
+    F: Less<T>,
+{
+    if v.len() <= BASE_CASE_MULTIPLIER * config.base_case_size {
+        base_case::base_case_sort(v, is_less);
+        return;
+    }
+    sequential_ips4o(v, is_less, config, pool);
+}
+
+/// Parallel version of [ips4o]
+fn ips4o_par<T, F>(
+    v: &mut [T],
+    is_less: F,
+    config: &Config,
+    pool: &mut BufferPool<T>,
+) -> Presortedness
+where
+    T: PSortable,
+    F: PLess<T>,
+{
+    // Sorting has no meaningful behavior on zero-sized types. Do nothing.
+    if size_of::<T>() == 0 {
+        return Presortedness::default();
+    }
+    let sort_gap = |gap: &mut [T]| partition_sort_par(gap, &is_less, config, pool);
+    if let Some(stats) = merge_presorted(v, &is_less, config, sort_gap) {
+        return stats;
+    }
+    partition_sort_par(v, &is_less, config, pool);
+    Presortedness::default()
+}
+
+/// Parallel version of [partition_sort]
+fn partition_sort_par<T, F>(v: &mut [T], is_less: &F, config: &Config, pool: &mut BufferPool<T>)
+where
+    T: PSortable,
+    F: PLess<T>,
+{
+    if v.len() <= BASE_CASE_MULTIPLIER * config.base_case_size {
+        base_case::base_case_sort(v, is_less);
+        return;
+    }
+    // Sorting in parallel makes no sense with only one thread
+    let num_threads = config.num_threads();
+    if num_threads == 1 || v.len() <= config.min_parallel_len(num_threads) {
+        sequential_ips4o(v, is_less, config, pool);
+        return;
+    }
+    parallel_ips4o(v, is_less, config, pool, num_threads);
+}
+
+fn ips4o_stable<T, F>(v: &mut [T], is_less: F, config: &Config)
+where
+    T: Sortable,
+    F: Less<T>,
+{
+    // Sorting has no meaningful behavior on zero-sized types. Do nothing.
+    if size_of::<T>() == 0 {
+        return;
+    }
+    // sort_simple_cases() reverses descending inputs, which would reorder equal elements
+    if v.windows(2).all(|w| !is_less(&w[1], &w[0])) {
+        return;
+    }
+    if v.len() <= BASE_CASE_MULTIPLIER * config.base_case_size {
+        base_case::stable_base_case_sort(v, &is_less);
+        return;
+    }
+    stable_sequential_ips4o(v, &is_less, config);
+}
+
+fn ips4o_stable_par<T, F>(v: &mut [T], is_less: F, config: &Config)
+where
+    T: PSortable,
+    F: PLess<T>,
+{
+    // Sorting has no meaningful behavior on zero-sized types. Do nothing.
+    if size_of::<T>() == 0 {
+        return;
+    }
+    // See ips4o_stable()
+    if v.windows(2).all(|w| !is_less(&w[1], &w[0])) {
+        return;
+    }
+    if v.len() <= BASE_CASE_MULTIPLIER * config.base_case_size {
+        base_case::stable_base_case_sort(v, &is_less);
+        return;
+    }
+    // Sorting in parallel makes no sense with only one thread
+    let num_threads = config.num_threads();
+    if num_threads == 1 || v.len() <= config.min_parallel_len(num_threads) {
+        stable_sequential_ips4o(v, &is_less, config);
+        return;
+    }
+    stable_parallel_ips4o(v, &is_less, config, num_threads);
+}
+
+fn ips4o_select<T, F>(v: &mut [T], ranks: Range<usize>, is_less: F, config: &Config)
+where
+    T: Sortable,
+    F: Less<T>,
+{
+    debug_assert!(ranks.end <= v.len());
+    // Sorting has no meaningful behavior on zero-sized types. Do nothing.
+    if size_of::<T>() == 0 || ranks.is_empty() {
+        return;
+    }
+    if v.len() <= BASE_CASE_MULTIPLIER * config.base_case_size {
+        base_case::base_case_sort(v, &is_less);
+        return;
+    }
+    select_sequential(v, ranks, &is_less, config);
+}
+
+fn ips4o_merge<T, F>(v: &mut [T], run_boundaries: &[usize], is_less: F, config: &Config)
+where
+    T: Sortable,
+    F: Less<T>,
+{
+    assert!(
+        run_boundaries.windows(2).all(|w| w[0] <= w[1])
+            && run_boundaries.iter().all(|&b| b <= v.len()),
+        "run boundaries must be ascending and inside of the slice"
+    );
+    // Sorting has no meaningful behavior on zero-sized types. Do nothing.
+    if size_of::<T>() == 0 || v.len() < 2 {
+        return;
+    }
+    let mut runs = vec![0];
+    runs.extend(run_boundaries.iter().filter(|&&b| 0 < b && b < v.len()));
+    runs.push(v.len());
+    runs.dedup();
+    if runs.len() - 1 > MAX_MERGE_RUNS {
+        ips4o(v, is_less, config, &mut Vec::new());
+        return;
+    }
+    merge_runs(v, &runs, &is_less);
+}
+
+fn ips4o_dedup<T, F>(v: &mut [T], is_less: F, config: &Config) -> usize
+where
+    T: Sortable,
+    F: Less<T>,
+{
+    ips4o_reduce(v, is_less, |_, _| {}, config)
+}
+
+fn ips4o_reduce<T, F, R>(v: &mut [T], is_less: F, reduce: R, config: &Config) -> usize
+where
+    T: Sortable,
+    F: Less<T>,
+    R: FnMut(&mut T, &mut T),
+{
+    // See ips4o(), zero-sized elements are only reduced
+    if size_of::<T>() != 0 && !sort_simple_cases(v, &is_less) {
+        if v.len() > BASE_CASE_MULTIPLIER * config.base_case_size {
+            return sequential_reduce(v, &is_less, reduce, config, &mut Vec::new());
+        }
+        base_case::base_case_sort(v, &is_less);
+    }
+    reduce_sorted(v, &is_less, reduce)
+}
+
+fn ips4o_dedup_par<T, F>(v: &mut [T], is_less: F, config: &Config) -> usize
+where
+    T: PSortable,
+    F: PLess<T>,
+{
+    ips4o_reduce_par(v, is_less, |_, _| {}, config)
+}
+
+fn ips4o_reduce_par<T, F, R>(v: &mut [T], is_less: F, reduce: R, config: &Config) -> usize
+where
+    T: PSortable,
+    F: PLess<T>,
+    R: Fn(&mut T, &mut T) + Sync,
+{
+    // See ips4o_par()
+    let num_threads = config.num_threads();
+    if num_threads == 1 || v.len() <= config.min_parallel_len(num_threads) {
+        return ips4o_reduce(v, is_less, reduce, config);
+    }
+    ips4o_par(v, &is_less, config, &mut Vec::new());
+    reduce_sorted_par(v, &is_less, reduce, num_threads)
+}
+
+fn ips4o_zip<K, C, F>(keys: &mut [K], columns: C, is_less: F, config: &Config)
+where
+    K: Sortable,
+    C: Columns,
+    F: Less<K>,
+{
+    assert_eq!(
+        columns.num_rows(),
+        Some(keys.len()),
+        "keys and columns must have the same length"
+    );
+    // Zero-sized keys are all equal, so the rows already are in order
+    if size_of::<K>() == 0 {
+        return;
+    }
+    // sort_simple_cases() would reverse descending keys without their rows
+    if keys.windows(2).all(|w| !is_less(&w[1], &w[0])) {
+        return;
+    }
+    let rows = columns.into_payload();
+    if keys.len() <= BASE_CASE_MULTIPLIER * config.base_case_size {
+        base_case_sort_rows(keys, rows, &is_less);
+        return;
+    }
+    zip_sequential_ips4o(keys, rows, &is_less, config);
+}
+
+fn ips4o_zip_par<K, C, F>(keys: &mut [K], columns: C, is_less: F, config: &Config)
+where
+    K: PSortable,
+    C: Columns + Send,
+    F: PLess<K>,
+{
+    // See ips4o_zip()
+    assert_eq!(
+        columns.num_rows(),
+        Some(keys.len()),
+        "keys and columns must have the same length"
+    );
+    if size_of::<K>() == 0 {
+        return;
+    }
+    if keys.windows(2).all(|w| !is_less(&w[1], &w[0])) {
+        return;
+    }
+    let rows = columns.into_payload();
+    if keys.len() <= BASE_CASE_MULTIPLIER * config.base_case_size {
+        base_case_sort_rows(keys, rows, &is_less);
+        return;
+    }
+    // Sorting in parallel makes no sense with only one thread
+    let num_threads = config.num_threads();
+    if num_threads == 1 || keys.len() <= config.min_parallel_len(num_threads) {
+        zip_sequential_ips4o(keys, rows, &is_less, config);
+        return;
+    }
+    zip_parallel_ips4o(keys, rows, &is_less, config, num_threads);
+}
+
+fn ips2ra<T, K, F>(v: &mut [T], key: F, config: &Config)
+where
+    F: Fn(&T) -> K,
+    K: RadixKey,
+{
+    // Sorting has no meaningful behavior on zero-sized types. Do nothing.
+    if size_of::<T>() == 0 {
+        return;
+    }
+    let is_less = |a: &T, b: &T| key(a).radix_lt(&key(b));
+    if sort_simple_cases(v, &is_less) {
+        return;
+    }
+    if v.len() <= BASE_CASE_MULTIPLIER * config.base_case_size {
+        base_case::base_case_sort(v, &is_less);
+        return;
+    }
+    radix_sequential(v, &key, &is_less, config);
+    debug_assert!(v.is_sorted_by(is_less_to_compare!(is_less)));
+}
+
+fn ips2ra_par<T, K, F>(v: &mut [T], key: F, config: &Config)
+where
+    T: PSortable,
+    F: Fn(&T) -> K + Sync,
+    K: RadixKey,
+{
+    // Sorting has no meaningful behavior on zero-sized types. Do nothing.
+    if size_of::<T>() == 0 {
+        return;
+    }
+    let is_less = |a: &T, b: &T| key(a).radix_lt(&key(b));
+    if sort_simple_cases(v, &is_less) {
+        return;
+    }
+    if v.len() <= BASE_CASE_MULTIPLIER * config.base_case_size {
+        base_case::base_case_sort(v, &is_less);
+        return;
+    }
+    // Sorting in parallel makes no sense with only one thread
+    let num_threads = config.num_threads();
+    if num_threads == 1 || v.len() <= config.min_parallel_len(num_threads) {
+        radix_sequential(v, &key, &is_less, config);
+    } else {
+        radix_parallel(v, &key, &is_less, config, num_threads);
+    }
+    debug_assert!(v.is_sorted_by(is_less_to_compare!(is_less)));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        array,
+        cell::Cell,
+        cmp::{max, min, Ordering},
+        collections::BTreeMap,
+        fmt::Debug,
+        fs, panic,
+        sync::{
+            atomic::{self, AtomicUsize},
+            Mutex,
+        },
+        thread,
+    };
+
+    use rand::{distributions::Uniform, rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+    use rayon::ThreadPoolBuilder;
+
+    use crate::{
+        argsort_by, argsort_by_key, argsort_par, argsort_par_by, argsort_par_by_key,
+        config::Config, debug, ips4o, ips4o_dedup, ips4o_dedup_par, ips4o_merge, ips4o_par,
+        ips4o_reduce, ips4o_reduce_par, ips4o_select, ips4o_stable, ips4o_stable_par, ips4o_zip,
+        ips4o_zip_par, merge_sorted_runs_by_key, partial_sort, radix_sort, radix_sort_by_key,
+        radix_sort_par, radix_sort_par_by_key, select_nth_unstable, sort, sort_adaptive_by_key,
+        sort_by, sort_by_key, sort_dedup_by, sort_dedup_by_key, sort_par,
+        sort_par_and_reduce_by_key, sort_par_by, sort_par_by_cached_key, sort_par_by_in,
+        sort_par_by_key, sort_par_by_key_in, sort_par_dedup, sort_par_dedup_by_key, sort_par_in,
+        stable_sort_by, stable_sort_par_by, top_k, AtomicPointers, Ips4o, PSortable, RadixKey,
+    };
+
+    const TEST_PARALLEL: bool = false;
+
+    const FAILING_INPUT: &str = "./target/failing_input.json";
+
+    fn sort_test<T>(input: &mut [T])
+    where
+        T: Ord + PSortable,
+    {
+        if TEST_PARALLEL {
+            sort_par(input)
+        } else {
+            sort(input)
+        }
+    }
+
+    fn sort_and_save_to_file_if_failed(mut input: Vec<u64>) {
+        let clone = input.clone();
+        let result = panic::catch_unwind(move || {
+            sort_test(&mut input);
+            input
+        });
+        match result {
+            Ok(sorted_input) => {
+                let mut sorted = clone.clone();
+                sorted.sort();
+                if sorted != sorted_input {
+                    let data =
+                        serde_json::to_string(&clone).expect("unable to serialize failing slice");
+                    fs::write(FAILING_INPUT, data).expect("unable to write failing slice to file");
+                    panic!("result is not a sorted permutation of its input")
+                }
+            }
+            Err(_e) => {
+                let data =
+                    serde_json::to_string(&clone).expect("unable to serialize failing slice");
+                fs::write(FAILING_INPUT, data).expect("unable to write failing slice to file");
+                panic!()
+            }
+        }
+    }
+
+    /// Parameters that every variant is tested with: the defaults, the fewest buckets, no equal
+    /// buckets, and tiny blocks and base cases, which also partition short slices in parallel.
+    fn test_configs() -> [Ips4o; 5] {
+        [
+            Ips4o::new(),
+            Ips4o::new().log_buckets(1),
+            Ips4o::new().equal_bucket_threshold(usize::MAX),
+            Ips4o::new()
+                .log_buckets(1)
+                .equal_bucket_threshold(usize::MAX),
+            Ips4o::new()
+                .block_size(3)
+                .log_buckets(2)
+                .base_case_size(1)
+                .min_parallel_blocks_per_thread(1),
+        ]
+    }
+
+    /// Calls `test` with the config of every [test_configs] entry for elements of type `T` and
+    /// with a seeded random generator, inside of a pool with four threads.
+    fn check_configs<T>(mut test: impl FnMut(&Config, &mut StdRng) + Send) {
+        let mut rng = StdRng::seed_from_u64(0);
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        for params in test_configs() {
+            pool.install(|| test(&params.config::<T>(), &mut rng));
+        }
+    }
+
+    /// Asserts that `sort` orders a copy of `input` like the standard library's sort.
+    fn assert_sorts_like_std<T, S>(input: &[T], sort: S)
+    where
+        T: Ord + Clone + Debug,
+        S: FnOnce(&mut [T]),
+    {
+        let mut expected = input.to_vec();
+        expected.sort();
+        let mut v = input.to_vec();
+        sort(&mut v);
+        assert_eq!(v, expected);
+    }
+
+    #[test]
+    fn simple_test1() {
+        let mut input = some_vec();
+        input.append(&mut some_vec());
+        input.append(&mut some_vec());
+        input.append(&mut some_vec());
+        input.append(&mut some_vec());
+        debug!(input);
+        sort_test(&mut input);
+        debug!(input);
+    }
+
+    #[test]
+    fn simple_test2() {
+        let mut input = [
+            1, 9, 26, 29, 1, 2, 3, 4, 5, 6, 7, 8, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21,
+            22, 23, 24, 25, 27, 28,
+        ];
+        debug!(input);
+        sort_test(&mut input);
+        debug!(input);
+        assert!(input.is_sorted());
+    }
+
+    #[test]
+    fn simple_test3() {
+        let mut input = [4, 4, 4, 4, 4, 4, 1, 2];
+        debug!(input);
+        sort_test(&mut input);
+        debug!(input);
+        assert!(input.is_sorted());
+    }
+
+    #[test]
+    fn simple_test4() {
+        let mut input = some_vec();
+        input.append(&mut some_vec());
+        debug!(input);
+        sort_test(&mut input);
+        debug!(input);
+        assert!(input.is_sorted());
+    }
+
+    #[test]
+    fn fuzz() {
+        // let mut rng = rand::thread_rng();
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..5000 {
+            let len: usize = rng.gen_range(0..10000);
+            let (a, b) = (
+                rng.gen_range(u64::MIN..u64::MAX),
+                rng.gen_range(u64::MIN..u64::MAX),
+            );
+            let (lower, upper) = (min(a, b), max(a, b));
+            let input: Vec<_> = (0..len).map(|_| rng.gen_range(lower..upper)).collect();
+            sort_and_save_to_file_if_failed(input);
+        }
+    }
+
+    #[ignore = "only used to reproduce failing test"]
+    #[test]
+    fn test_json_input() {
+        let input = fs::read_to_string(FAILING_INPUT).expect("no file found at given path");
+        let mut input: Vec<u64> = serde_json::from_str(&input).unwrap();
+        let mut sorted = input.clone();
+        sorted.sort();
+        sort_test(&mut input);
+        assert!(input.is_sorted());
+        assert!(input == sorted);
+    }
+
+    #[test]
+    fn test_block_aligned_buckets() {
+        // blocks of size 8
+        let mut v = vec![];
+        for i in 0..200 {
+            v.append(&mut vec![i, i, i, i, i, i, i, i]);
+        }
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..1 {
+            v.shuffle(&mut rng);
+            sort_test(&mut v);
+            assert!(v.is_sorted());
+        }
+    }
+
+    #[test]
+    fn big_test() {
+        let len = 1usize << 20;
+        let mut rng = StdRng::seed_from_u64(0);
+        let range = Uniform::from(0..10_000);
+        let mut v: Vec<u32> = (0..len).map(|_| rng.sample(&range)).collect();
+        let mut sorted = v.clone();
+        sorted.sort();
+        sort_test(&mut v);
         assert!(v.is_sorted());
         assert!(v == sorted);
     }
 
+    #[test]
+    fn sort_par_by_variants() {
+        let len = 1usize << 16;
+        let mut rng = StdRng::seed_from_u64(0);
+        let input: Vec<(u32, u32)> = (0..len)
+            .map(|_| (rng.gen_range(0..1000), rng.gen_range(0..1000)))
+            .collect();
+
+        let mut v = input.clone();
+        sort_par_by(&mut v, |a, b| b.cmp(a));
+        let mut expected = input.clone();
+        expected.sort_by(|a, b| b.cmp(a));
+        assert!(v == expected);
+
+        let mut v = input.clone();
+        sort_par_by_key(&mut v, |a| a.1);
+        assert!(v.windows(2).all(|w| w[0].1 <= w[1].1));
+
+        // Equal keys keep their order, also with several threads
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let mut v: Vec<String> = input.iter().map(|(a, b)| format!("{a}-{b}")).collect();
+        let mut expected = v.clone();
+        pool.install(|| sort_par_by_cached_key(&mut v, |s| s.len()));
+        expected.sort_by_key(|s| s.len());
+        assert!(v == expected);
+    }
+
+    #[test]
+    fn sort_without_default_and_debug() {
+        #[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+        struct Key(u64);
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let input: Vec<u64> = (0..50_000).map(|_| rng.gen_range(0..5000)).collect();
+        let mut v: Vec<Key> = input.iter().copied().map(Key).collect();
+        sort(&mut v);
+        let mut expected = input.clone();
+        expected.sort();
+        assert!(v.iter().map(|k| k.0).eq(expected.iter().copied()));
+
+        let mut v: Vec<String> = input.iter().map(u64::to_string).collect();
+        sort_by(&mut v, |a, b| b.cmp(a));
+        let mut expected: Vec<String> = input.iter().map(u64::to_string).collect();
+        expected.sort_by(|a, b| b.cmp(a));
+        assert!(v == expected);
+    }
+
+    #[test]
+    fn sort_move_only() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let input: Vec<u64> = (0..50_000).map(|_| rng.gen_range(0..5000)).collect();
+        let mut expected = input.clone();
+        expected.sort();
+
+        let mut v: Vec<Box<u64>> = input.iter().copied().map(Box::new).collect();
+        sort(&mut v);
+        assert!(v.iter().map(|b| **b).eq(expected.iter().copied()));
+
+        let mut v: Vec<Vec<u64>> = input.iter().map(|&x| vec![x; (x % 4) as usize]).collect();
+        sort_by_key(&mut v, |x| x.first().copied());
+        let mut expected: Vec<Vec<u64>> =
+            input.iter().map(|&x| vec![x; (x % 4) as usize]).collect();
+        expected.sort_by_key(|x| x.first().copied());
+        assert!(v == expected);
+    }
+
+    #[test]
+    fn sort_drops_every_element_once() {
+        thread_local!(static DROPS: Cell<usize> = const { Cell::new(0) });
+
+        #[derive(PartialEq, Eq, PartialOrd, Ord)]
+        struct DropCounter(u64);
+        impl Drop for DropCounter {
+            fn drop(&mut self) {
+                DROPS.with(|d| d.set(d.get() + 1));
+            }
+        }
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let len = 50_000;
+        let mut v: Vec<DropCounter> = (0..len)
+            .map(|_| DropCounter(rng.gen_range(0..1000)))
+            .collect();
+        sort(&mut v);
+        assert!(v.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(DROPS.with(Cell::get), 0);
+        drop(v);
+        assert_eq!(DROPS.with(Cell::get), len);
+    }
+
+    /// Panics at the `panic_at`-th comparison and checks that the input is still a permutation of
+    /// the original input, and that every element gets dropped exactly once
+    fn sort_with_panicking_comparison(input: &[u64], panic_at: usize) {
+        thread_local!(static DROPS: Cell<usize> = const { Cell::new(0) });
+
+        struct DropCounter(u64);
+        impl Drop for DropCounter {
+            fn drop(&mut self) {
+                DROPS.with(|d| d.set(d.get() + 1));
+            }
+        }
+
+        DROPS.with(|d| d.set(0));
+        let mut v: Vec<DropCounter> = input.iter().copied().map(DropCounter).collect();
+        let comparisons = Cell::new(0);
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            sort_by(&mut v, |a, b| {
+                comparisons.set(comparisons.get() + 1);
+                if comparisons.get() == panic_at {
+                    panic!("injected panic");
+                }
+                a.0.cmp(&b.0)
+            })
+        }));
+        // The number of comparisons depends on the random sample, so the panic might not happen
+        assert!(result.is_err() || v.windows(2).all(|w| w[0].0 <= w[1].0));
+        assert_eq!(DROPS.with(Cell::get), 0);
+
+        let mut values: Vec<u64> = v.iter().map(|x| x.0).collect();
+        values.sort();
+        let mut expected = input.to_vec();
+        expected.sort();
+        assert!(values == expected);
+        drop(v);
+        assert_eq!(DROPS.with(Cell::get), input.len());
+    }
+
+    fn count_comparisons(input: &[u64], parallel: bool) -> usize {
+        let comparisons = AtomicUsize::new(0);
+        let mut v = input.to_vec();
+        let compare = |a: &u64, b: &u64| {
+            comparisons.fetch_add(1, atomic::Ordering::Relaxed);
+            a.cmp(b)
+        };
+        if parallel {
+            sort_par_by(&mut v, compare);
+        } else {
+            sort_by(&mut v, compare);
+        }
+        comparisons.into_inner()
+    }
+
+    #[test]
+    fn panic_safety() {
+        let mut rng = StdRng::seed_from_u64(0);
+        // Large enough for two levels of recursion
+        let input: Vec<u64> = (0..20_000).map(|_| rng.gen_range(0..1000)).collect();
+        let total = count_comparisons(&input, false);
+        // Hit every phase at least once: sampling, classification, permutation, cleanup
+        // and the base cases
+        for panic_at in (1..total).step_by(total / 97) {
+            sort_with_panicking_comparison(&input, panic_at);
+        }
+    }
+
+    #[test]
+    fn panic_safety_parallel() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let input: Vec<u64> = (0..1 << 18).map(|_| rng.gen_range(0..100_000)).collect();
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let total = pool.install(|| count_comparisons(&input, true));
+        for panic_at in (1..total).step_by(total / 47) {
+            let comparisons = AtomicUsize::new(0);
+            let mut v = input.clone();
+            let _ = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                pool.install(|| {
+                    sort_par_by(&mut v, |a, b| {
+                        if comparisons.fetch_add(1, atomic::Ordering::Relaxed) + 1 == panic_at {
+                            panic!("injected panic");
+                        }
+                        a.cmp(b)
+                    })
+                })
+            }));
+            v.sort();
+            let mut expected = input.clone();
+            expected.sort();
+            assert!(v == expected);
+        }
+    }
+
+    /// Sorts with comparison functions that are not strict weak orders, the result is unspecified
+    /// but has to be a permutation of the input. Panics are allowed, e.g. by debug assertions.
+    fn sort_with_inconsistent_comparisons(parallel: bool) {
+        let mut rng = StdRng::seed_from_u64(0);
+        let random = Mutex::new(StdRng::seed_from_u64(1));
+        type Comparison<'a> = &'a (dyn Fn(&u64, &u64) -> Ordering + Sync);
+        let comparisons: [Comparison; 3] = [
+            &|_, _| match random.lock().unwrap().gen_range(0..3) {
+                0 => Ordering::Less,
+                1 => Ordering::Equal,
+                _ => Ordering::Greater,
+            },
+            // Not transitive
+            &|a, b| match (b % 3 + 3 - a % 3) % 3 {
+                0 => Ordering::Equal,
+                1 => Ordering::Less,
+                _ => Ordering::Greater,
+            },
+            // Multiples of 7 are smaller than everything else, and also bigger
+            &|a, b| {
+                if a % 7 == 0 {
+                    Ordering::Less
+                } else {
+                    a.cmp(b)
+                }
+            },
+        ];
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        for len in [100, 1_000, 10_000, 100_000, 1 << 18] {
+            let input: Vec<u64> = (0..len).map(|_| rng.gen_range(0..1 << 20)).collect();
+            for compare in comparisons {
+                let mut v = input.clone();
+                let _ = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                    if parallel {
+                        pool.install(|| sort_par_by(&mut v, compare));
+                    } else {
+                        sort_by(&mut v, compare);
+                    }
+                }));
+                v.sort();
+                let mut expected = input.clone();
+                expected.sort();
+                assert!(v == expected);
+            }
+        }
+    }
+
+    #[test]
+    fn inconsistent_comparison() {
+        sort_with_inconsistent_comparisons(false);
+    }
+
+    #[test]
+    fn inconsistent_comparison_parallel() {
+        sort_with_inconsistent_comparisons(true);
+    }
+
+    /// Sorts pairs by their first value, the second value is the position in the input. The
+    /// sort is stable iff it orders the pairs like a sort by both values.
+    fn check_stable_sort(parallel: bool) {
+        check_configs::<(u32, usize)>(|config, rng| {
+            for len in [0, 10, 100, 1_000, 10_000, 100_000, 1 << 20] {
+                let reversed = (0..len).map(|i| ((len - i) as u32 / 3, i)).collect();
+                let mut inputs: Vec<Vec<(u32, usize)>> = vec![reversed];
+                for keys in [2, 100, 1 << 30] {
+                    inputs.push((0..len).map(|i| (rng.gen_range(0..keys), i)).collect());
+                }
+                // Most samples only contain the frequent key, so there is a single splitter
+                let skewed = (0..len).map(|i| (rng.gen_range(0..10) / 9 * rng.gen::<u32>(), i));
+                inputs.push(skewed.collect());
+                for v in inputs {
+                    assert_sorts_like_std(&v, |v| {
+                        if parallel {
+                            ips4o_stable_par(v, |a, b| a.0 < b.0, config);
+                        } else {
+                            ips4o_stable(v, |a, b| a.0 < b.0, config);
+                        }
+                    });
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn stable_sort_is_stable() {
+        check_stable_sort(false);
+    }
+
+    #[test]
+    fn stable_sort_par_is_stable() {
+        check_stable_sort(true);
+    }
+
+    #[test]
+    fn stable_sort_with_a_single_splitter() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let v: Vec<(u32, usize)> = (0..100_000)
+            .map(|i| (rng.gen_range(0..10) / 9 * rng.gen::<u32>(), i))
+            .collect();
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        for params in [
+            Ips4o::new().log_buckets(1),
+            Ips4o::new().equal_bucket_threshold(usize::MAX),
+        ] {
+            assert_sorts_like_std(&v, |v| params.stable_sort_by_key(v, |x| x.0));
+            assert_sorts_like_std(&v, |v| {
+                pool.install(|| params.stable_sort_par_by_key(v, |x| x.0))
+            });
+        }
+    }
+
+    #[test]
+    fn stable_sort_move_only() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut v: Vec<(Box<u32>, usize)> = (0..50_000)
+            .map(|i| (Box::new(rng.gen_range(0..1000)), i))
+            .collect();
+        stable_sort_by(&mut v, |a, b| a.0.cmp(&b.0));
+        assert!(v.windows(2).all(|w| (&w[0].0, w[0].1) < (&w[1].0, w[1].1)));
+    }
+
+    #[test]
+    fn argsort() {
+        check_configs::<usize>(|config, rng| {
+            for len in [0, 1, 100, 10_000, 1 << 18] {
+                let v: Vec<u32> = (0..len).map(|_| rng.gen_range(0..1000)).collect();
+                for parallel in [false, true] {
+                    let mut indices: Vec<usize> = (0..len).collect();
+                    let is_less = |&a: &usize, &b: &usize| v[a] < v[b];
+                    if parallel {
+                        ips4o_par(&mut indices, is_less, config, &mut Vec::new());
+                    } else {
+                        ips4o(&mut indices, is_less, config, &mut Vec::new());
+                    }
+                    assert_sorts_like_std(&v, |sorted| {
+                        for (x, &i) in sorted.iter_mut().zip(&indices) {
+                            *x = v[i];
+                        }
+                    });
+                    indices.sort();
+                    assert!(indices.into_iter().eq(0..len));
+                }
+            }
+        });
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let v: Vec<u32> = (0..10_000).map(|_| rng.gen_range(0..1000)).collect();
+        let expected = crate::argsort(&v);
+        let results = [
+            argsort_by(&v, |a, b| a.cmp(b)),
+            argsort_by_key(&v, |&x| x),
+            pool.install(|| argsort_par(&v)),
+            pool.install(|| argsort_par_by(&v, |a, b| a.cmp(b))),
+            pool.install(|| argsort_par_by_key(&v, |&x| x)),
+        ];
+        for mut indices in results {
+            // The sort is unstable, so equal elements may have their indices swapped
+            assert!(indices
+                .iter()
+                .map(|&i| v[i])
+                .eq(expected.iter().map(|&i| v[i])));
+            indices.sort();
+            assert!(indices.into_iter().eq(0..v.len()));
+        }
+    }
+
+    #[test]
+    fn select() {
+        check_configs::<u64>(|config, rng| {
+            for len in [1, 10, 100, 10_000, 1 << 18] {
+                for max in [2, 1000, u64::MAX] {
+                    let input: Vec<u64> = (0..len).map(|_| rng.gen_range(0..max)).collect();
+                    let mut expected = input.clone();
+                    expected.sort();
+                    for k in [0, 1, len / 3, len / 2, len - 1, len] {
+                        // Like partial_sort(), select_nth_unstable() and a range in between
+                        for ranks in [0..k, k..(k + 1).min(len), k / 2..k] {
+                            assert_sorts_like_std(&input, |v| {
+                                ips4o_select(v, ranks.clone(), u64::lt, config);
+                                assert_eq!(v[ranks.clone()], expected[ranks.clone()]);
+                                if !ranks.is_empty() {
+                                    let (first, last) =
+                                        (expected[ranks.start], expected[ranks.end - 1]);
+                                    assert!(v[..ranks.start].iter().all(|&x| x <= first));
+                                    assert!(v[ranks.end..].iter().all(|&x| x >= last));
+                                }
+                                v.sort();
+                            });
+                        }
+                    }
+                }
+            }
+        });
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let input: Vec<u64> = (0..10_000).map(|_| rng.gen_range(0..1000)).collect();
+        let mut expected = input.clone();
+        expected.sort();
+        for k in [0, 1, 5000, 9999] {
+            let mut v = input.clone();
+            partial_sort(&mut v, k);
+            assert_eq!(v[..k], expected[..k]);
+
+            let mut v = input.clone();
+            let top = top_k(&mut v, k);
+            assert!(top.iter().eq(expected.iter().rev().take(k)));
+
+            let mut v = input.clone();
+            let (left, nth, right) = select_nth_unstable(&mut v, k);
+            assert_eq!(*nth, expected[k]);
+            assert!(left.iter().all(|x| x <= &expected[k]));
+            assert!(right.iter().all(|x| x >= &expected[k]));
+        }
+    }
+
+    #[test]
+    fn merge_sorted_runs() {
+        check_configs::<(u64, usize)>(|config, rng| {
+            for len in [0, 1, 100, 10_000] {
+                for num_runs in [1, 2, 3, 16, 17, 100] {
+                    let mut bounds: Vec<usize> =
+                        (0..num_runs - 1).map(|_| rng.gen_range(0..=len)).collect();
+                    bounds.sort();
+                    let mut input: Vec<(u64, usize)> =
+                        (0..len).map(|i| (rng.gen_range(0..100), i)).collect();
+                    let starts = [0].into_iter().chain(bounds.iter().copied());
+                    for (start, end) in starts.zip(bounds.iter().copied().chain([len])) {
+                        input[start..end].sort_by_key(|x| x.0);
+                    }
+
+                    let is_less = |a: &(u64, usize), b: &(u64, usize)| a.0 < b.0;
+                    if num_runs <= 16 {
+                        // The merge is stable, so it orders the pairs like a sort by both values
+                        assert_sorts_like_std(&input, |v| ips4o_merge(v, &bounds, is_less, config));
+                    } else {
+                        let mut v = input.clone();
+                        ips4o_merge(&mut v, &bounds, is_less, config);
+                        assert!(v.windows(2).all(|w| w[0].0 <= w[1].0));
+                    }
+
+                    // A panicking comparison leaves a permutation of the input behind
+                    let count = Cell::new(0);
+                    let mut v = input.clone();
+                    let _ = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                        let is_less = |a: &(u64, usize), b: &(u64, usize)| {
+                            count.set(count.get() + 1);
+                            assert!(count.get() < len / 2);
+                            a.0 < b.0
+                        };
+                        ips4o_merge(&mut v, &bounds, is_less, config);
+                    }));
+                    v.sort_by_key(|x| x.1);
+                    assert!(v.into_iter().map(|x| x.1).eq(0..len));
+                }
+            }
+        });
+
+        let mut v: Vec<(u64, usize)> = (0..1000).map(|i| (i % 10, i as usize)).collect();
+        v[..500].sort();
+        v[500..].sort();
+        merge_sorted_runs_by_key(&mut v, &[500], |x| x.0);
+        assert!(v.windows(2).all(|w| w[0] <= w[1]));
+
+        let result = panic::catch_unwind(|| crate::merge_sorted_runs(&mut [1, 2], &[2, 1]));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn sort_adaptive() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let n = 100_000;
+        let ascending = |len: u64| (0..len).collect::<Vec<u64>>();
+        let descending = |len: u64| (0..len).rev().collect::<Vec<u64>>();
+        let random = |rng: &mut StdRng, len: u64| -> Vec<u64> {
+            (0..len).map(|_| rng.gen_range(0..n)).collect()
+        };
+        let inputs: [(Vec<u64>, usize, usize); 6] = [
+            (ascending(n), 1, 0),
+            (descending(n), 1, 1),
+            (random(&mut rng, n), 0, 0),
+            ([ascending(n / 2), descending(n / 2)].concat(), 2, 1),
+            (
+                [
+                    ascending(n / 4),
+                    random(&mut rng, 1000),
+                    descending(n / 4),
+                    random(&mut rng, 1000),
+                    ascending(n / 4),
+                ]
+                .concat(),
+                3,
+                1,
+            ),
+            // Too many runs to merge them
+            ((0..n).map(|i| i % 4000).collect(), 0, 0),
+        ];
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let config = Ips4o::new().config::<u64>();
+        for (input, runs, reversed_runs) in inputs {
+            let mut v = input.clone();
+            let mut expected = input.clone();
+            expected.sort();
+            let stats = crate::sort_adaptive(&mut v);
+            assert_eq!(v, expected);
+            assert_eq!((stats.runs, stats.reversed_runs), (runs, reversed_runs));
+            assert!(stats.presorted_len <= v.len());
+
+            // sort_par merges the same runs
+            let mut v = input;
+            let par_stats =
+                pool.install(|| ips4o_par(&mut v, |a, b| a < b, &config, &mut Vec::new()));
+            assert_eq!(v, expected);
+            assert_eq!(par_stats, stats);
+        }
+
+        let mut v: Vec<(u64, u64)> = (0..1000).map(|i| (i % 3, i)).collect();
+        sort_adaptive_by_key(&mut v, |x| x.0);
+        assert!(v.windows(2).all(|w| w[0].0 <= w[1].0));
+    }
+
+    #[test]
+    fn sort_dedup() {
+        check_configs::<u64>(|config, rng| {
+            for len in [0, 1, 100, 10_000, 1 << 18] {
+                for max in [1, 2, 1000, u64::MAX] {
+                    let input: Vec<u64> = (0..len).map(|_| rng.gen_range(0..max)).collect();
+                    let mut expected = input.clone();
+                    expected.sort();
+                    expected.dedup();
+                    for parallel in [false, true] {
+                        // The duplicates are kept behind the unique elements
+                        assert_sorts_like_std(&input, |v| {
+                            let unique = if parallel {
+                                ips4o_dedup_par(v, u64::lt, config)
+                            } else {
+                                ips4o_dedup(v, u64::lt, config)
+                            };
+                            assert_eq!(v[..unique], expected);
+                            v.sort();
+                        });
+                    }
+                }
+            }
+        });
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let input: Vec<u64> = (0..10_000).map(|_| rng.gen_range(0..1000)).collect();
+        let mut expected = input.clone();
+        expected.sort();
+        expected.dedup();
+        let mut v = input.clone();
+        assert_eq!(crate::sort_dedup(&mut v), expected.len());
+        assert_eq!(v[..expected.len()], expected);
+        let mut v = input;
+        assert_eq!(pool.install(|| sort_par_dedup(&mut v)), expected.len());
+        assert_eq!(v[..expected.len()], expected);
+
+        let input: Vec<(u32, usize)> = (0..10_000).map(|i| (rng.gen_range(0..50), i)).collect();
+        let mut keys: Vec<u32> = input.iter().map(|x| x.0).collect();
+        keys.sort();
+        keys.dedup();
+        let results = [
+            sort_dedup_by(&mut input.clone(), |a, b| a.0.cmp(&b.0)),
+            sort_dedup_by_key(&mut input.clone(), |x| x.0),
+            pool.install(|| sort_par_dedup_by_key(&mut input.clone(), |x| x.0)),
+        ];
+        assert_eq!(results, [keys.len(); 3]);
+
+        // Equal buckets are reduced without comparing their elements again, so removing the
+        // duplicates takes fewer comparisons than a pass over the sorted slice
+        let input: Vec<u64> = (0..1 << 18).map(|_| rng.gen_range(0..16)).collect();
+        let [sorted, deduped] = [false, true].map(|dedup| {
+            let count = AtomicUsize::new(0);
+            let compare = |a: &u64, b: &u64| {
+                count.fetch_add(1, atomic::Ordering::Relaxed);
+                a.cmp(b)
+            };
+            let mut v = input.clone();
+            if dedup {
+                assert_eq!(sort_dedup_by(&mut v, compare), 16);
+            } else {
+                sort_by(&mut v, compare);
+            }
+            count.into_inner()
+        });
+        assert!(deduped < sorted + input.len() - 1);
+    }
+
+    #[test]
+    fn sort_and_reduce_by_key() {
+        check_configs::<(u64, Vec<usize>)>(|config, rng| {
+            for len in [0, 1, 100, 10_000, 1 << 18] {
+                for max in [1, 2, 1000, u64::MAX] {
+                    let keys: Vec<u64> = (0..len).map(|_| rng.gen_range(0..max)).collect();
+                    let mut expected: BTreeMap<u64, Vec<usize>> = BTreeMap::new();
+                    for (i, &key) in keys.iter().enumerate() {
+                        expected.entry(key).or_default().push(i);
+                    }
+                    for parallel in [false, true] {
+                        let mut rows: Vec<(u64, Vec<usize>)> = keys
+                            .iter()
+                            .enumerate()
+                            .map(|(i, &k)| (k, vec![i]))
+                            .collect();
+                        let is_less = |a: &(u64, Vec<usize>), b: &(u64, Vec<usize>)| a.0 < b.0;
+                        let reduce = |a: &mut (u64, Vec<usize>), b: &mut (u64, Vec<usize>)| {
+                            a.1.append(&mut b.1)
+                        };
+                        let groups = if parallel {
+                            ips4o_reduce_par(&mut rows, is_less, reduce, config)
+                        } else {
+                            ips4o_reduce(&mut rows, is_less, reduce, config)
+                        };
+                        assert_eq!(groups, expected.len());
+                        for ((key, mut indices), (expected_key, expected_indices)) in
+                            rows.drain(..groups).zip(expected.clone())
+                        {
+                            indices.sort();
+                            assert_eq!((key, indices), (expected_key, expected_indices));
+                        }
+                        assert!(rows.iter().all(|x| x.1.is_empty()));
+                    }
+                }
+            }
+        });
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let keys: Vec<u64> = (0..10_000).map(|_| rng.gen_range(0..1000)).collect();
+        let mut expected: BTreeMap<u64, usize> = BTreeMap::new();
+        for &key in &keys {
+            *expected.entry(key).or_insert(0) += 1;
+        }
+        let mut counts: Vec<(u64, usize)> = keys.iter().map(|&k| (k, 1)).collect();
+        let groups = crate::sort_and_reduce_by_key(&mut counts, |x| x.0, |a, b| a.1 += b.1);
+        assert!(counts[..groups].iter().copied().eq(expected));
+
+        // The parallel version and the one that takes a configuration
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let counts: Vec<(u16, usize)> = (0..1 << 18).map(|_| (rng.gen(), 1)).collect();
+        let mut expected = BTreeMap::new();
+        for &(key, count) in &counts {
+            *expected.entry(key).or_insert(0) += count;
+        }
+        let sorter = Ips4o::new().log_buckets(4);
+        for parallel in [false, true] {
+            let mut v = counts.clone();
+            let groups = if parallel {
+                pool.install(|| sort_par_and_reduce_by_key(&mut v, |x| x.0, |a, b| a.1 += b.1))
+            } else {
+                sorter.sort_and_reduce_by_key(&mut v, |x| x.0, |a, b| a.1 += b.1)
+            };
+            assert!(v[..groups].iter().copied().eq(expected.clone()));
+        }
+    }
+
+    #[test]
+    fn sort_zip() {
+        check_configs::<u64>(|config, rng| {
+            for len in [0, 10, 100, 10_000, 1 << 18] {
+                let mut inputs: Vec<Vec<u64>> = [2, 1000, u64::MAX]
+                    .iter()
+                    .map(|&max| (0..len).map(|_| rng.gen_range(0..max)).collect())
+                    .collect();
+                // See check_stable_sort()
+                inputs.push(
+                    (0..len)
+                        .map(|_| rng.gen_range(0..10) / 9 * rng.gen::<u64>())
+                        .collect(),
+                );
+                for input in inputs {
+                    let rows: Vec<(u64, usize)> = input.into_iter().zip(0..).collect();
+                    for parallel in [false, true] {
+                        // The sort is unstable, so the rows are only compared as a multiset
+                        assert_sorts_like_std(&rows, |rows| {
+                            let (mut keys, mut values): (Vec<u64>, Vec<String>) =
+                                rows.iter().map(|&(key, i)| (key, i.to_string())).unzip();
+                            if parallel {
+                                ips4o_zip_par(&mut keys, &mut values[..], u64::lt, config);
+                            } else {
+                                ips4o_zip(&mut keys, &mut values[..], u64::lt, config);
+                            }
+                            assert!(keys.is_sorted());
+                            for (row, (key, value)) in
+                                rows.iter_mut().zip(keys.into_iter().zip(values))
+                            {
+                                *row = (key, value.parse().unwrap());
+                            }
+                            rows.sort();
+                        });
+                    }
+                }
+            }
+        });
+
+        let mut keys: Vec<u32> = (0..100_000).map(|i| i % 10 / 9 * i).collect();
+        let mut values: Vec<usize> = (0..keys.len()).collect();
+        Ips4o::new().log_buckets(1).sort_zip(&mut keys, &mut values);
+        assert!(keys.is_sorted());
+        assert!(keys
+            .iter()
+            .zip(&values)
+            .all(|(&k, &i)| k == 0 || k as usize == i));
+        values.sort();
+        assert!(values.iter().copied().eq(0..keys.len()));
+
+        let result = panic::catch_unwind(|| crate::sort_zip(&mut [2, 1], &mut [0]));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn sort_zip_columns() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        for len in [0, 1, 100, 10_000, 100_000] {
+            for parallel in [false, true] {
+                let mut keys: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+                let mut names: Vec<String> = (0..len).map(|i| i.to_string()).collect();
+                let mut ids: Vec<usize> = (0..len).collect();
+                let mut flags: Vec<bool> = (0..len).map(|i| i % 3 == 0).collect();
+                let input = keys.clone();
+
+                let columns = (&mut names[..], (&mut ids[..], &mut flags[..]));
+                if parallel {
+                    pool.install(|| crate::sort_par_zip_columns(&mut keys, columns));
+                } else {
+                    crate::sort_zip_columns(&mut keys, columns);
+                }
+                assert!(keys.is_sorted());
+                for (i, &index) in ids.iter().enumerate() {
+                    assert_eq!(keys[i], input[index]);
+                    assert_eq!(names[i], index.to_string());
+                    assert_eq!(flags[i], index % 3 == 0);
+                }
+                ids.sort();
+                assert!(ids.into_iter().eq(0..len));
+            }
+        }
+
+        let result = panic::catch_unwind(|| {
+            crate::sort_zip_columns(&mut [2, 1], (&mut [0, 1][..], &mut [0][..]))
+        });
+        assert!(result.is_err());
+    }
+
+    fn check_radix_sort(parallel: bool) {
+        let mut rng = StdRng::seed_from_u64(0);
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        for len in [0, 10, 100, 1_000, 10_000, 100_000, 1 << 20] {
+            let mut inputs: Vec<Vec<u64>> = vec![(0..len as u64).rev().collect()];
+            for max in [2, 1000, 1 << 40, u64::MAX] {
+                inputs.push((0..len).map(|_| rng.gen_range(0..max)).collect());
+            }
+            // Keys that only differ in their lowest bits
+            inputs.push((0..len).map(|_| (1 << 63) | rng.gen_range(0..64)).collect());
+            for mut v in inputs {
+                let mut expected = v.clone();
+                expected.sort();
+                if parallel {
+                    pool.install(|| radix_sort_par(&mut v));
+                } else {
+                    radix_sort(&mut v);
+                }
+                assert_eq!(v, expected);
+            }
+
+            let mut v: Vec<(u8, usize)> = (0..len).map(|i| (rng.gen(), i)).collect();
+            let mut expected = v.clone();
+            expected.sort_by_key(|x| x.0);
+            if parallel {
+                pool.install(|| radix_sort_par_by_key(&mut v, |x| x.0));
+            } else {
+                radix_sort_by_key(&mut v, |x| x.0);
+            }
+            assert!(v.iter().map(|x| x.0).eq(expected.iter().map(|x| x.0)));
+            v.sort_by_key(|x| x.1);
+            assert!(v.iter().map(|x| x.1).eq(0..len));
+        }
+    }
+
+    #[test]
+    fn radix_sort_matches_std() {
+        check_radix_sort(false);
+    }
+
+    #[test]
+    fn radix_sort_par_matches_std() {
+        check_radix_sort(true);
+    }
+
+    fn check_radix_key<K, F>(mut v: Vec<K>, compare: F)
+    where
+        K: RadixKey + Send + Sync + std::fmt::Debug,
+        F: Fn(&K, &K) -> Ordering,
+    {
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let mut expected = v.clone();
+        expected.sort_by(&compare);
+        let mut w = v.clone();
+        radix_sort(&mut v);
+        pool.install(|| radix_sort_par(&mut w));
+        for v in [v, w] {
+            assert!(v.iter().zip(&expected).all(|(a, b)| compare(a, b).is_eq()));
+        }
+    }
+
+    #[test]
+    fn radix_keys() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let len = 100_000;
+        check_radix_key((0..len).map(|_| rng.gen::<i64>()).collect(), i64::cmp);
+        check_radix_key(
+            (0..len).map(|_| rng.gen_range(-50..50i8)).collect(),
+            i8::cmp,
+        );
+        check_radix_key(
+            (0..len).map(|_| rng.gen::<u128>() >> 60).collect(),
+            u128::cmp,
+        );
+        check_radix_key((0..len).map(|_| rng.gen::<i128>()).collect(), i128::cmp);
+
+        let special = [
+            f64::NAN,
+            -f64::NAN,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            0.0,
+            -0.0,
+        ];
+        let floats = (0..len)
+            .map(|i| match i % 10 {
+                0 => special[rng.gen_range(0..special.len())],
+                _ => rng.gen_range(-1e10..1e10),
+            })
+            .collect::<Vec<f64>>();
+        check_radix_key(floats.clone(), f64::total_cmp);
+        let floats = floats.iter().map(|&x| x as f32).collect();
+        check_radix_key(floats, f32::total_cmp);
+
+        let tuples = (0..len)
+            .map(|_| (rng.gen_range(0..10u32), rng.gen::<u64>()))
+            .collect();
+        check_radix_key(tuples, |a: &(u32, u64), b| a.cmp(b));
+        let tuples = (0..len)
+            .map(|_| {
+                (
+                    rng.gen_range(0..4u8),
+                    rng.gen_range(-3..3i16),
+                    rng.gen::<f32>(),
+                )
+            })
+            .collect();
+        check_radix_key(tuples, |a: &(u8, i16, f32), b| {
+            (a.0, a.1).cmp(&(b.0, b.1)).then(a.2.total_cmp(&b.2))
+        });
+        let arrays = (0..len)
+            .map(|_| {
+                let mut a = [0u8; 13];
+                a[..2].fill(rng.gen_range(0..2));
+                a[2..].iter_mut().for_each(|x| *x = rng.gen());
+                a
+            })
+            .collect();
+        check_radix_key(arrays, <[u8; 13]>::cmp);
+    }
+
+    #[test]
+    fn sorted_buckets_are_skipped() {
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        // Partitioning leaves buckets made of a few sorted runs behind. The chunks are shuffled,
+        // so the input as a whole does not look presorted.
+        let mut chunks: Vec<Vec<u64>> = (0..1 << 18)
+            .collect::<Vec<_>>()
+            .chunks(1 << 12)
+            .map(<[u64]>::to_vec)
+            .collect();
+        chunks.shuffle(&mut StdRng::seed_from_u64(0));
+        let input = chunks.concat();
+        for parallel in [false, true] {
+            let comparisons = [
+                Ips4o::new(),
+                Ips4o::new().sorted_check_threshold(usize::MAX),
+            ]
+            .map(|sorter| {
+                let count = AtomicUsize::new(0);
+                let mut v = input.clone();
+                let compare = |a: &u64, b: &u64| {
+                    count.fetch_add(1, atomic::Ordering::Relaxed);
+                    a.cmp(b)
+                };
+                if parallel {
+                    pool.install(|| sorter.sort_par_by(&mut v, compare));
+                } else {
+                    sorter.sort_by(&mut v, compare);
+                }
+                assert!(v.iter().copied().eq(0..1 << 18));
+                count.into_inner()
+            });
+            assert!(comparisons[0] < comparisons[1]);
+        }
+
+        // Panics while merging a bucket
+        let total = count_comparisons(&input, false);
+        for panic_at in (total / 2..total).step_by(total / 29) {
+            sort_with_panicking_comparison(&input, panic_at);
+        }
+    }
+
+    #[test]
+    fn custom_parameters() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let sorters = [
+            Ips4o::new().block_size(1),
+            Ips4o::new().block_size(7).log_buckets(1),
+            Ips4o::new()
+                .block_size_bytes(100)
+                .log_buckets(3)
+                .base_case_size(1),
+            Ips4o::new()
+                .base_case_size(100)
+                .oversampling_factor_percent(0.0)
+                .equal_bucket_threshold(0)
+                .min_parallel_blocks_per_thread(1),
+            Ips4o::new().sorted_check_threshold(0),
+        ];
+        for sorter in sorters {
+            for len in [0, 10, 1_000, 100_000] {
+                let v: Vec<u32> = (0..len)
+                    .map(|_| rng.gen_range(0..len as u32 / 4 + 1))
+                    .collect();
+                let mut expected = v.clone();
+                expected.sort();
+                let mut w = v.clone();
+                sorter.sort(&mut w);
+                assert_eq!(w, expected);
+                let mut w = v.clone();
+                pool.install(|| sorter.sort_par(&mut w));
+                assert_eq!(w, expected);
+                let mut w = v.clone();
+                sorter.stable_sort(&mut w);
+                assert_eq!(w, expected);
+                let mut w = v.clone();
+                pool.install(|| sorter.stable_sort_par(&mut w));
+                assert_eq!(w, expected);
+                let mut w = v.clone();
+                sorter.radix_sort(&mut w);
+                assert_eq!(w, expected);
+                let mut w = v.clone();
+                pool.install(|| sorter.radix_sort_par(&mut w));
+                assert_eq!(w, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn block_size_depends_on_element_size() {
+        assert_eq!(Ips4o::new().config::<u8>().block_size, 2048);
+        assert_eq!(Ips4o::new().config::<u64>().block_size, 256);
+        assert_eq!(Ips4o::new().config::<[u8; 128]>().block_size, 16);
+        assert_eq!(Ips4o::new().config::<[u8; 4096]>().block_size, 1);
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut v: Vec<[u64; 16]> = (0..20_000)
+            .map(|_| array::from_fn(|_| rng.gen_range(0..100)))
+            .collect();
+        let mut expected = v.clone();
+        expected.sort();
+        sort(&mut v);
+        assert_eq!(v, expected);
+    }
+
+    #[test]
+    fn sort_par_in_foreign_pools() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let input: Vec<u32> = (0..1 << 18).map(|_| rng.gen_range(0..1000)).collect();
+        let mut expected = input.clone();
+        expected.sort();
+        let small = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        let large = ThreadPoolBuilder::new().num_threads(5).build().unwrap();
+
+        let mut v = input.clone();
+        sort_par_in(&large, &mut v);
+        assert_eq!(v, expected);
+        // Started from a thread of another pool
+        let mut v = input.clone();
+        small.install(|| sort_par_by_in(&large, &mut v, |a, b| a.cmp(b)));
+        assert_eq!(v, expected);
+        let mut v = input.clone();
+        large.install(|| sort_par_by_key_in(&small, &mut v, |&x| x));
+        assert_eq!(v, expected);
+        // Started from a thread that is not part of any pool
+        let mut v = input.clone();
+        std::thread::spawn(move || {
+            sort_par(&mut v);
+            v
+        })
+        .join()
+        .map(|v| assert_eq!(v, expected))
+        .unwrap();
+    }
+
+    #[test]
+    fn num_threads() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let input: Vec<u32> = (0..1 << 18).map(|_| rng.gen_range(0..1000)).collect();
+        let mut expected = input.clone();
+        expected.sort();
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        for num_threads in [1, 2, 3, 4, 7] {
+            let sorter = Ips4o::new().num_threads(num_threads);
+            let mut v = input.clone();
+            pool.install(|| sorter.sort_par(&mut v));
+            assert_eq!(v, expected);
+            let mut v = input.clone();
+            pool.install(|| sorter.stable_sort_par(&mut v));
+            assert_eq!(v, expected);
+            let mut v = input.clone();
+            pool.install(|| sorter.radix_sort_par(&mut v));
+            assert_eq!(v, expected);
+            let mut v = input.clone();
+            let mut values = input.clone();
+            pool.install(|| sorter.sort_par_zip(&mut v, &mut values));
+            assert_eq!((&v, &values), (&expected, &expected));
+        }
+    }
+
+    /// Threads that are comparing right now, and the most at any time, see [Probed]
+    static COMPARING: AtomicUsize = AtomicUsize::new(0);
+    static MAX_COMPARING: AtomicUsize = AtomicUsize::new(0);
+
+    /// Counts the threads that compare elements at the same time
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct Probed(u32);
+
+    impl Probed {
+        fn probe<R>(f: impl FnOnce() -> R) -> R {
+            let comparing = COMPARING.fetch_add(1, atomic::Ordering::SeqCst) + 1;
+            MAX_COMPARING.fetch_max(comparing, atomic::Ordering::SeqCst);
+            // Let the other threads of the pool run while this one is counted
+            thread::yield_now();
+            let result = f();
+            COMPARING.fetch_sub(1, atomic::Ordering::SeqCst);
+            result
+        }
+    }
+
+    impl PartialOrd for Probed {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for Probed {
+        fn cmp(&self, other: &Self) -> Ordering {
+            Self::probe(|| self.0.cmp(&other.0))
+        }
+    }
+
+    #[test]
+    fn num_threads_limits_concurrency() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let input: Vec<Probed> = (0..1 << 16)
+            .map(|_| Probed(rng.gen_range(0..1000)))
+            .collect();
+        let pool = ThreadPoolBuilder::new().num_threads(8).build().unwrap();
+        let sorter = Ips4o::new().num_threads(2);
+        let check = |sort: &(dyn Fn(&mut [Probed]) + Sync)| {
+            MAX_COMPARING.store(0, atomic::Ordering::SeqCst);
+            let mut v = input.clone();
+            pool.install(|| sort(&mut v));
+            assert!(MAX_COMPARING.load(atomic::Ordering::SeqCst) <= 2);
+        };
+        check(&|v| sorter.sort_par(v));
+        check(&|v| sorter.stable_sort_par(v));
+        check(&|v| sorter.radix_sort_par_by_key(v, |x| Probed::probe(|| x.0)));
+        check(&|v| sorter.sort_par_zip(v, &mut v.to_vec()));
+        check(&|v| {
+            sorter.sort_par_and_reduce_by_key(v, |x| *x, |_, _| {});
+        });
+    }
+
+    #[test]
+    fn atomic_pointers() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let input: Vec<u32> = (0..1 << 18).map(|_| rng.gen_range(0..1000)).collect();
+        let mut expected = input.clone();
+        expected.sort();
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        for kind in [
+            AtomicPointers::Auto,
+            AtomicPointers::U64,
+            AtomicPointers::U128,
+            AtomicPointers::Locked,
+        ] {
+            let sorter = Ips4o::new().atomic_pointers(kind);
+            let mut v = input.clone();
+            sorter.sort(&mut v);
+            assert_eq!(v, expected);
+            let mut v = input.clone();
+            pool.install(|| sorter.sort_par(&mut v));
+            assert_eq!(v, expected);
+            let mut v = input.clone();
+            pool.install(|| sorter.radix_sort_par(&mut v));
+            assert_eq!(v, expected);
+        }
+
+        // Without native 64-bit atomics, a lock would be taken for every access
+        #[cfg(target_has_atomic = "64")]
+        assert_eq!(AtomicPointers::Auto.resolve(1 << 20), AtomicPointers::U64);
+        #[cfg(not(target_has_atomic = "64"))]
+        assert_ne!(AtomicPointers::Auto.resolve(1 << 20), AtomicPointers::U64);
+        assert_eq!(
+            AtomicPointers::Locked.resolve(1 << 20),
+            AtomicPointers::Locked
+        );
+        #[cfg(target_pointer_width = "64")]
+        {
+            assert_eq!(AtomicPointers::U64.resolve(1 << 32), AtomicPointers::U128);
+            assert_ne!(AtomicPointers::Auto.resolve(1 << 32), AtomicPointers::U64);
+        }
+    }
+
+    #[test]
+    fn sort_par_skewed() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        for len in [10_000, 100_000, 1 << 20] {
+            // Roughly Zipfian keys, the smallest ones make up huge buckets
+            let mut v: Vec<u64> = (0..len)
+                .map(|_| (1 << 20) / rng.gen_range(1..1 << 20))
+                .collect();
+            let mut expected = v.clone();
+            expected.sort();
+            pool.install(|| sort_par(&mut v));
+            assert_eq!(v, expected);
+        }
+    }
+
+    #[test]
+    fn sort_par_non_copy() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DropCounter(String);
+        impl Drop for DropCounter {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+        }
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let input: Vec<String> = (0..1 << 18)
+            .map(|_| rng.gen_range(0..10_000).to_string())
+            .collect();
+        let mut expected = input.clone();
+        expected.sort();
+
+        let mut v = input.clone();
+        pool.install(|| sort_par(&mut v));
+        assert_eq!(v, expected);
+
+        let mut v: Vec<(String, usize)> = input.iter().cloned().zip(0..).collect();
+        pool.install(|| stable_sort_par_by(&mut v, |a, b| a.0.cmp(&b.0)));
+        assert!(v.windows(2).all(|w| w[0] < w[1]));
+
+        // Every element is still there exactly once after a panic
+        for panic_at in [1_000, 100_000, 1_000_000, 3_000_000] {
+            let mut v: Vec<DropCounter> = input.iter().cloned().map(DropCounter).collect();
+            let comparisons = AtomicUsize::new(0);
+            let _ = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                pool.install(|| {
+                    sort_par_by(&mut v, |a, b| {
+                        if comparisons.fetch_add(1, atomic::Ordering::Relaxed) + 1 == panic_at {
+                            panic!("injected panic");
+                        }
+                        a.0.cmp(&b.0)
+                    })
+                })
+            }));
+            assert_eq!(DROPS.load(atomic::Ordering::Relaxed), 0);
+            let mut values: Vec<String> = v.iter().map(|x| x.0.clone()).collect();
+            values.sort();
+            assert_eq!(values, expected);
+            drop(v);
+            assert_eq!(DROPS.swap(0, atomic::Ordering::Relaxed), input.len());
+        }
+    }
+
     fn some_vec() -> Vec<i32> {
         vec![5, 5, 35, 7, 4, 4, 4, 7, 67, 7, 7, 6] //           3*4 +  2*5 + 1*6 +  4*7 + 1*35 + 1*67
                                                    // times 2:  6*4 +  4*5 + 2*6 +  8*7 + 2*35 + 2*67
@@ -0,0 +1,295 @@
+//! Merging of presorted runs.
+//!
+//! Neighbouring runs are merged pairwise, so `k` runs take `log(k)` rounds over the input and
+//! *O*(*n* \* log(*k*)) comparisons in total. Each merge moves the left run into a buffer and
+//! merges it with the right run from the front.
+//!
+//! Before partitioning, [crate::sort] checks a sample of its input for long runs. If the sample
+//! looks presorted, it finds the runs with one scan, sorts the gaps between them and merges
+//! everything, so the runs are never partitioned.
+
+use std::{cmp::max, mem::MaybeUninit, ops::Range, ptr};
+
+use crate::{
+    config::Config,
+    constants::{BASE_CASE_MULTIPLIER, MAX_MERGE_RUNS, PRESORTED_SAMPLE_SIZE},
+    is_less_to_compare, Less, Sortable,
+};
+
+/// The presorted runs that [crate::sort_adaptive] found and merged instead of sorting them.
+///
+/// All fields are zero if the input was sorted without using its runs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Presortedness {
+    /// Number of merged runs, including the reversed ones
+    pub runs: usize,
+    /// Number of descending runs, which were reversed
+    pub reversed_runs: usize,
+    /// Number of elements in the runs, which were not partitioned
+    pub presorted_len: usize,
+}
+
+/// Sorts `v` by merging its long runs, if it has at most [MAX_MERGE_RUNS] runs and gaps between
+/// them. The gaps are sorted with `sort_gap`.
+///
+/// Returns `None` if `v` still has to be sorted, which is found out from a sample of
+/// [PRESORTED_SAMPLE_SIZE] elements for most inputs and with one scan otherwise.
+pub(crate) fn merge_presorted<T, F>(
+    v: &mut [T],
+    is_less: &F,
+    config: &Config,
+    mut sort_gap: impl FnMut(&mut [T]),
+) -> Option<Presortedness>
+where
+    T: Sortable,
+    F: Less<T>,
+{
+    let n = v.len();
+    if n <= BASE_CASE_MULTIPLIER * config.base_case_size || !sample_is_presorted(v, is_less) {
+        return None;
+    }
+    // Shorter runs do not save enough to be worth a merge, and there are at most
+    // `MAX_MERGE_RUNS / 2` longer ones before giving up
+    let min_run_len = max(
+        BASE_CASE_MULTIPLIER * config.base_case_size,
+        n / (2 * MAX_MERGE_RUNS),
+    );
+    let mut stats = Presortedness::default();
+    let mut runs: Vec<Range<usize>> = Vec::new();
+    let mut num_parts = 0;
+    let mut start = 0;
+    while start < n {
+        let (len, descending) = find_run(&v[start..], is_less);
+        let run = start..start + len;
+        start = run.end;
+        if len < min_run_len {
+            continue;
+        }
+        // The run itself and the gap before it
+        let gap = runs.last().map_or(0, |last| last.end) < run.start;
+        num_parts += 1 + gap as usize;
+        if num_parts > MAX_MERGE_RUNS {
+            return None;
+        }
+        if descending {
+            v[run.clone()].reverse();
+            stats.reversed_runs += 1;
+        }
+        stats.presorted_len += len;
+        runs.push(run);
+    }
+    if runs.is_empty() {
+        return None;
+    }
+    stats.runs = runs.len();
+
+    // Sort the gaps between the runs and merge them together with the runs
+    let mut parts = vec![0];
+    for run in runs {
+        let gap_start = *parts.last().unwrap();
+        if gap_start < run.start {
+            sort_gap(&mut v[gap_start..run.start]);
+            parts.push(run.start);
+        }
+        parts.push(run.end);
+    }
+    if *parts.last().unwrap() < n {
+        let gap_start = *parts.last().unwrap();
+        sort_gap(&mut v[gap_start..]);
+        parts.push(n);
+    }
+    merge_runs(v, &parts, is_less);
+    Some(stats)
+}
+
+/// Returns true if evenly spaced samples of `v` change between ascending and descending order
+/// at most twice per run that can be merged. Random inputs fail after a few dozen comparisons.
+fn sample_is_presorted<T, F>(v: &[T], is_less: &F) -> bool
+where
+    F: Less<T>,
+{
+    debug_assert!(!v.is_empty());
+    let step = max(1, v.len() / PRESORTED_SAMPLE_SIZE);
+    let mut changes = 0;
+    let mut descending = None;
+    let mut prev = &v[0];
+    for x in v.iter().step_by(step).skip(1) {
+        let order = if is_less(x, prev) {
+            Some(true)
+        } else if is_less(prev, x) {
+            Some(false)
+        } else {
+            None
+        };
+        if order.is_some() {
+            if descending.is_some() && descending != order {
+                changes += 1;
+                if changes > 2 * MAX_MERGE_RUNS {
+                    return false;
+                }
+            }
+            descending = order;
+        }
+        prev = x;
+    }
+    true
+}
+
+/// Returns the length of the run at the start of `v` and whether it is descending.
+///
+/// Descending runs may contain equal elements, reversing them is fine for an unstable sort.
+fn find_run<T, F>(v: &[T], is_less: &F) -> (usize, bool)
+where
+    F: Less<T>,
+{
+    if v.len() < 2 {
+        return (v.len(), false);
+    }
+    let descending = is_less(&v[1], &v[0]);
+    let mut end = 2;
+    if descending {
+        while end < v.len() && !is_less(&v[end - 1], &v[end]) {
+            end += 1;
+        }
+    } else {
+        while end < v.len() && !is_less(&v[end], &v[end - 1]) {
+            end += 1;
+        }
+    }
+    (end, descending)
+}
+
+/// Merges the sorted runs of `v` which start at `runs`, the last entry of `runs` is `v.len()`.
+pub(crate) fn merge_runs<T, F>(v: &mut [T], runs: &[usize], is_less: &F)
+where
+    T: Sortable,
+    F: Less<T>,
+{
+    let mut buffer = Box::new_uninit_slice(merge_buffer_len(runs));
+    merge_runs_with(v, runs, &mut buffer, is_less);
+}
+
+/// Like [merge_runs], but with a given buffer of at least [merge_buffer_len] elements.
+pub(crate) fn merge_runs_with<T, F>(
+    v: &mut [T],
+    runs: &[usize],
+    buffer: &mut [MaybeUninit<T>],
+    is_less: &F,
+) where
+    T: Sortable,
+    F: Less<T>,
+{
+    debug_assert!(runs.first() == Some(&0) && runs.last() == Some(&v.len()));
+    debug_assert!(buffer.len() >= merge_buffer_len(runs));
+    let mut runs = runs.to_vec();
+    while runs.len() > 2 {
+        for i in (0..runs.len() - 2).step_by(2) {
+            let (start, mid, end) = (runs[i], runs[i + 1], runs[i + 2]);
+            merge(&mut v[start..end], mid - start, buffer, is_less);
+        }
+        runs = merge_round(&runs);
+    }
+    debug_assert!(v.is_sorted_by(is_less_to_compare!(is_less)));
+}
+
+/// Returns the size of the buffer that merging the runs starting at `runs` needs, which is the
+/// length of the longest left run of a merge.
+pub(crate) fn merge_buffer_len(runs: &[usize]) -> usize {
+    let mut runs = runs.to_vec();
+    let mut len = 0;
+    while runs.len() > 2 {
+        for i in (0..runs.len() - 2).step_by(2) {
+            len = max(len, runs[i + 1] - runs[i]);
+        }
+        runs = merge_round(&runs);
+    }
+    len
+}
+
+/// Returns the run starts after merging neighbouring runs pairwise
+fn merge_round(runs: &[usize]) -> Vec<usize> {
+    let mut merged: Vec<usize> = runs[..runs.len() - 1].iter().step_by(2).copied().collect();
+    merged.push(*runs.last().unwrap());
+    merged
+}
+
+/// Returns the starts of the ascending runs of `v` followed by `v.len()`, or `None` if there are
+/// more than `max_runs` of them. Stops at the first run too many, so unsorted inputs are
+/// rejected quickly.
+pub(crate) fn find_sorted_runs<T, F>(v: &[T], is_less: &F, max_runs: usize) -> Option<Vec<usize>>
+where
+    F: Less<T>,
+{
+    let mut runs = vec![0];
+    for i in 1..v.len() {
+        if is_less(&v[i], &v[i - 1]) {
+            if runs.len() == max_runs {
+                return None;
+            }
+            runs.push(i);
+        }
+    }
+    runs.push(v.len());
+    Some(runs)
+}
+
+/// Merges the sorted runs `v[..mid]` and `v[mid..]`, `buffer` holds at least `mid` elements.
+///
+/// Equal elements of the left run stay in front of those of the right run.
+fn merge<T, F>(v: &mut [T], mid: usize, buffer: &mut [MaybeUninit<T>], is_less: &F)
+where
+    F: Less<T>,
+{
+    debug_assert!(0 < mid && mid < v.len() && mid <= buffer.len());
+    // The runs are already in order
+    if !is_less(&v[mid], &v[mid - 1]) {
+        return;
+    }
+    let v = v.as_mut_ptr_range();
+    let buffer = buffer.as_mut_ptr() as *mut T;
+    // SAFETY: the left run is moved into the buffer, which leaves a gap of `mid` elements in
+    // `v`. Every step moves one element into the front of the gap and removes one element from
+    // either the buffer or the right run, so the gap keeps exactly the size of the rest of the
+    // buffer and the hole fills it on drop, also if a comparison panics.
+    unsafe {
+        ptr::copy_nonoverlapping(v.start, buffer, mid);
+        let mut hole = MergeHole {
+            start: buffer,
+            end: buffer.add(mid),
+            dest: v.start,
+        };
+        let mut right = v.start.add(mid);
+        while hole.start < hole.end && right < v.end {
+            // Only strictly smaller elements are taken from the right run, to keep the order of
+            // equal elements
+            let src = if is_less(&*right, &*hole.start) {
+                right = right.add(1);
+                right.sub(1)
+            } else {
+                hole.start = hole.start.add(1);
+                hole.start.sub(1)
+            };
+            ptr::copy_nonoverlapping(src, hole.dest, 1);
+            hole.dest = hole.dest.add(1);
+        }
+        // The rest of the right run already is in place, the hole moves the rest of the
+        // buffer
+    }
+}
+
+/// Moves the elements in `start..end` to `dest` when dropped
+struct MergeHole<T> {
+    start: *mut T,
+    end: *mut T,
+    dest: *mut T,
+}
+
+impl<T> Drop for MergeHole<T> {
+    fn drop(&mut self) {
+        // SAFETY: see merge()
+        unsafe {
+            let len = self.end.offset_from(self.start) as usize;
+            ptr::copy_nonoverlapping(self.start, self.dest, len);
+        }
+    }
+}
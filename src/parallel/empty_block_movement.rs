@@ -1,11 +1,10 @@
 use std::{
     cmp::{max, min},
     ops::Range,
+    ptr,
 };
 
-use rayon::current_num_threads;
-
-use crate::{bucket_pointers::BucketPointer, constants::BLOCK_SIZE, storage::BucketBoundaries};
+use crate::{bucket_pointers::BucketPointer, payload::Payload, storage::BucketBoundaries};
 
 /// Moves empty blocks to establish invariant:
 /// All buckets must consist of full blocks followed by empty blocks.
@@ -16,23 +15,26 @@ use crate::{bucket_pointers::BucketPointer, constants::BLOCK_SIZE, storage::Buck
 ///        full             full             empty             full             full             empty             full             full             empty
 /// |***************************************|****************************************************************************|****************************|**********|
 ///               bucket k                                                            bucket k+1                                   bucket k+2          bucket k+3
-pub(super) fn move_empty_blocks<T>(
+///
+/// The rows of the bucket in `rows` are moved along with the elements.
+#[allow(clippy::too_many_arguments)]
+pub(super) fn move_empty_blocks<T, P: Payload>(
     bucket: &mut [T],
+    rows: P,
     bucket_number: usize,
     stripe_ranges: &[Range<usize>],
     flushed_elements_in_stripes: &[usize],
     bucket_boundaries: &BucketBoundaries,
     bucket_pointers: &[BucketPointer],
-) where
-    T: Copy,
-{
+    block_size: usize,
+) {
     debug_assert_eq!(stripe_ranges.len(), flushed_elements_in_stripes.len());
-    let num_threads = current_num_threads();
+    let num_threads = stripe_ranges.len();
 
     let first_empty_block =
         |thread: usize| stripe_ranges[thread].start + flushed_elements_in_stripes[thread];
 
-    let align_to_prev_block = |x: usize| x - x % BLOCK_SIZE;
+    let align_to_prev_block = |x: usize| x - x % block_size;
     let bucket_range = align_to_prev_block(bucket_boundaries[bucket_number])
         ..align_to_prev_block(bucket_boundaries[bucket_number + 1]);
     let offset = bucket_range.start;
@@ -107,9 +109,19 @@ pub(super) fn move_empty_blocks<T>(
             read_range_size -= currently_reserved;
             currently_reserved = 0;
             let size = min(read_range_size, write_end - write_ptr);
-            let range = read_ptr - size - offset..read_ptr - offset;
-            let w = write_ptr - offset;
-            bucket.copy_within(range, w);
+            let src = read_ptr - size - offset;
+            let dest = write_ptr - offset;
+            debug_assert!(src + size <= bucket.len() && dest + size <= bucket.len());
+            // SAFETY: both ranges are inside the bucket. The elements are moved out of full
+            // blocks behind the first empty block of the bucket, which are empty afterwards.
+            unsafe {
+                ptr::copy(
+                    bucket.as_ptr().add(src),
+                    bucket.as_mut_ptr().add(dest),
+                    size,
+                );
+                rows.copy(src, dest, size);
+            }
             write_ptr += size;
             reserved += size;
         }
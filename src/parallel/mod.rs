@@ -1,192 +1,240 @@
 mod empty_block_movement;
+pub(crate) mod scheduler;
 
-use std::{cmp::min, sync::Mutex, vec};
+use std::{
+    cmp::min,
+    panic::{self, AssertUnwindSafe},
+    slice, vec,
+};
 
-use rayon::{current_num_threads, current_thread_index, scope};
+use rayon::scope;
 
 use crate::{
-    base_case::base_case_sort,
+    base_case::{base_case_sort, heapsort::heapsort},
     bucket_pointers::BucketPointer,
-    constants::{BASE_CASE_SIZE, BLOCK_SIZE, MAX_BUCKETS, SINGLE_LEVEL_THRESHOLD},
+    classifier::Classify,
+    config::Config,
+    constants::MAX_BUCKETS,
     is_less_to_compare,
-    parallel::empty_block_movement::move_empty_blocks,
-    permute_blocks::permute_blocks_parallel,
-    sequential::{calculate_bucket_boundaries, get_splitters, seq_recurse},
-    storage::{GlobalStorage, LocalStorage},
-    util::{round_up_to_block_size, test_block_permutation, test_cleanup_margins},
+    parallel::{
+        empty_block_movement::move_empty_blocks,
+        scheduler::{buckets_left, par_recurse, ParallelSort, StoragePool},
+    },
+    payload::{base_case_sort_rows, Payload},
+    permute_blocks::{permute_blocks_parallel, SharedSlice},
+    restore::{blocks_fit_buckets, holes_during_permutation, HoleFiller},
+    sequential::{
+        calculate_bucket_boundaries, get_splitters, merge_presorted_bucket, recursion_depth_limit,
+        seq_recurse, sequential,
+    },
+    storage::{BufferPool, GlobalStorage, LocalStorage},
+    util::{
+        debug_assertions::test_stripe_classification, move_from_slice, round_up_to_block_size,
+        test_block_permutation, test_cleanup_margins,
+    },
     Less, PLess, PSortable, Sortable,
 };
 
-pub(crate) fn parallel_ips4o<T, F>(v: &mut [T], is_less: &F)
-where
-    T: PSortable,
-    F: PLess<T>,
-{
-    let num_threads = current_num_threads();
-
-    // initialize storage
-    let mut lss = Vec::new();
-    lss.resize_with(num_threads, || LocalStorage::new(is_less));
-    let mut gs: GlobalStorage<T, F> = GlobalStorage::new(is_less);
-
-    parallel(v, &mut lss, &mut gs, is_less);
-}
-
-fn parallel<T, F>(
+pub(crate) fn parallel_ips4o<T, F>(
     v: &mut [T],
-    lss: &mut [LocalStorage<T, F>],
-    gs: &mut GlobalStorage<T, F>,
     is_less: &F,
+    config: &Config,
+    pool: &mut BufferPool<T>,
+    num_threads: usize,
 ) where
     T: PSortable,
     F: PLess<T>,
 {
-    if v.len() <= 2 * BASE_CASE_SIZE {
+    if v.len() <= 2 * config.base_case_size {
         base_case_sort(v, is_less);
         return;
     }
-    par_recurse(v, lss, gs, is_less);
+    // initialize storage, one local storage per thread and one global storage
+    let new_storage = || LocalStorage::new(is_less, config);
+    let storages = StoragePool::new(
+        (0..num_threads + 1)
+            .map(|_| LocalStorage::new_in(is_less, config, pool))
+            .collect(),
+        &new_storage,
+    );
+
+    let depth_limit = recursion_depth_limit(v.len());
+    let task = (v, depth_limit);
+    par_recurse(&Unstable { is_less }, task, &storages, num_threads);
+    // Thread groups may have allocated more storages than the next sort takes out again, keep
+    // the pool from growing with every sort
+    let free = storages.into_free().into_iter().take(num_threads + 1);
+    pool.extend(free.map(LocalStorage::into_buffers));
 }
 
-/// Entry point for sequential recursion.
-fn par_recurse<T, F>(
-    v: &mut [T],
-    lss: &mut [LocalStorage<T, F>],
-    gs: &mut GlobalStorage<T, F>,
-    is_less: &F,
-) where
+/// The unstable sort, its tasks are buckets with their remaining recursion depth
+struct Unstable<'a, F> {
+    is_less: &'a F,
+}
+
+impl<'a, 'v, T, F> ParallelSort<(&'v mut [T], usize)> for Unstable<'a, F>
+where
     T: PSortable,
     F: PLess<T>,
 {
-    debug_assert!(v.len() > 2 * BASE_CASE_SIZE);
-    partition(v, lss, gs, is_less);
-    let bucket_boundaries = Vec::from(&gs.bucket_boundaries[..gs.num_buckets + 1]);
+    type Storage = LocalStorage<'a, T, F>;
 
-    // Final base cases were executed in cleanup step, so we're done here
-    if v.len() <= SINGLE_LEVEL_THRESHOLD {
-        debug_assert!(v.is_sorted_by(is_less_to_compare!(is_less)));
-        return;
+    fn len(&self, task: &(&'v mut [T], usize)) -> usize {
+        task.0.len()
     }
-    let equal_buckets = gs.classifier.equal_buckets;
-    let num_buckets = gs.num_buckets;
-
-    let mut parallel_queue = Vec::new();
-    let mut sequential_queue = Vec::new();
-    sequential_queue.reserve_exact(MAX_BUCKETS);
-    let unbalancing_factor = current_num_threads() / 2; // TODO as f64?
-    let len = v.len();
-    let mut buckets: Vec<Option<&mut [T]>> =
-        split_at_bounds(v, &gs.bucket_boundaries[..gs.num_buckets])
-            .into_iter()
-            .map(Some)
-            .collect();
-
-    let mut add_to_queue = |bucket: usize| {
-        let range = bucket_boundaries[bucket]..bucket_boundaries[bucket + 1];
-        if range.len() > 2 * BASE_CASE_SIZE {
-            if range.len() > len / unbalancing_factor {
-                parallel_queue.push(buckets[bucket].take().unwrap());
-            } else {
-                sequential_queue.push(buckets[bucket].take().unwrap());
-            }
-        } else {
-            // should already be sorted in cleanup_margins()
-            debug_assert!(buckets[bucket]
-                .take()
-                .unwrap()
-                .is_sorted_by(is_less_to_compare!(is_less)));
+
+    fn partition(
+        &self,
+        (v, depth_limit): (&'v mut [T], usize),
+        lss: &mut [Self::Storage],
+        gs: &mut Self::Storage,
+    ) -> Vec<(usize, (&'v mut [T], usize))> {
+        let is_less = self.is_less;
+        debug_assert!(v.len() > 2 * gs.config.base_case_size);
+        if depth_limit == 0 || !partition(v, lss, gs, is_less) {
+            heapsort(v, is_less);
+            return Vec::new();
         }
-    };
 
-    let step = 1 + equal_buckets as usize;
-    for i in (0..num_buckets).step_by(step) {
-        add_to_queue(i);
-    }
-    if equal_buckets {
-        add_to_queue(num_buckets - 1);
+        // Final base cases were executed in cleanup step, so we're done here
+        if v.len() <= gs.config.single_level_threshold() {
+            debug_assert!(v.is_sorted_by(is_less_to_compare!(is_less)));
+            return Vec::new();
+        }
+        let bounds = gs.bucket_boundaries;
+        let mut left = buckets_left(gs, gs.classifier.equal_buckets)
+            .into_iter()
+            .peekable();
+        split_at_bounds(v, &bounds[..gs.num_buckets])
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| left.next_if_eq(i).is_some())
+            .map(|(i, bucket)| (bounds[i], (bucket, depth_limit - 1)))
+            .collect()
     }
 
-    for bucket in parallel_queue {
-        par_recurse(bucket, lss, gs, is_less);
+    fn sort_sequential(&self, (v, depth_limit): (&'v mut [T], usize), ls: &mut Self::Storage) {
+        seq_recurse(v, ls, self.is_less, depth_limit);
     }
-    let lss = lss
-        .iter_mut()
-        .map(Mutex::new)
-        .collect::<Vec<Mutex<&mut LocalStorage<T, F>>>>();
-    scope(|s| {
-        for bucket in sequential_queue.into_iter() {
-            s.spawn(|_| seq_recurse_wrapper(bucket, &lss, is_less));
-        }
-    });
-}
 
-/// Must be called from inside a thread pool
-pub(crate) fn seq_recurse_wrapper<T, F>(
-    v: &mut [T],
-    lss: &[Mutex<&mut LocalStorage<T, F>>],
-    is_less: &F,
-) where
-    T: Sortable,
-    F: Less<T>,
-{
-    let mut ls = lss[current_thread_index().unwrap()].lock().unwrap();
-    seq_recurse(v, *ls, is_less)
+    fn presort(
+        &self,
+        task: (&'v mut [T], usize),
+        ls: &mut Self::Storage,
+    ) -> Option<(&'v mut [T], usize)> {
+        (!merge_presorted_bucket(task.0, ls, self.is_less)).then_some(task)
+    }
 }
 
-fn partition<T, F>(
+/// Returns false if the blocks don't fit into their buckets after block permutation, see
+/// [blocks_fit_buckets]. `v` is left unpartitioned in that case.
+fn partition<'a, T, F>(
     v: &mut [T],
-    lss: &mut [LocalStorage<T, F>],
-    gs: &mut GlobalStorage<T, F>,
+    lss: &mut [LocalStorage<'a, T, F>],
+    gs: &mut GlobalStorage<'a, T, F>,
     is_less: &F,
-) where
+) -> bool
+where
     T: PSortable,
     F: PLess<T>,
 {
-    let num_threads = current_num_threads();
+    // The sample is much smaller than the input, so it is sorted sequentially
     let mut sorting_callback =
-        |v: &mut [T], gs: &mut GlobalStorage<T, F>| parallel(v, lss, gs, is_less);
-    get_splitters(v, gs, &mut sorting_callback, is_less);
+        |v: &mut [T], gs: &mut GlobalStorage<'a, T, F>| sequential(v, gs, is_less);
+    get_splitters(v, (), gs, &mut sorting_callback, is_less);
 
     gs.classifier.build();
     debug_assert!(gs.classifier.test_classification(v, is_less));
 
+    let is_last_level = v.len() <= gs.config.single_level_threshold();
+    partition_blocks(v, (), lss, gs, is_less, is_last_level)
+}
+
+/// Parallel version of [crate::sequential::partition_blocks], the thread pool must have as many
+/// threads as there are local storages.
+pub(crate) fn partition_blocks<T, F, C, L, P>(
+    v: &mut [T],
+    rows: P,
+    lss: &mut [LocalStorage<T, F, C, P::Buffers>],
+    gs: &mut GlobalStorage<T, F, C, P::Buffers>,
+    is_less: &L,
+    is_last_level: bool,
+) -> bool
+where
+    T: PSortable,
+    C: Classify<T> + Send + Sync,
+    L: PLess<T>,
+    P: Payload,
+{
+    let num_threads = lss.len();
+    let block_size = gs.config.block_size;
     // 0.5 is added to avoid rounding errors
     let stripe_len_temp = v.len() as f64 / num_threads as f64;
     let mut stripe_bounds = Vec::new();
     stripe_bounds.reserve_exact(num_threads);
     for i in 0..num_threads {
         let temp = (i as f64 * stripe_len_temp + 0.5) as usize;
-        stripe_bounds.push(round_up_to_block_size(temp).min(v.len()));
+        stripe_bounds.push(round_up_to_block_size(temp, block_size).min(v.len()));
     }
     debug_assert!(stripe_bounds[0] == 0);
 
+    let mut stripe_ranges = Vec::new();
+    for i in 0..num_threads - 1 {
+        stripe_ranges.push(stripe_bounds[i]..stripe_bounds[i + 1]);
+    }
+    stripe_ranges.push(stripe_bounds[num_threads - 1]..v.len());
+
     let mut stripes = split_at_bounds(v, &stripe_bounds);
     let mut results = vec![([0; MAX_BUCKETS], 0); num_threads];
-    scope(|s| {
-        // Give every thread an equal part of the input to classify locally
-        for ((stripe, ls), r) in stripes
-            .iter_mut()
-            .zip(lss.iter_mut())
-            .zip(results.iter_mut())
+    let classification = panic::catch_unwind(AssertUnwindSafe(|| {
+        scope(|s| {
+            // Give every thread an equal part of the input to classify locally
+            let classifier = &gs.classifier;
+            let num_buckets = gs.num_buckets;
+            for (((stripe, ls), r), &start) in stripes
+                .iter_mut()
+                .zip(lss.iter_mut())
+                .zip(results.iter_mut())
+                .zip(&stripe_bounds)
+            {
+                s.spawn(move |_| {
+                    let elements_written = classifier.classify_locally(
+                        stripe,
+                        rows.skip(start),
+                        &mut ls.bucket_buffers,
+                        &mut ls.payload_buffers,
+                        &mut ls.elements_written_per_bucket,
+                        num_buckets,
+                    );
+
+                    let elements_per_bucket = ls.elements_written_per_bucket;
+                    *r = (elements_per_bucket, elements_written);
+                    debug_assert!(test_stripe_classification(
+                        classifier,
+                        stripe,
+                        &elements_per_bucket[..num_buckets],
+                        elements_written,
+                        block_size,
+                    ));
+                });
+            }
+        })
+    }));
+    if let Err(payload) = classification {
+        // A thread that panicked during classification already restored its stripe and left
+        // its buffers empty, the other stripes are restored here
+        for ((ls, range), (_, elements_written)) in lss.iter_mut().zip(&stripe_ranges).zip(&results)
         {
-            s.spawn(|_| {
-                let elements_written = gs.classifier.classify_locally(
-                    stripe,
-                    &mut ls.bucket_buffers,
-                    &mut ls.elements_written_per_bucket,
-                    gs.num_buckets,
-                );
-
-                let elements_per_bucket = ls.elements_written_per_bucket;
-                debug_assert!(gs.classifier.test_stripe_classification(
-                    stripe,
-                    &elements_per_bucket,
-                    elements_written,
-                ));
-                *r = (elements_per_bucket, elements_written);
-            });
+            let hole = range.start + elements_written..range.end;
+            let mut filler = HoleFiller::suffix(&mut v[range.clone()], *elements_written);
+            // SAFETY: see ClassificationGuard
+            unsafe {
+                filler.fill_from_bucket_buffers(&mut ls.bucket_buffers);
+                rows.fill_holes(slice::from_ref(&hole), vec![&mut ls.payload_buffers], false);
+            }
         }
-    });
+        panic::resume_unwind(payload);
+    }
 
     let elements_per_bucket = results
         .iter()
@@ -204,71 +252,119 @@ fn partition<T, F>(
         gs.num_buckets,
         &elements_per_bucket,
     );
-
-    let mut stripe_ranges = Vec::new();
-    for i in 0..num_threads - 1 {
-        stripe_ranges.push(stripe_bounds[i]..stripe_bounds[i + 1]);
+    // The pointers are set in move_empty_blocks()
+    let kind = gs.config.atomic_pointers.resolve(v.len());
+    for bp in &mut gs.bucket_pointers[..gs.num_buckets] {
+        *bp = BucketPointer::new(0, 0, block_size, kind);
     }
-    stripe_ranges.push(stripe_bounds[num_threads - 1]..v.len());
 
     let bounds = gs.bucket_boundaries[..gs.num_buckets]
         .iter()
-        .map(|i| i - i % BLOCK_SIZE)
+        .map(|i| i - i % block_size)
         .collect::<Vec<_>>();
-    let buckets = split_at_bounds(v, &bounds);
+    let buckets_per_thread = (gs.num_buckets + num_threads - 1) / num_threads;
+    let mut buckets = split_at_bounds(v, &bounds);
     scope(|s| {
-        for (i, bucket) in buckets.into_iter().enumerate() {
+        for (i, my_buckets) in buckets.chunks_mut(buckets_per_thread).enumerate() {
             let stripe_ranges = &stripe_ranges;
             let elements_written_per_thread = &elements_written_per_thread;
             let bucket_boundaries = &gs.bucket_boundaries;
             let bucket_pointers = &gs.bucket_pointers;
+            let bounds = &bounds;
             s.spawn(move |_| {
-                move_empty_blocks(
-                    bucket,
-                    i,
-                    stripe_ranges,
-                    elements_written_per_thread,
-                    bucket_boundaries,
-                    bucket_pointers,
-                )
+                for (j, bucket) in my_buckets.iter_mut().enumerate() {
+                    let bucket_number = i * buckets_per_thread + j;
+                    move_empty_blocks(
+                        bucket,
+                        rows.skip(bounds[bucket_number]),
+                        bucket_number,
+                        stripe_ranges,
+                        elements_written_per_thread,
+                        bucket_boundaries,
+                        bucket_pointers,
+                        block_size,
+                    )
+                }
             })
         }
     });
 
-    let buckets_per_thread = (gs.num_buckets + num_threads - 1) / num_threads;
-    let bounds = gs.bucket_boundaries[..gs.num_buckets]
-        .iter()
-        .map(|i| i - i % BLOCK_SIZE)
-        .collect::<Vec<_>>();
-    let buckets = split_at_bounds(v, &bounds)
-        .into_iter()
-        .map(Mutex::new)
-        .collect::<Vec<_>>();
-    let my_buckets = &buckets[..];
-
-    scope(|s| {
-        for (i, ls) in lss.iter_mut().enumerate() {
-            let my_first_bucket = i * buckets_per_thread;
-            let my_buckets = &my_buckets;
-            let bounds = &bounds;
-            let c = &gs.classifier;
-            let sb = &mut ls.swap_buffers;
-            let bucket_pointers = &gs.bucket_pointers[..gs.num_buckets];
-            let num_buckets = gs.num_buckets;
-            s.spawn(move |_| {
-                permute_blocks_parallel(
-                    my_buckets,
-                    bounds,
-                    c,
-                    sb,
-                    bucket_pointers,
-                    my_first_bucket,
-                    num_buckets,
-                )
-            });
+    for ls in lss.iter_mut() {
+        ls.swap_buffers.clear();
+        P::clear_swap(&mut ls.payload_buffers, 0);
+        P::clear_swap(&mut ls.payload_buffers, 1);
+    }
+    let permutation = panic::catch_unwind(AssertUnwindSafe(|| {
+        let shared = SharedSlice::new(v);
+        scope(|s| {
+            for (i, ls) in lss.iter_mut().enumerate() {
+                let my_first_bucket = i * buckets_per_thread;
+                let shared = &shared;
+                let c = &gs.classifier;
+                let sb = &mut ls.swap_buffers;
+                let pb = &mut ls.payload_buffers;
+                let bucket_pointers = &gs.bucket_pointers[..gs.num_buckets];
+                let bucket_boundaries = &gs.bucket_boundaries[..gs.num_buckets + 1];
+                s.spawn(move |_| {
+                    permute_blocks_parallel(
+                        shared,
+                        rows,
+                        c,
+                        sb,
+                        pb,
+                        bucket_pointers,
+                        bucket_boundaries,
+                        my_first_bucket,
+                    )
+                });
+            }
+        });
+        debug_assert!(test_block_permutation(v, gs));
+    }));
+    if let Err(payload) = permutation {
+        let holes = holes_during_permutation(
+            &gs.bucket_boundaries[..gs.num_buckets + 1],
+            &gs.bucket_pointers[..gs.num_buckets],
+            block_size,
+        );
+        let payload_buffers = lss.iter_mut().map(|ls| &mut ls.payload_buffers).collect();
+        // SAFETY: see holes_during_permutation(), all threads have stopped permuting blocks
+        unsafe { rows.fill_holes(&holes, payload_buffers, true) };
+        let mut filler = HoleFiller::new(v, holes);
+        // SAFETY: see holes_during_permutation(), all threads have stopped permuting blocks
+        unsafe {
+            for ls in lss.iter_mut() {
+                filler.fill_from_swap_buffers(&mut ls.swap_buffers);
+                filler.fill_from_bucket_buffers(&mut ls.bucket_buffers);
+            }
         }
-    });
-    debug_assert!(test_block_permutation(v, gs));
+        debug_assert!(filler.is_filled());
+        panic::resume_unwind(payload);
+    }
+    if !blocks_fit_buckets(
+        &gs.bucket_boundaries[..gs.num_buckets + 1],
+        &gs.bucket_pointers[..gs.num_buckets],
+        block_size,
+        |i| lss.iter().map(|ls| ls.bucket_buffers.len(i)).sum(),
+    ) {
+        let holes = holes_during_permutation(
+            &gs.bucket_boundaries[..gs.num_buckets + 1],
+            &gs.bucket_pointers[..gs.num_buckets],
+            block_size,
+        );
+        let payload_buffers = lss.iter_mut().map(|ls| &mut ls.payload_buffers).collect();
+        // SAFETY: see holes_during_permutation(), the swap buffers are empty
+        unsafe { rows.fill_holes(&holes, payload_buffers, false) };
+        let mut filler = HoleFiller::new(v, holes);
+        // SAFETY: see holes_during_permutation(), the swap buffers are empty
+        unsafe {
+            for ls in lss.iter_mut() {
+                filler.fill_from_bucket_buffers(&mut ls.bucket_buffers);
+            }
+        }
+        debug_assert!(filler.is_filled());
+        return false;
+    }
 
     let mut swaps: Vec<Option<usize>> = vec![None; num_threads];
     scope(|s| {
@@ -277,7 +373,7 @@ fn partition<T, F>(
             let v = &v;
             let gs = &gs;
             s.spawn(move |_| {
-                *swap = save_margins(v, my_first_bucket, ls, gs);
+                *swap = save_margins(v, rows, my_first_bucket, ls, gs);
             });
         }
     });
@@ -291,43 +387,55 @@ fn partition<T, F>(
 
     debug_assert!(stripe_bounds[0] == 0);
 
-    let v_len = v.len();
     let mut stripes = split_at_bounds(v, &stripe_bounds);
 
-    scope(|s| {
-        for (i, stripe) in stripes.iter_mut().enumerate() {
-            let my_first_bucket = min(i * buckets_per_thread, gs.num_buckets);
-            let my_last_bucket = min((i + 1) * buckets_per_thread, gs.num_buckets);
-            let v_len = v_len;
-            let bucket_boundaries = &gs.bucket_boundaries;
-            let bucket_pointers = &gs.bucket_pointers;
-            let lss = &lss;
-            let swap = &swaps[i];
-            s.spawn(move |_| {
-                cleanup_margins(
-                    stripe,
-                    v_len,
-                    bucket_boundaries,
-                    bucket_pointers,
-                    my_first_bucket,
-                    my_last_bucket,
-                    i,
-                    lss,
-                    swap,
-                    is_less,
-                )
-            });
-        }
-    });
+    let cleanup = panic::catch_unwind(AssertUnwindSafe(|| {
+        scope(|s| {
+            for (i, stripe) in stripes.iter_mut().enumerate() {
+                let my_first_bucket = min(i * buckets_per_thread, gs.num_buckets);
+                let my_last_bucket = min((i + 1) * buckets_per_thread, gs.num_buckets);
+                let bucket_boundaries = &gs.bucket_boundaries;
+                let bucket_pointers = &gs.bucket_pointers;
+                let lss = &lss;
+                let swap = &swaps[i];
+                s.spawn(move |_| {
+                    cleanup_margins(
+                        stripe,
+                        rows,
+                        is_last_level,
+                        bucket_boundaries,
+                        bucket_pointers,
+                        my_first_bucket,
+                        my_last_bucket,
+                        i,
+                        lss,
+                        swap,
+                        is_less,
+                    )
+                });
+            }
+        })
+    }));
+    // Every thread moved its elements back before calling the comparison function,
+    // so the buffers are empty even if one of them panicked
     for s in lss.iter_mut() {
         // reset buffers
         s.bucket_buffers.clear_buckets();
+        s.swap_buffers.clear();
+        P::clear(&mut s.payload_buffers);
+    }
+    if let Err(payload) = cleanup {
+        panic::resume_unwind(payload);
     }
     debug_assert!(test_cleanup_margins(v, gs));
-    debug_assert!(v.len() > SINGLE_LEVEL_THRESHOLD || v.is_sorted_by(is_less_to_compare!(is_less)));
+    debug_assert!(!is_last_level || v.is_sorted_by(is_less_to_compare!(is_less)));
+    true
 }
 
-fn split_at_bounds<'a, T>(v: &'a mut [T], splitting_points: &[usize]) -> Vec<&'a mut [T]> {
+pub(crate) fn split_at_bounds<'a, T>(
+    v: &'a mut [T],
+    splitting_points: &[usize],
+) -> Vec<&'a mut [T]> {
     let mut stripes = Vec::new();
     let (mut temp_v, right) = v.split_at_mut(*splitting_points.last().unwrap());
     stripes.push(right);
@@ -340,15 +448,16 @@ fn split_at_bounds<'a, T>(v: &'a mut [T], splitting_points: &[usize]) -> Vec<&'a
     stripes
 }
 
-fn save_margins<T, F>(
+fn save_margins<T, F, C, P>(
     v: &[T],
+    rows: P,
     first_bucket: usize,
-    ls: &mut LocalStorage<T, F>,
-    gs: &GlobalStorage<T, F>,
+    ls: &mut LocalStorage<T, F, C, P::Buffers>,
+    gs: &GlobalStorage<T, F, C, P::Buffers>,
 ) -> Option<usize>
 where
     T: Sortable,
-    F: Less<T>,
+    P: Payload,
 {
     //        head                 tail
     //        <-->                 <--->
@@ -361,9 +470,10 @@ where
     // - tail is empty
     // - head might be filled (is filled if at least one block was written back)
 
+    let block_size = gs.config.block_size;
     let head_start =
-        gs.bucket_boundaries[first_bucket] - gs.bucket_boundaries[first_bucket] % BLOCK_SIZE;
-    let next_block_boundary = head_start + BLOCK_SIZE;
+        gs.bucket_boundaries[first_bucket] - gs.bucket_boundaries[first_bucket] % block_size;
+    let next_block_boundary = head_start + block_size;
     let head_bucket;
     'block: {
         // Find bucket this first block belongs to
@@ -371,7 +481,7 @@ where
         let mut bucket = first_bucket;
         while bucket < gs.num_buckets && gs.bucket_boundaries[bucket] < next_block_boundary {
             let size_of_bucket = gs.bucket_boundaries[bucket + 1] - gs.bucket_boundaries[bucket];
-            if size_of_bucket >= BLOCK_SIZE {
+            if size_of_bucket >= block_size {
                 // if bucket is smaller than a block, the block cannot belong to the bucket
                 head_bucket = bucket;
                 break 'block;
@@ -395,25 +505,37 @@ where
     }
 
     // Read head elements
-    ls.swap_buffers.fill_with(0, &v[head_range]);
+    // SAFETY: the head is overwritten with the tail of the previous bucket in cleanup_margins()
+    unsafe {
+        rows.read_swap(
+            &mut ls.payload_buffers,
+            0,
+            head_range.start,
+            head_range.len(),
+        );
+        ls.swap_buffers.fill_with(0, &v[head_range]);
+    }
     Some(head_bucket)
 }
 
+/// `rows` are the rows of the whole slice, not only of the stripe
 #[allow(clippy::too_many_arguments)]
-fn cleanup_margins<T, F>(
+fn cleanup_margins<T, F, C, L, P>(
     stripe: &mut [T],
-    v_len: usize,
+    rows: P,
+    is_last_level: bool,
     bucket_boundaries: &[usize],
     bucket_pointers: &[BucketPointer],
     first_bucket: usize,
     last_bucket: usize,
     thread_id: usize,
-    lss: &[LocalStorage<T, F>],
+    lss: &[LocalStorage<T, F, C, P::Buffers>],
     swap: &Option<usize>,
-    is_less: &F,
+    is_less: &L,
 ) where
     T: Sortable,
-    F: Less<T>,
+    L: Less<T>,
+    P: Payload,
 {
     //        head                 tail
     //        <-->                 <--->
@@ -445,7 +567,7 @@ fn cleanup_margins<T, F>(
     //
     // ------|------|------|------|------|------|------|------
     //    ...        ][  bucket i  ][    ...
-    let is_last_level = v_len <= SINGLE_LEVEL_THRESHOLD;
+    let block_size = lss[thread_id].config.block_size;
     for i in (first_bucket..last_bucket).rev() {
         // as the indices saved in `bucket_boundaries` and `bucket_pointers` are "global" indices,
         // they have to be converted into "stripe local" indices
@@ -453,11 +575,11 @@ fn cleanup_margins<T, F>(
         let start = bucket_boundaries[i];
         let end = bucket_boundaries[i + 1];
         let (write, _read) = bucket_pointers[i].fetch();
-        let head_range = (start - start % BLOCK_SIZE)..start;
+        let head_range = (start - start % block_size)..start;
         let offset = bucket_boundaries[first_bucket];
 
         let mut tail_beginning;
-        debug_assert!(head_range.len() < BLOCK_SIZE);
+        debug_assert!(head_range.len() < block_size);
         if write == end {
             // end is block aligned and block was written
             // write only increases when block is written back
@@ -467,13 +589,26 @@ fn cleanup_margins<T, F>(
             // head of this bucket was saved in save_margins()
             let swap_buffer = lss[thread_id].swap_buffers.get(0);
 
-            stripe[write - offset..write - offset + swap_buffer.len()].clone_from_slice(swap_buffer);
+            // SAFETY: the head is overwritten with the tail of the previous bucket below
+            unsafe {
+                move_from_slice(
+                    &mut stripe[write - offset..write - offset + swap_buffer.len()],
+                    swap_buffer,
+                );
+                rows.write_swap(&lss[thread_id].payload_buffers, 0, write);
+            }
             tail_beginning = write + swap_buffer.len();
         } else if start < write {
             // first block was written back into v => head is filled
-            // workaround, as slice::clone_within(&mut self, R, usize) doesn't exit
             let (head_slice, write_slice) = stripe.split_at_mut(write - offset);
-            write_slice[..head_range.len()].clone_from_slice(&head_slice[head_range.start - offset..head_range.end - offset]);
+            // SAFETY: the head is overwritten with the tail of the previous bucket below
+            unsafe {
+                move_from_slice(
+                    &mut write_slice[..head_range.len()],
+                    &head_slice[head_range.start - offset..head_range.end - offset],
+                );
+                rows.copy(head_range.start, write, head_range.len());
+            }
 
             tail_beginning = write + head_range.len();
         } else {
@@ -486,13 +621,26 @@ fn cleanup_margins<T, F>(
             let src = ls.bucket_buffers.get(i);
             let count = src.len();
             let tail = &mut stripe[tail_beginning - offset..tail_beginning - offset + count];
-            tail.clone_from_slice(src);
+            // SAFETY: the tail only holds elements which were moved into the buffers
+            // or head elements which were moved above
+            unsafe {
+                move_from_slice(tail, src);
+                rows.write_bucket(&ls.payload_buffers, i, tail_beginning);
+            }
             tail_beginning += count;
         }
         debug_assert_eq!(tail_beginning, end);
+    }
 
-        if is_last_level || end - start <= 2 * BASE_CASE_SIZE {
-            base_case_sort(&mut stripe[start - offset..end - offset], is_less);
+    // All elements of this stripe are back in place, so the comparison function may panic
+    // from here on
+    let offset = bucket_boundaries[first_bucket];
+    let base_case_size = lss[thread_id].config.base_case_size;
+    for i in first_bucket..last_bucket {
+        let (start, end) = (bucket_boundaries[i], bucket_boundaries[i + 1]);
+        if is_last_level || end - start <= 2 * base_case_size {
+            let bucket = &mut stripe[start - offset..end - offset];
+            base_case_sort_rows(bucket, rows.skip(start), is_less);
         }
     }
 }
@@ -0,0 +1,179 @@
+use std::sync::{Mutex, PoisonError};
+
+use rayon::scope;
+
+use crate::{sequential::buckets_to_sort, storage::LocalStorage};
+
+/// A recursive sort whose buckets are scheduled by [par_recurse].
+///
+/// A `Task` is a bucket together with everything that is sorted along with it and the state of
+/// the recursion, e.g. the remaining recursion depth.
+pub(crate) trait ParallelSort<Task: Send>: Sync {
+    /// What a thread needs to partition or sort a task
+    type Storage: Send;
+
+    fn len(&self, task: &Task) -> usize;
+
+    /// Partitions `task` with one local storage per thread and a global storage.
+    ///
+    /// Returns the buckets that are left to sort, with the position of their first element in
+    /// `task`. Returns none if `task` is sorted already, e.g. by a fallback.
+    fn partition(
+        &self,
+        task: Task,
+        lss: &mut [Self::Storage],
+        gs: &mut Self::Storage,
+    ) -> Vec<(usize, Task)>;
+
+    /// Sorts `task` with a single thread
+    fn sort_sequential(&self, task: Task, ls: &mut Self::Storage);
+
+    /// Sorts `task` without partitioning it if that is cheap, see
+    /// [crate::sequential::merge_presorted_bucket]. Returns the task if it is left to sort.
+    fn presort(&self, task: Task, _ls: &mut Self::Storage) -> Option<Task> {
+        Some(task)
+    }
+}
+
+/// Storages that are not used by a running task.
+///
+/// Tasks take the storages they need and put them back when they are done. If none are left, a
+/// new one is allocated instead of waiting, so tasks never block each other.
+pub(crate) struct StoragePool<'a, S> {
+    free: Mutex<Vec<S>>,
+    new_storage: &'a (dyn Fn() -> S + Sync),
+}
+
+impl<'a, S> StoragePool<'a, S>
+where
+    S: Send,
+{
+    pub(crate) fn new(free: Vec<S>, new_storage: &'a (dyn Fn() -> S + Sync)) -> Self {
+        Self {
+            free: Mutex::new(free),
+            new_storage,
+        }
+    }
+
+    fn take(&self, n: usize) -> Vec<S> {
+        let mut free = self.free.lock().unwrap_or_else(PoisonError::into_inner);
+        let keep = free.len().saturating_sub(n);
+        let mut storages = free.split_off(keep);
+        drop(free);
+        storages.resize_with(n, self.new_storage);
+        storages
+    }
+
+    fn take_one(&self) -> S {
+        self.take(1).pop().unwrap()
+    }
+
+    fn put(&self, storages: impl IntoIterator<Item = S>) {
+        let mut free = self.free.lock().unwrap_or_else(PoisonError::into_inner);
+        free.extend(storages);
+    }
+
+    pub(crate) fn into_free(self) -> Vec<S> {
+        let free = self.free.into_inner();
+        free.unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Returns the buckets of `storage` that are left to sort after partitioning, see
+/// [buckets_to_sort]. Smaller buckets were sorted in cleanup_margins().
+pub(crate) fn buckets_left<T, F, C, B>(
+    storage: &LocalStorage<T, F, C, B>,
+    equal_buckets: bool,
+) -> Vec<usize> {
+    let bounds = &storage.bucket_boundaries;
+    buckets_to_sort(storage.num_buckets, equal_buckets)
+        .filter(|&i| bounds[i + 1] - bounds[i] > 2 * storage.config.base_case_size)
+        .collect()
+}
+
+/// Partitions `task` with a group of `num_threads` threads and schedules its buckets.
+///
+/// Like in the IPS4o paper, the threads of the group are assigned to the buckets in proportion
+/// to their size: thread `i` belongs to the bucket holding element `i * len / num_threads`.
+/// Buckets with at least two threads are sorted in parallel by a subgroup of those threads, all
+/// other buckets become sequential tasks. Threads without a bucket and subgroups that are done
+/// take the sequential tasks from a shared queue, largest first.
+///
+/// The group never runs more than `num_threads` jobs at a time, however many threads the pool
+/// has, and every job takes the storages it needs from `storages` for itself.
+pub(crate) fn par_recurse<S, Task>(
+    sorter: &S,
+    task: Task,
+    storages: &StoragePool<S::Storage>,
+    num_threads: usize,
+) where
+    S: ParallelSort<Task>,
+    Task: Send,
+{
+    let len = sorter.len(&task);
+    let mut lss = storages.take(num_threads);
+    let mut gs = storages.take_one();
+    let buckets = sorter.partition(task, &mut lss, &mut gs);
+    storages.put(lss);
+    storages.put([gs]);
+
+    let mut parallel_tasks = Vec::new();
+    let mut sequential_tasks = Vec::new();
+    for (start, bucket) in buckets {
+        let end = start + sorter.len(&bucket);
+        let threads = end * num_threads / len - start * num_threads / len;
+        if threads >= 2 {
+            parallel_tasks.push((bucket, threads));
+        } else {
+            sequential_tasks.push(bucket);
+        }
+    }
+
+    let idle_threads = num_threads - parallel_tasks.iter().map(|t| t.1).sum::<usize>();
+    sequential_tasks.sort_unstable_by_key(|bucket| sorter.len(bucket));
+    let sequential_tasks = Mutex::new(sequential_tasks);
+    let sequential_tasks = &sequential_tasks;
+    scope(|s| {
+        for (bucket, threads) in parallel_tasks {
+            s.spawn(move |s| {
+                let mut ls = storages.take_one();
+                let bucket = sorter.presort(bucket, &mut ls);
+                storages.put([ls]);
+                if let Some(bucket) = bucket {
+                    par_recurse(sorter, bucket, storages, threads);
+                }
+                for _ in 0..threads {
+                    s.spawn(move |_| sort_sequential_tasks(sorter, sequential_tasks, storages));
+                }
+            });
+        }
+        for _ in 0..idle_threads {
+            s.spawn(move |_| sort_sequential_tasks(sorter, sequential_tasks, storages));
+        }
+    });
+}
+
+/// Sorts the buckets of `tasks` one after another, until no bucket is left.
+fn sort_sequential_tasks<S, Task>(
+    sorter: &S,
+    tasks: &Mutex<Vec<Task>>,
+    storages: &StoragePool<S::Storage>,
+) where
+    S: ParallelSort<Task>,
+    Task: Send,
+{
+    let mut ls = None;
+    loop {
+        let task = tasks.lock().unwrap_or_else(PoisonError::into_inner).pop();
+        match task {
+            Some(task) => {
+                let ls = ls.get_or_insert_with(|| storages.take_one());
+                if let Some(task) = sorter.presort(task, ls) {
+                    sorter.sort_sequential(task, ls);
+                }
+            }
+            None => break,
+        }
+    }
+    storages.put(ls);
+}
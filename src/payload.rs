@@ -0,0 +1,372 @@
+//! Rows of payload columns which are moved along with the elements during block partitioning,
+//! see [crate::sort_zip].
+//!
+//! Whenever partitioning moves elements within the slice or into and out of the bucket and swap
+//! buffers, it calls the same operation of the [Payload] with the same positions, so every row
+//! stays at the position of its key. The payload `()` does nothing, so sorts without payload
+//! compile to the same code as before.
+
+use std::{marker::PhantomData, ops::Range, ptr, slice};
+
+use crate::{
+    base_case::base_case_sort,
+    is_less_to_compare,
+    restore::HoleFiller,
+    storage::{BucketBuffers, SwapBuffers},
+    Less,
+};
+
+/// A view of the rows that are moved along with the elements of a slice, from some position
+/// on. Positions are relative to the start of the view.
+///
+/// Like the operations on the elements they mirror, the unsafe methods don't check their
+/// positions and leave bitwise copies behind, which must be overwritten before the rows are
+/// used again.
+pub trait Payload: Copy + Send + Sync {
+    /// The bucket and swap buffers of one thread, see [BucketBuffers] and [SwapBuffers]
+    type Buffers: Send + Sync;
+
+    /// True if there are no rows to move
+    const IS_EMPTY: bool = false;
+
+    fn new_buffers(block_size: usize) -> Self::Buffers;
+
+    /// The rows from `start` on
+    fn skip(self, start: usize) -> Self;
+
+    unsafe fn swap(self, a: usize, b: usize);
+
+    /// Moves `len` rows from `src` to `dest`, the ranges may overlap
+    unsafe fn copy(self, src: usize, dest: usize, len: usize);
+
+    /// Moves row `i` into bucket buffer `bucket`, see [BucketBuffers::unchecked_push]
+    unsafe fn push(self, buffers: &mut Self::Buffers, bucket: usize, i: usize);
+
+    /// Moves the rows of bucket buffer `bucket` to the rows from `dest` on, the buffer must be
+    /// cleared afterwards
+    unsafe fn write_bucket(self, buffers: &Self::Buffers, bucket: usize, dest: usize);
+
+    fn clear_bucket(buffers: &mut Self::Buffers, bucket: usize);
+
+    /// Moves `len` rows from `start` on into swap buffer `index`, see [SwapBuffers::fill_with]
+    unsafe fn read_swap(self, buffers: &mut Self::Buffers, index: usize, start: usize, len: usize);
+
+    /// Moves the rows of swap buffer `index` to the rows from `dest` on, the buffer must be
+    /// cleared afterwards
+    unsafe fn write_swap(self, buffers: &Self::Buffers, index: usize, dest: usize);
+
+    fn clear_swap(buffers: &mut Self::Buffers, index: usize);
+
+    /// Forgets the contents of all buffers
+    fn clear(buffers: &mut Self::Buffers);
+
+    /// Moves the rows of `buffers` into `holes` like a [HoleFiller] does with the elements: the
+    /// swap buffers first if `swap` is true, then the bucket buffers, for each of the buffers in
+    /// turn. The buffers are empty afterwards.
+    unsafe fn fill_holes(
+        self,
+        holes: &[Range<usize>],
+        buffers: Vec<&mut Self::Buffers>,
+        swap: bool,
+    );
+}
+
+impl Payload for () {
+    type Buffers = ();
+    const IS_EMPTY: bool = true;
+
+    fn new_buffers(_block_size: usize) {}
+
+    fn skip(self, _start: usize) {}
+
+    unsafe fn swap(self, _a: usize, _b: usize) {}
+
+    unsafe fn copy(self, _src: usize, _dest: usize, _len: usize) {}
+
+    unsafe fn push(self, _buffers: &mut (), _bucket: usize, _i: usize) {}
+
+    unsafe fn write_bucket(self, _buffers: &(), _bucket: usize, _dest: usize) {}
+
+    fn clear_bucket(_buffers: &mut (), _bucket: usize) {}
+
+    unsafe fn read_swap(self, _buffers: &mut (), _index: usize, _start: usize, _len: usize) {}
+
+    unsafe fn write_swap(self, _buffers: &(), _index: usize, _dest: usize) {}
+
+    fn clear_swap(_buffers: &mut (), _index: usize) {}
+
+    fn clear(_buffers: &mut ()) {}
+
+    unsafe fn fill_holes(self, _holes: &[Range<usize>], _buffers: Vec<&mut ()>, _swap: bool) {}
+}
+
+/// The rows of a single column
+#[derive(Debug)]
+pub struct Column<'a, V> {
+    ptr: *mut V,
+    len: usize,
+    column: PhantomData<&'a mut [V]>,
+}
+
+impl<'a, V> Column<'a, V> {
+    pub(crate) fn new(column: &'a mut [V]) -> Self {
+        Self {
+            ptr: column.as_mut_ptr(),
+            len: column.len(),
+            column: PhantomData,
+        }
+    }
+}
+
+impl<V> Clone for Column<'_, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<V> Copy for Column<'_, V> {}
+
+// SAFETY: threads only move the rows of the blocks and buckets they claimed for their elements,
+// see SharedSlice. Columns are only shared by the parallel sorts, which require `V: Send`.
+unsafe impl<V> Send for Column<'_, V> {}
+unsafe impl<V> Sync for Column<'_, V> {}
+
+/// The buffers of a [Column]
+#[derive(Debug)]
+pub struct ColumnBuffers<V> {
+    bucket_buffers: BucketBuffers<V>,
+    swap_buffers: SwapBuffers<V>,
+}
+
+// SAFETY: see Column, the buffers are empty between partitioning steps
+unsafe impl<V> Send for ColumnBuffers<V> {}
+unsafe impl<V> Sync for ColumnBuffers<V> {}
+
+impl<V> Payload for Column<'_, V> {
+    type Buffers = ColumnBuffers<V>;
+
+    fn new_buffers(block_size: usize) -> Self::Buffers {
+        ColumnBuffers {
+            bucket_buffers: BucketBuffers::new(block_size),
+            swap_buffers: SwapBuffers::new(block_size),
+        }
+    }
+
+    fn skip(self, start: usize) -> Self {
+        assert!(start <= self.len);
+        Self {
+            // SAFETY: start is at most the length of the column
+            ptr: unsafe { self.ptr.add(start) },
+            len: self.len - start,
+            column: PhantomData,
+        }
+    }
+
+    unsafe fn swap(self, a: usize, b: usize) {
+        debug_assert!(a < self.len && b < self.len);
+        ptr::swap(self.ptr.add(a), self.ptr.add(b));
+    }
+
+    unsafe fn copy(self, src: usize, dest: usize, len: usize) {
+        debug_assert!(src + len <= self.len && dest + len <= self.len);
+        ptr::copy(self.ptr.add(src), self.ptr.add(dest), len);
+    }
+
+    unsafe fn push(self, buffers: &mut Self::Buffers, bucket: usize, i: usize) {
+        debug_assert!(i < self.len);
+        buffers
+            .bucket_buffers
+            .unchecked_push(bucket, self.ptr.add(i));
+    }
+
+    unsafe fn write_bucket(self, buffers: &Self::Buffers, bucket: usize, dest: usize) {
+        let rows = buffers.bucket_buffers.get(bucket);
+        debug_assert!(dest + rows.len() <= self.len);
+        ptr::copy_nonoverlapping(rows.as_ptr(), self.ptr.add(dest), rows.len());
+    }
+
+    fn clear_bucket(buffers: &mut Self::Buffers, bucket: usize) {
+        buffers.bucket_buffers.clear(bucket);
+    }
+
+    unsafe fn read_swap(self, buffers: &mut Self::Buffers, index: usize, start: usize, len: usize) {
+        debug_assert!(start + len <= self.len);
+        let rows = slice::from_raw_parts(self.ptr.add(start), len);
+        buffers.swap_buffers.fill_with(index, rows);
+    }
+
+    unsafe fn write_swap(self, buffers: &Self::Buffers, index: usize, dest: usize) {
+        let rows = buffers.swap_buffers.get(index);
+        debug_assert!(dest + rows.len() <= self.len);
+        ptr::copy_nonoverlapping(rows.as_ptr(), self.ptr.add(dest), rows.len());
+    }
+
+    fn clear_swap(buffers: &mut Self::Buffers, index: usize) {
+        buffers.swap_buffers.clear_one(index);
+    }
+
+    fn clear(buffers: &mut Self::Buffers) {
+        buffers.bucket_buffers.clear_buckets();
+        buffers.swap_buffers.clear();
+    }
+
+    unsafe fn fill_holes(
+        self,
+        holes: &[Range<usize>],
+        buffers: Vec<&mut Self::Buffers>,
+        swap: bool,
+    ) {
+        let column = slice::from_raw_parts_mut(self.ptr, self.len);
+        let mut filler = HoleFiller::new(column, holes.to_vec());
+        for b in buffers {
+            if swap {
+                filler.fill_from_swap_buffers(&mut b.swap_buffers);
+            }
+            filler.fill_from_bucket_buffers(&mut b.bucket_buffers);
+        }
+    }
+}
+
+macro_rules! impl_payload_for_tuple {
+    ($($column:ident $index:tt),+) => {
+        impl<$($column: Payload),+> Payload for ($($column,)+) {
+            type Buffers = ($($column::Buffers,)+);
+
+            fn new_buffers(block_size: usize) -> Self::Buffers {
+                ($($column::new_buffers(block_size),)+)
+            }
+
+            fn skip(self, start: usize) -> Self {
+                ($(self.$index.skip(start),)+)
+            }
+
+            unsafe fn swap(self, a: usize, b: usize) {
+                $(self.$index.swap(a, b);)+
+            }
+
+            unsafe fn copy(self, src: usize, dest: usize, len: usize) {
+                $(self.$index.copy(src, dest, len);)+
+            }
+
+            unsafe fn push(self, buffers: &mut Self::Buffers, bucket: usize, i: usize) {
+                $(self.$index.push(&mut buffers.$index, bucket, i);)+
+            }
+
+            unsafe fn write_bucket(self, buffers: &Self::Buffers, bucket: usize, dest: usize) {
+                $(self.$index.write_bucket(&buffers.$index, bucket, dest);)+
+            }
+
+            fn clear_bucket(buffers: &mut Self::Buffers, bucket: usize) {
+                $($column::clear_bucket(&mut buffers.$index, bucket);)+
+            }
+
+            unsafe fn read_swap(
+                self,
+                buffers: &mut Self::Buffers,
+                index: usize,
+                start: usize,
+                len: usize,
+            ) {
+                $(self.$index.read_swap(&mut buffers.$index, index, start, len);)+
+            }
+
+            unsafe fn write_swap(self, buffers: &Self::Buffers, index: usize, dest: usize) {
+                $(self.$index.write_swap(&buffers.$index, index, dest);)+
+            }
+
+            fn clear_swap(buffers: &mut Self::Buffers, index: usize) {
+                $($column::clear_swap(&mut buffers.$index, index);)+
+            }
+
+            fn clear(buffers: &mut Self::Buffers) {
+                $($column::clear(&mut buffers.$index);)+
+            }
+
+            unsafe fn fill_holes(
+                self,
+                holes: &[Range<usize>],
+                mut buffers: Vec<&mut Self::Buffers>,
+                swap: bool,
+            ) {
+                $(
+                    let column_buffers = buffers.iter_mut().map(|b| &mut b.$index).collect();
+                    self.$index.fill_holes(holes, column_buffers, swap);
+                )+
+            }
+        }
+    };
+}
+
+impl_payload_for_tuple!(A 0);
+impl_payload_for_tuple!(A 0, B 1);
+impl_payload_for_tuple!(A 0, B 1, C 2);
+impl_payload_for_tuple!(A 0, B 1, C 2, D 3);
+
+/// Turns payload columns into their [Payload], see [crate::Columns]
+pub trait IntoPayload {
+    type Payload: Payload;
+
+    /// The number of rows, or `None` if the columns have different lengths
+    fn num_rows(&self) -> Option<usize>;
+
+    fn into_payload(self) -> Self::Payload;
+}
+
+impl<'a, V> IntoPayload for &'a mut [V] {
+    type Payload = Column<'a, V>;
+
+    fn num_rows(&self) -> Option<usize> {
+        Some(self.len())
+    }
+
+    fn into_payload(self) -> Self::Payload {
+        Column::new(self)
+    }
+}
+
+macro_rules! impl_into_payload_for_tuple {
+    ($($column:ident $index:tt),+) => {
+        impl<$($column: IntoPayload),+> IntoPayload for ($($column,)+) {
+            type Payload = ($($column::Payload,)+);
+
+            fn num_rows(&self) -> Option<usize> {
+                let rows = [$(self.$index.num_rows()),+];
+                rows.iter().all(|&r| r == rows[0]).then(|| rows[0]).flatten()
+            }
+
+            fn into_payload(self) -> Self::Payload {
+                ($(self.$index.into_payload(),)+)
+            }
+        }
+    };
+}
+
+impl_into_payload_for_tuple!(A 0);
+impl_into_payload_for_tuple!(A 0, B 1);
+impl_into_payload_for_tuple!(A 0, B 1, C 2);
+impl_into_payload_for_tuple!(A 0, B 1, C 2, D 3);
+
+/// Sorts small inputs like [base_case_sort] and moves the rows along.
+///
+/// With a payload, the elements are sorted by insertion sort with swaps, so that every swap can
+/// be applied to the rows as well.
+pub(crate) fn base_case_sort_rows<T, F, P>(v: &mut [T], rows: P, is_less: &F)
+where
+    F: Less<T>,
+    P: Payload,
+{
+    if P::IS_EMPTY {
+        base_case_sort(v, is_less);
+        return;
+    }
+    for i in 1..v.len() {
+        let mut j = i;
+        while j > 0 && is_less(&v[j], &v[j - 1]) {
+            v.swap(j, j - 1);
+            // SAFETY: the rows have as many positions as v
+            unsafe { rows.swap(j, j - 1) };
+            j -= 1;
+        }
+    }
+    debug_assert!(v.is_sorted_by(is_less_to_compare!(is_less)));
+}
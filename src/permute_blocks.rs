@@ -1,31 +1,44 @@
-use std::sync::Mutex;
+use std::{marker::PhantomData, slice};
 
 use crate::{
-    bucket_pointers::BucketPointer,
-    classifier::Classifier,
-    constants::{BLOCK_SIZE, MAX_BUCKETS},
-    storage::SwapBuffers,
-    Less, Sortable,
+    bucket_pointers::BucketPointer, classifier::Classify, constants::MAX_BUCKETS, payload::Payload,
+    storage::SwapBuffers, Sortable,
 };
 
-pub(crate) fn permute_blocks<T, F>(
+/// Moves the blocks into their buckets, the rows of `rows` are moved along through `pb`
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn permute_blocks<T, C, P>(
     v: &mut [T],
-    c: &Classifier<T, F>,
+    rows: P,
+    c: &C,
     sb: &mut SwapBuffers<T>,
+    pb: &mut P::Buffers,
     bucket_pointers: &mut [BucketPointer],
+    bucket_boundaries: &[usize],
     starting_bucket: usize,
 ) where
     T: Sortable,
-    F: Less<T>,
+    C: Classify<T>,
+    P: Payload,
 {
     let mut current_swap;
     for bucket in 0..bucket_pointers.len() {
         let current_bucket = (starting_bucket + bucket) % MAX_BUCKETS;
-        while classify_and_read_block(v, sb, c, bucket_pointers, current_bucket).is_some() {
+        while classify_and_read_block(v, rows, sb, pb, c, bucket_pointers, current_bucket).is_some()
+        {
             current_swap = 0;
             loop {
                 let dest = c.classify_single_element(&sb.get(current_swap)[0]);
-                let performed_swap = swap_block(v, sb, bucket_pointers, dest, current_swap);
+                let performed_swap = swap_block(
+                    v,
+                    rows,
+                    sb,
+                    pb,
+                    bucket_pointers,
+                    bucket_boundaries,
+                    dest,
+                    current_swap,
+                );
                 current_swap = 1 - current_swap;
                 if !performed_swap {
                     break;
@@ -35,24 +48,32 @@ pub(crate) fn permute_blocks<T, F>(
     }
 }
 
-fn classify_and_read_block<T, F>(
+fn classify_and_read_block<T, C, P>(
     v: &[T],
+    rows: P,
     s: &mut SwapBuffers<T>,
-    c: &Classifier<T, F>,
+    pb: &mut P::Buffers,
+    c: &C,
     bucket_pointers: &mut [BucketPointer],
     read_bucket: usize,
 ) -> Option<usize>
 where
     T: Sortable,
-    F: Less<T>,
+    C: Classify<T>,
+    P: Payload,
 {
-    match bucket_pointers[read_bucket].dec_read() {
+    let block_size = s.block_size();
+    match bucket_pointers[read_bucket].dec_read(block_size) {
         Ok((write, read)) => {
             if read < write {
                 // No more blocks to read in this bucket
                 return None;
             }
-            s.fill_with(0, &v[read..read + BLOCK_SIZE]);
+            // SAFETY: the block is now empty, it is overwritten by a later swap_block()
+            unsafe {
+                s.fill_with(0, &v[read..read + block_size]);
+                rows.read_swap(pb, 0, read, block_size);
+            }
 
             Some(c.classify_single_element(&s.get(0)[0]))
         }
@@ -60,59 +81,152 @@ where
     }
 }
 
-fn swap_block<T>(
+/// Blocks of a bucket may be written up to the block containing the start of the next bucket
+fn write_limit(bucket_boundaries: &[usize], bucket: usize, block_size: usize) -> usize {
+    let end = bucket_boundaries[bucket + 1];
+    end - end % block_size
+}
+
+#[allow(clippy::too_many_arguments)]
+fn swap_block<T, P>(
     v: &mut [T],
+    rows: P,
     swap: &mut SwapBuffers<T>,
+    pb: &mut P::Buffers,
     bucket_pointers: &mut [BucketPointer],
+    bucket_boundaries: &[usize],
     dest: usize,
     current_swap: usize,
 ) -> bool
 where
     T: Sortable,
+    P: Payload,
 {
-    let (write, read) = bucket_pointers[dest].inc_write();
+    // If the comparison function is not a strict weak order, blocks can be classified differently
+    // than during local classification, so the destination bucket may already be full. The block
+    // is written into the next bucket with space left instead, there always is one because the
+    // buckets have at least as much space as there are blocks.
+    let num_buckets = bucket_pointers.len();
+    let block_size = swap.block_size();
+    let (write, read) = (dest..num_buckets)
+        .chain(0..dest)
+        .find_map(|bucket| {
+            let limit = write_limit(bucket_boundaries, bucket, block_size);
+            bucket_pointers[bucket].inc_write(limit, block_size)
+        })
+        .expect("every bucket is full");
     if write > read {
         // Destination block is empty
-        v[write - BLOCK_SIZE..write].clone_from_slice(swap.get(current_swap));
+        // SAFETY: empty blocks only hold elements which were moved out before
+        unsafe {
+            swap.write_to(current_swap, &mut v[write - block_size..write]);
+            rows.write_swap(pb, current_swap, write - block_size);
+        }
+        P::clear_swap(pb, current_swap);
         return false;
     }
 
     // Swap blocks
-    swap.fill_with(1 - current_swap, &v[write - BLOCK_SIZE..write]);
-    v[write - BLOCK_SIZE..write].clone_from_slice(swap.get(current_swap));
+    // SAFETY: the destination block is moved into the other swap buffer first
+    unsafe {
+        swap.fill_with(1 - current_swap, &v[write - block_size..write]);
+        swap.write_to(current_swap, &mut v[write - block_size..write]);
+        rows.read_swap(pb, 1 - current_swap, write - block_size, block_size);
+        rows.write_swap(pb, current_swap, write - block_size);
+    }
+    P::clear_swap(pb, current_swap);
     true
 }
 
-pub(crate) fn permute_blocks_parallel<T, F>(
-    buckets: &[Mutex<&mut [T]>],
-    bounds: &[usize],
-    c: &Classifier<T, F>,
+/// The slice whose blocks are permuted by several threads at once.
+///
+/// Threads only access a block after claiming it through the bucket pointers: a block is read
+/// by the thread which decreased the read pointer to its start, and written by the thread which
+/// increased the write pointer to its end. If the write pointer passes the read pointer, the
+/// writer waits until the block was read, see [BucketPointer::start_read].
+#[derive(Debug)]
+pub(crate) struct SharedSlice<'a, T> {
+    ptr: *mut T,
+    len: usize,
+    slice: PhantomData<&'a mut [T]>,
+}
+
+// SAFETY: the threads access disjoint blocks, see above
+unsafe impl<T: Send> Send for SharedSlice<'_, T> {}
+unsafe impl<T: Send> Sync for SharedSlice<'_, T> {}
+
+impl<'a, T: Sortable> SharedSlice<'a, T> {
+    pub(crate) fn new(v: &'a mut [T]) -> Self {
+        Self {
+            ptr: v.as_mut_ptr(),
+            len: v.len(),
+            slice: PhantomData,
+        }
+    }
+
+    /// Moves the block starting at `start` into swap buffer `index`.
+    ///
+    /// # Safety
+    /// The calling thread must have claimed the block for reading, see [SwapBuffers::fill_with].
+    unsafe fn read_block(&self, start: usize, sb: &mut SwapBuffers<T>, index: usize) {
+        let block_size = sb.block_size();
+        debug_assert!(start + block_size <= self.len);
+        sb.fill_with(
+            index,
+            slice::from_raw_parts(self.ptr.add(start), block_size),
+        );
+    }
+
+    /// Moves swap buffer `index` into the block starting at `start`.
+    ///
+    /// # Safety
+    /// The calling thread must have claimed the block for writing, see [SwapBuffers::write_to].
+    unsafe fn write_block(&self, start: usize, sb: &mut SwapBuffers<T>, index: usize) {
+        let block_size = sb.block_size();
+        debug_assert!(start + block_size <= self.len);
+        sb.write_to(
+            index,
+            slice::from_raw_parts_mut(self.ptr.add(start), block_size),
+        );
+    }
+}
+
+/// Parallel version of [permute_blocks], `rows` must be shared like `v`
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn permute_blocks_parallel<T, C, P>(
+    v: &SharedSlice<T>,
+    rows: P,
+    c: &C,
     sb: &mut SwapBuffers<T>,
+    pb: &mut P::Buffers,
     bucket_pointers: &[BucketPointer],
+    bucket_boundaries: &[usize],
     starting_bucket: usize,
-    num_buckets: usize,
 ) where
     T: Sortable,
-    F: Less<T>,
+    C: Classify<T>,
+    P: Payload,
 {
+    let num_buckets = bucket_pointers.len();
     let mut current_swap;
     for bucket in 0..num_buckets {
         let current_bucket = (starting_bucket + bucket) % num_buckets;
-        while classify_and_read_block_parallel(
-            buckets,
-            bounds,
-            sb,
-            c,
-            bucket_pointers,
-            current_bucket,
-        )
-        .is_some()
+        while classify_and_read_block_parallel(v, rows, sb, pb, c, bucket_pointers, current_bucket)
+            .is_some()
         {
             current_swap = 0;
             loop {
                 let dest = c.classify_single_element(&sb.get(current_swap)[0]);
-                let performed_swap =
-                    swap_block_parallel(buckets, bounds, sb, bucket_pointers, dest, current_swap);
+                let performed_swap = swap_block_parallel(
+                    v,
+                    rows,
+                    sb,
+                    pb,
+                    bucket_pointers,
+                    bucket_boundaries,
+                    dest,
+                    current_swap,
+                );
                 current_swap = 1 - current_swap;
                 if !performed_swap {
                     break;
@@ -122,59 +236,95 @@ pub(crate) fn permute_blocks_parallel<T, F>(
     }
 }
 
-fn classify_and_read_block_parallel<T, F>(
-    buckets: &[Mutex<&mut [T]>],
-    bounds: &[usize],
+fn classify_and_read_block_parallel<T, C, P>(
+    v: &SharedSlice<T>,
+    rows: P,
     s: &mut SwapBuffers<T>,
-    c: &Classifier<T, F>,
+    pb: &mut P::Buffers,
+    c: &C,
     bucket_pointers: &[BucketPointer],
     read_bucket: usize,
 ) -> Option<usize>
 where
     T: Sortable,
-    F: Less<T>,
+    C: Classify<T>,
+    P: Payload,
 {
-    // The lock the to bucket must be acquired before decreasing the read pointer
-    // to prevent other threads to write to the block before it is read
-    let v = buckets[read_bucket].lock().unwrap();
-    match bucket_pointers[read_bucket].dec_read() {
-        Ok((write, mut read)) => {
-            if read < write {
-                // No more blocks to read in this bucket
+    let bp = &bucket_pointers[read_bucket];
+    let block_size = s.block_size();
+    {
+        let _guard = bp.lock();
+        bp.start_read();
+        let read = match bp.dec_read(block_size) {
+            Ok((write, read)) if read >= write => read,
+            // No more blocks to read in this bucket
+            _ => {
+                bp.stop_read();
                 return None;
             }
-            read -= bounds[read_bucket];
-            s.fill_with(0, &v[read..read + BLOCK_SIZE]);
-
-            Some(c.classify_single_element(&s.get(0)[0]))
+        };
+        // SAFETY: the block was claimed by decreasing the read pointer, it is overwritten by a
+        // later swap_block_parallel() once stop_read() was called
+        unsafe {
+            v.read_block(read, s, 0);
+            rows.read_swap(pb, 0, read, block_size);
         }
-        Err(_) => None,
+        bp.stop_read();
     }
+
+    Some(c.classify_single_element(&s.get(0)[0]))
 }
 
-fn swap_block_parallel<T>(
-    buckets: &[Mutex<&mut [T]>],
-    bounds: &[usize],
+#[allow(clippy::too_many_arguments)]
+fn swap_block_parallel<T, P>(
+    v: &SharedSlice<T>,
+    rows: P,
     swap: &mut SwapBuffers<T>,
+    pb: &mut P::Buffers,
     bucket_pointers: &[BucketPointer],
+    bucket_boundaries: &[usize],
     dest: usize,
     current_swap: usize,
 ) -> bool
 where
     T: Sortable,
+    P: Payload,
 {
-    let mut v = buckets[dest].lock().unwrap();
-    let (mut write, read) = bucket_pointers[dest].inc_write();
+    // See swap_block(), other threads still hold blocks in their swap buffers, so the search is
+    // repeated until a bucket with space left is found
+    let num_buckets = bucket_pointers.len();
+    let block_size = swap.block_size();
+    let (dest, _guard, (write, read)) = (dest..num_buckets)
+        .chain(0..dest)
+        .cycle()
+        .find_map(|bucket| {
+            let guard = bucket_pointers[bucket].lock();
+            let limit = write_limit(bucket_boundaries, bucket, block_size);
+            let pointers = bucket_pointers[bucket].inc_write(limit, block_size)?;
+            Some((bucket, guard, pointers))
+        })
+        .unwrap();
     if write > read {
-        write -= bounds[dest];
-        // Destination block is empty
-        v[write - BLOCK_SIZE..write].clone_from_slice(swap.get(current_swap));
+        // Destination block is empty, but another thread may still be reading it
+        bucket_pointers[dest].wait_for_readers();
+        // SAFETY: empty blocks only hold elements which were moved out before
+        unsafe {
+            v.write_block(write - block_size, swap, current_swap);
+            rows.write_swap(pb, current_swap, write - block_size);
+        }
+        P::clear_swap(pb, current_swap);
         return false;
     }
-    write -= bounds[dest];
 
     // Swap blocks
-    swap.fill_with(1 - current_swap, &v[write - BLOCK_SIZE..write]);
-    v[write - BLOCK_SIZE..write].clone_from_slice(swap.get(current_swap));
+    // SAFETY: the block was claimed by increasing the write pointer, it lies before the read
+    // pointer so no other thread reads it, and it is moved into the other swap buffer first
+    unsafe {
+        v.read_block(write - block_size, swap, 1 - current_swap);
+        v.write_block(write - block_size, swap, current_swap);
+        rows.read_swap(pb, 1 - current_swap, write - block_size, block_size);
+        rows.write_swap(pb, current_swap, write - block_size);
+    }
+    P::clear_swap(pb, current_swap);
     true
 }
@@ -0,0 +1,202 @@
+use std::{cmp::min, mem::size_of};
+
+/// A key that can be sorted by its bits, see [radix_sort](crate::radix_sort).
+///
+/// The bits of a key, read from the most significant one, must order keys the same way as
+/// [RadixKey::radix_lt] does.
+///
+/// Implemented for
+/// - unsigned and signed integers, signed ones have their sign bit flipped
+/// - `f32` and `f64`, ordered like [f64::total_cmp]: negative NaNs come first and positive NaNs
+///   last, `-0.0` is less than `0.0`
+/// - tuples of up to three keys, ordered lexicographically
+/// - byte arrays `[u8; N]`, ordered lexicographically
+pub trait RadixKey: Copy {
+    /// Number of bits of the key
+    const BITS: u32;
+
+    /// Returns the 64 bits of the key starting `offset` bits after the most significant one.
+    /// Bits after the end of the key are zero.
+    fn bits(&self, offset: u32) -> u64;
+
+    /// Compares two keys by their bits.
+    fn radix_lt(&self, other: &Self) -> bool {
+        let mut offset = 0;
+        while offset < Self::BITS {
+            let (a, b) = (self.bits(offset), other.bits(offset));
+            if a != b {
+                return a < b;
+            }
+            offset += u64::BITS;
+        }
+        false
+    }
+}
+
+macro_rules! impl_radix_key_unsigned {
+    ($($t:ty),*) => {$(
+        impl RadixKey for $t {
+            const BITS: u32 = <$t>::BITS;
+
+            #[inline]
+            fn bits(&self, offset: u32) -> u64 {
+                ((*self as u64) << (u64::BITS - Self::BITS))
+                    .checked_shl(offset)
+                    .unwrap_or(0)
+            }
+
+            #[inline]
+            fn radix_lt(&self, other: &Self) -> bool {
+                self < other
+            }
+        }
+    )*};
+}
+
+impl_radix_key_unsigned!(u8, u16, u32, u64, usize);
+
+impl RadixKey for u128 {
+    const BITS: u32 = u128::BITS;
+
+    #[inline]
+    fn bits(&self, offset: u32) -> u64 {
+        (self.checked_shl(offset).unwrap_or(0) >> u64::BITS) as u64
+    }
+
+    #[inline]
+    fn radix_lt(&self, other: &Self) -> bool {
+        self < other
+    }
+}
+
+/// Flipping the sign bit orders negative numbers before positive ones
+macro_rules! impl_radix_key_signed {
+    ($($t:ty => $u:ty),*) => {$(
+        impl RadixKey for $t {
+            const BITS: u32 = <$t>::BITS;
+
+            #[inline]
+            fn bits(&self, offset: u32) -> u64 {
+                (*self as $u ^ (1 << (<$u>::BITS - 1))).bits(offset)
+            }
+
+            #[inline]
+            fn radix_lt(&self, other: &Self) -> bool {
+                self < other
+            }
+        }
+    )*};
+}
+
+impl_radix_key_signed!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128, isize => usize);
+
+/// Flipping all bits of negative numbers and the sign bit of positive ones orders them like
+/// `total_cmp`
+macro_rules! impl_radix_key_float {
+    ($($t:ty => $u:ty),*) => {$(
+        impl RadixKey for $t {
+            const BITS: u32 = <$u>::BITS;
+
+            #[inline]
+            fn bits(&self, offset: u32) -> u64 {
+                let bits = self.to_bits();
+                let sign = 1 << (<$u>::BITS - 1);
+                let bits = if bits & sign == 0 { bits | sign } else { !bits };
+                bits.bits(offset)
+            }
+
+            #[inline]
+            fn radix_lt(&self, other: &Self) -> bool {
+                self.total_cmp(other).is_lt()
+            }
+        }
+    )*};
+}
+
+impl_radix_key_float!(f32 => u32, f64 => u64);
+
+/// Returns the 64 bits starting at `offset` of the concatenation of two keys, given by their
+/// `bits` functions.
+#[inline]
+fn concat_bits(
+    first: impl Fn(u32) -> u64,
+    first_len: u32,
+    second: impl Fn(u32) -> u64,
+    offset: u32,
+) -> u64 {
+    if offset >= first_len {
+        return second(offset - first_len);
+    }
+    let bits = first(offset);
+    let remaining = first_len - offset;
+    if remaining >= u64::BITS {
+        bits
+    } else {
+        bits | (second(0) >> remaining)
+    }
+}
+
+impl<A, B> RadixKey for (A, B)
+where
+    A: RadixKey,
+    B: RadixKey,
+{
+    const BITS: u32 = A::BITS + B::BITS;
+
+    #[inline]
+    fn bits(&self, offset: u32) -> u64 {
+        concat_bits(|o| self.0.bits(o), A::BITS, |o| self.1.bits(o), offset)
+    }
+
+    #[inline]
+    fn radix_lt(&self, other: &Self) -> bool {
+        self.0.radix_lt(&other.0) || (!other.0.radix_lt(&self.0) && self.1.radix_lt(&other.1))
+    }
+}
+
+impl<A, B, C> RadixKey for (A, B, C)
+where
+    A: RadixKey,
+    B: RadixKey,
+    C: RadixKey,
+{
+    const BITS: u32 = A::BITS + B::BITS + C::BITS;
+
+    #[inline]
+    fn bits(&self, offset: u32) -> u64 {
+        let rest = |o| concat_bits(|o| self.1.bits(o), B::BITS, |o| self.2.bits(o), o);
+        concat_bits(|o| self.0.bits(o), A::BITS, rest, offset)
+    }
+
+    #[inline]
+    fn radix_lt(&self, other: &Self) -> bool {
+        (self.0, self.1).radix_lt(&(other.0, other.1))
+            || (!(other.0, other.1).radix_lt(&(self.0, self.1)) && self.2.radix_lt(&other.2))
+    }
+}
+
+impl<const N: usize> RadixKey for [u8; N] {
+    const BITS: u32 = u8::BITS * N as u32;
+
+    #[inline]
+    fn bits(&self, offset: u32) -> u64 {
+        let start = (offset / u8::BITS) as usize;
+        let shift = offset % u8::BITS;
+        if start >= N {
+            return 0;
+        }
+        let end = min(start + size_of::<u64>(), N);
+        let mut bytes = [0; size_of::<u64>()];
+        bytes[..end - start].copy_from_slice(&self[start..end]);
+        let mut bits = u64::from_be_bytes(bytes) << shift;
+        if shift > 0 && end < N {
+            bits |= (self[end] >> (u8::BITS - shift)) as u64;
+        }
+        bits
+    }
+
+    #[inline]
+    fn radix_lt(&self, other: &Self) -> bool {
+        self < other
+    }
+}
@@ -0,0 +1,173 @@
+//! In-place radix sort (IPS2Ra).
+//!
+//! Uses the same block partitioning as IPS4o, but the bucket of an element is given by the next
+//! bits of its key instead of a search in the splitter tree. Bits in which all keys of a
+//! bucket agree are skipped before it is partitioned, so each level makes progress and the
+//! recursion ends once all bits are used up.
+
+mod key;
+pub(crate) mod parallel;
+
+use std::{array, cmp::min, marker::PhantomData};
+
+pub use key::RadixKey;
+
+use crate::{
+    base_case::heapsort::heapsort,
+    classifier::{classify_stripe, Classify},
+    config::Config,
+    constants::MAX_BUCKETS,
+    payload::Payload,
+    sequential::partition_blocks,
+    storage::{BucketBuffers, LocalStorage},
+    Less, Sortable,
+};
+
+/// Classifies elements by the `log_buckets` bits of their key starting at `offset`.
+#[derive(Debug)]
+pub(crate) struct RadixClassifier<'a, T, G> {
+    key: &'a G,
+    offset: u32,
+    log_buckets: u32,
+    element: PhantomData<fn(&T)>,
+}
+
+impl<'a, T, G, K> RadixClassifier<'a, T, G>
+where
+    G: Fn(&T) -> K,
+    K: RadixKey,
+{
+    pub(crate) fn new(key: &'a G) -> Self {
+        Self {
+            key,
+            offset: 0,
+            log_buckets: 1,
+            element: PhantomData,
+        }
+    }
+
+    /// Classifies by the bits of the keys starting at `offset` when partitioning `n` elements.
+    /// Returns the number of buckets.
+    fn set_digit(&mut self, n: usize, offset: u32, config: &Config) -> usize {
+        debug_assert!(offset < K::BITS);
+        // Radix classification is cheaper than a search in the splitter tree, so twice as many
+        // buckets are used as without equal buckets
+        let log_buckets = min(config.log_buckets(n), config.log_buckets) as u32 + 1;
+        self.offset = offset;
+        self.log_buckets = min(log_buckets, K::BITS - offset);
+        1 << self.log_buckets
+    }
+
+    /// Offset of the first bit which is not used by the current classification
+    fn next_offset(&self) -> u32 {
+        self.offset + self.log_buckets
+    }
+
+    #[inline]
+    fn digit(&self, val: &T) -> usize {
+        ((self.key)(val).bits(self.offset) >> (u64::BITS - self.log_buckets)) as usize
+    }
+}
+
+impl<'a, T, G, K> Classify<T> for RadixClassifier<'a, T, G>
+where
+    G: Fn(&T) -> K,
+    K: RadixKey,
+{
+    fn classify_single_element(&self, val: &T) -> usize {
+        self.digit(val)
+    }
+
+    fn classify_locally<P: Payload>(
+        &self,
+        stripe: &mut [T],
+        rows: P,
+        buckets: &mut BucketBuffers<T>,
+        payload_buffers: &mut P::Buffers,
+        elements_per_bucket: &mut [usize; MAX_BUCKETS],
+        num_buckets: usize,
+    ) -> usize {
+        classify_stripe(
+            stripe,
+            rows,
+            buckets,
+            payload_buffers,
+            &mut elements_per_bucket[..num_buckets],
+            self,
+            |batch| array::from_fn(|i| self.digit(&batch[i])),
+            |val| self.digit(val),
+        )
+    }
+}
+
+/// Returns the offset of the first bit at or after `offset` in which the keys of `v` differ, or
+/// `None` if all keys are equal.
+fn first_differing_bit<T, G, K>(v: &[T], key: &G, offset: u32) -> Option<u32>
+where
+    G: Fn(&T) -> K,
+    K: RadixKey,
+{
+    let first = key(&v[0]);
+    let mut window = offset;
+    while window < K::BITS {
+        let first_bits = first.bits(window);
+        let diff = v
+            .iter()
+            .fold(0, |diff, x| diff | (key(x).bits(window) ^ first_bits));
+        if diff != 0 {
+            return Some(window + diff.leading_zeros());
+        }
+        window += u64::BITS;
+    }
+    None
+}
+
+pub(crate) fn radix_sequential<T, G, K, F>(v: &mut [T], key: &G, is_less: &F, config: &Config)
+where
+    T: Sortable,
+    G: Fn(&T) -> K,
+    K: RadixKey,
+    F: Less<T>,
+{
+    debug_assert!(v.len() > 2 * config.base_case_size);
+    let mut ls = LocalStorage::with_classifier(RadixClassifier::new(key), config);
+    radix_seq_recurse(v, &mut ls, is_less, 0);
+}
+
+/// Entry point for sequential recursion, the keys of `v` agree in all bits before `offset`.
+///
+/// `is_less` must compare the keys like [RadixKey::radix_lt], it sorts the small buckets.
+pub(crate) fn radix_seq_recurse<T, G, K, F>(
+    v: &mut [T],
+    ls: &mut LocalStorage<T, G, RadixClassifier<T, G>>,
+    is_less: &F,
+    offset: u32,
+) where
+    T: Sortable,
+    G: Fn(&T) -> K,
+    K: RadixKey,
+    F: Less<T>,
+{
+    debug_assert!(v.len() > 2 * ls.config.base_case_size);
+    let offset = match first_differing_bit(v, ls.classifier.key, offset) {
+        Some(offset) => offset,
+        None => return,
+    };
+    ls.num_buckets = ls.classifier.set_digit(v.len(), offset, &ls.config);
+    if !partition_blocks(v, (), ls, is_less, false) {
+        // Not reached, the classification only depends on the keys, so the blocks always fit
+        heapsort(v, is_less);
+        return;
+    }
+
+    let bucket_boundaries = ls.bucket_boundaries;
+    let next_offset = ls.classifier.next_offset();
+    let base_case_size = ls.config.base_case_size;
+    for i in 0..ls.num_buckets {
+        let range = bucket_boundaries[i]..bucket_boundaries[i + 1];
+        // Smaller buckets were sorted in cleanup_margins()
+        if range.len() > 2 * base_case_size && next_offset < K::BITS {
+            radix_seq_recurse(&mut v[range], ls, is_less, next_offset);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use std::marker::PhantomData;
+
+use rayon::prelude::*;
+
+use crate::{
+    base_case::heapsort::heapsort,
+    config::Config,
+    parallel::{
+        partition_blocks,
+        scheduler::{buckets_left, par_recurse, ParallelSort, StoragePool},
+        split_at_bounds,
+    },
+    radix::{radix_seq_recurse, RadixClassifier, RadixKey},
+    storage::LocalStorage,
+    PLess, PSortable,
+};
+
+pub(crate) fn radix_parallel<T, G, K, F>(
+    v: &mut [T],
+    key: &G,
+    is_less: &F,
+    config: &Config,
+    num_threads: usize,
+) where
+    T: PSortable,
+    G: Fn(&T) -> K + Sync,
+    K: RadixKey,
+    F: PLess<T>,
+{
+    // initialize storage
+    let new_storage = || LocalStorage::with_classifier(RadixClassifier::new(key), config);
+    let storages = StoragePool::new(Vec::new(), &new_storage);
+
+    let sorter = Radix {
+        is_less,
+        key: PhantomData,
+        digit: PhantomData,
+    };
+    par_recurse(&sorter, (v, 0), &storages, num_threads);
+}
+
+/// Parallel version of [super::first_differing_bit] with `num_threads` stripes
+fn first_differing_bit<T, G, K>(v: &[T], key: &G, offset: u32, num_threads: usize) -> Option<u32>
+where
+    T: PSortable,
+    G: Fn(&T) -> K + Sync,
+    K: RadixKey,
+{
+    let first = key(&v[0]);
+    let stripe_len = (v.len() + num_threads - 1) / num_threads;
+    let mut window = offset;
+    while window < K::BITS {
+        let first_bits = first.bits(window);
+        let diff = v
+            .par_chunks(stripe_len)
+            .map(|stripe| {
+                stripe
+                    .iter()
+                    .fold(0, |diff, x| diff | (key(x).bits(window) ^ first_bits))
+            })
+            .reduce(|| 0, |a, b| a | b);
+        if diff != 0 {
+            return Some(window + diff.leading_zeros());
+        }
+        window += u64::BITS;
+    }
+    None
+}
+
+/// The radix sort, its tasks are buckets with the offset of their next digit
+struct Radix<'a, G, K, F> {
+    is_less: &'a F,
+    key: PhantomData<&'a G>,
+    digit: PhantomData<fn() -> K>,
+}
+
+impl<'a, 'v, T, G, K, F> ParallelSort<(&'v mut [T], u32)> for Radix<'a, G, K, F>
+where
+    T: PSortable,
+    G: Fn(&T) -> K + Sync + 'a,
+    K: RadixKey,
+    F: PLess<T>,
+{
+    type Storage = LocalStorage<'a, T, G, RadixClassifier<'a, T, G>>;
+
+    fn len(&self, task: &(&'v mut [T], u32)) -> usize {
+        task.0.len()
+    }
+
+    /// See [radix_seq_recurse]
+    fn partition(
+        &self,
+        (v, offset): (&'v mut [T], u32),
+        lss: &mut [Self::Storage],
+        gs: &mut Self::Storage,
+    ) -> Vec<(usize, (&'v mut [T], u32))> {
+        debug_assert!(v.len() > 2 * gs.config.base_case_size);
+        let offset = match first_differing_bit(v, gs.classifier.key, offset, lss.len()) {
+            Some(offset) => offset,
+            None => return Vec::new(),
+        };
+        gs.num_buckets = gs.classifier.set_digit(v.len(), offset, &gs.config);
+        if !partition_blocks(v, (), lss, gs, self.is_less, false) {
+            heapsort(v, self.is_less);
+            return Vec::new();
+        }
+
+        let next_offset = gs.classifier.next_offset();
+        if next_offset >= K::BITS {
+            return Vec::new();
+        }
+        let bounds = gs.bucket_boundaries;
+        let mut left = buckets_left(gs, false).into_iter().peekable();
+        split_at_bounds(v, &bounds[..gs.num_buckets])
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| left.next_if_eq(i).is_some())
+            .map(|(i, bucket)| (bounds[i], (bucket, next_offset)))
+            .collect()
+    }
+
+    fn sort_sequential(&self, (v, offset): (&'v mut [T], u32), ls: &mut Self::Storage) {
+        radix_seq_recurse(v, ls, self.is_less, offset);
+    }
+}
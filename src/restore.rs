@@ -0,0 +1,129 @@
+//! Helpers to restore the slice if the comparison function panics or is inconsistent.
+//!
+//! During partitioning elements are moved out of the slice into the bucket and swap buffers.
+//! If a comparison panics, every buffered element is moved back into one of the holes left in
+//! the slice, so that it holds a permutation of its original elements afterwards, the same
+//! guarantee the sorting functions of the standard library give. The same is done if the blocks
+//! don't fit into their buckets after block permutation, see [blocks_fit_buckets].
+
+use std::{cmp::min, ops::Range};
+
+use crate::{
+    bucket_pointers::BucketPointer,
+    constants::MAX_BUCKETS,
+    storage::{BucketBuffers, SwapBuffers},
+    util::move_from_slice,
+};
+
+/// Moves buffered elements into the holes of a slice, in the order they are given.
+pub(crate) struct HoleFiller<'a, T> {
+    v: &'a mut [T],
+    holes: Vec<Range<usize>>,
+    current: usize,
+}
+
+impl<'a, T> HoleFiller<'a, T> {
+    pub(crate) fn new(v: &'a mut [T], holes: Vec<Range<usize>>) -> Self {
+        Self {
+            v,
+            holes,
+            current: 0,
+        }
+    }
+
+    /// Creates a filler for the single hole `v[start..]`.
+    pub(crate) fn suffix(v: &'a mut [T], start: usize) -> Self {
+        let len = v.len();
+        Self::new(v, vec![start..len; 1])
+    }
+
+    /// Moves `src` into the next holes.
+    ///
+    /// # Safety
+    /// The holes must only contain elements which were moved out before, and `src` must be
+    /// forgotten by the caller afterwards.
+    unsafe fn fill(&mut self, mut src: &[T]) {
+        while !src.is_empty() {
+            let hole = &mut self.holes[self.current];
+            let count = min(hole.len(), src.len());
+            move_from_slice(&mut self.v[hole.start..hole.start + count], &src[..count]);
+            hole.start += count;
+            src = &src[count..];
+            if hole.start == hole.end {
+                self.current += 1;
+            }
+        }
+    }
+
+    /// # Safety
+    /// See [Self::fill]
+    pub(crate) unsafe fn fill_from_bucket_buffers(&mut self, buffers: &mut BucketBuffers<T>) {
+        for i in 0..MAX_BUCKETS {
+            self.fill(buffers.get(i));
+            buffers.clear(i);
+        }
+    }
+
+    /// # Safety
+    /// See [Self::fill]
+    pub(crate) unsafe fn fill_from_swap_buffers(&mut self, swap: &mut SwapBuffers<T>) {
+        for i in 0..2 {
+            self.fill(swap.get(i));
+        }
+        swap.clear();
+    }
+
+    /// Returns true if all holes were filled
+    pub(crate) fn is_filled(&self) -> bool {
+        self.holes[self.current.min(self.holes.len())..]
+            .iter()
+            .all(Range::is_empty)
+    }
+}
+
+/// Returns the holes in `v` during block permutation.
+///
+/// Blocks in `[start, write)` are already permuted and blocks in `[write, read)` still have to
+/// be read, so the elements in the rest of each bucket were moved into a buffer.
+/// The end of the last bucket is not block aligned and is not touched by the block permutation.
+pub(crate) fn holes_during_permutation(
+    bucket_boundaries: &[usize],
+    bucket_pointers: &[BucketPointer],
+    block_size: usize,
+) -> Vec<Range<usize>> {
+    let num_buckets = bucket_pointers.len();
+    (0..num_buckets)
+        .map(|i| {
+            let (write, read) = bucket_pointers[i].fetch();
+            let end = bucket_boundaries[i + 1];
+            let end = if i == num_buckets - 1 {
+                end
+            } else {
+                end - end % block_size
+            };
+            write.max(read).min(end)..end
+        })
+        .collect()
+}
+
+/// Returns true if every bucket has exactly enough space left for its buffered elements after
+/// block permutation, which is required to clean up the margins.
+///
+/// Blocks are classified again during block permutation, so if the comparison function is not a
+/// strict weak order, they can end up in other buckets than local classification counted them
+/// for.
+pub(crate) fn blocks_fit_buckets<B>(
+    bucket_boundaries: &[usize],
+    bucket_pointers: &[BucketPointer],
+    block_size: usize,
+    buffered_elements: B,
+) -> bool
+where
+    B: Fn(usize) -> usize,
+{
+    bucket_pointers.iter().enumerate().all(|(i, bp)| {
+        let (start, end) = (bucket_boundaries[i], bucket_boundaries[i + 1]);
+        let (write, _read) = bp.fetch();
+        write + start % block_size + buffered_elements(i) == end
+    })
+}
@@ -0,0 +1,78 @@
+//! Selection and partial sorting.
+//!
+//! The input is partitioned like in the sequential sort, but only the buckets that hold one of
+//! the requested ranks are partitioned further. Buckets completely inside of the ranks are
+//! sorted, the others are left alone.
+
+use std::{
+    cmp::{max, min},
+    ops::Range,
+};
+
+use crate::{
+    base_case::{base_case_sort, heapsort::heapsort},
+    config::Config,
+    is_less_to_compare,
+    sequential::{buckets_to_sort, partition, recursion_depth_limit, seq_recurse},
+    storage::LocalStorage,
+    Less, Sortable,
+};
+
+pub(crate) fn select_sequential<T, F>(
+    v: &mut [T],
+    ranks: Range<usize>,
+    is_less: &F,
+    config: &Config,
+) where
+    T: Sortable,
+    F: Less<T>,
+{
+    let mut ls = LocalStorage::<T, F>::new(is_less, config);
+    select_recurse(v, ranks, &mut ls, is_less, recursion_depth_limit(v.len()));
+}
+
+/// Moves the elements with ranks in `ranks` to their positions in the sorted order. All
+/// elements before them are not greater, and all elements after them are not smaller.
+fn select_recurse<T, F>(
+    v: &mut [T],
+    ranks: Range<usize>,
+    ls: &mut LocalStorage<T, F>,
+    is_less: &F,
+    depth_limit: usize,
+) where
+    T: Sortable,
+    F: Less<T>,
+{
+    debug_assert!(!ranks.is_empty() && ranks.end <= v.len());
+    let base_case_size = ls.config.base_case_size;
+    if v.len() <= 2 * base_case_size {
+        base_case_sort(v, is_less);
+        return;
+    }
+    if depth_limit == 0 || !partition(v, ls, is_less) {
+        heapsort(v, is_less);
+        return;
+    }
+    // Final base cases were executed in cleanup step, see seq_recurse()
+    if v.len() <= ls.config.single_level_threshold() {
+        debug_assert!(v.is_sorted_by(is_less_to_compare!(is_less)));
+        return;
+    }
+
+    let bucket_boundaries = ls.bucket_boundaries;
+    for i in buckets_to_sort(ls.num_buckets, ls.classifier.equal_buckets) {
+        let bucket = bucket_boundaries[i]..bucket_boundaries[i + 1];
+        let start = max(bucket.start, ranks.start);
+        let end = min(bucket.end, ranks.end);
+        // Smaller buckets were sorted in cleanup_margins()
+        if start >= end || bucket.len() <= 2 * base_case_size {
+            continue;
+        }
+        if bucket == (start..end) {
+            seq_recurse(&mut v[bucket], ls, is_less, depth_limit - 1);
+        } else {
+            let ranks = start - bucket.start..end - bucket.start;
+            select_recurse(&mut v[bucket], ranks, ls, is_less, depth_limit - 1);
+        }
+    }
+}
@@ -1,57 +1,76 @@
-use std::{cmp::max, mem::MaybeUninit};
+use std::{
+    mem::MaybeUninit,
+    panic::{self, AssertUnwindSafe},
+};
 
 use rand::Rng;
 
 use crate::{
-    base_case::base_case_sort,
-    bucket_pointers::BucketPointer,
-    constants::{
-        log_buckets, ALLOW_EQUAL_BUCKETS, BASE_CASE_SIZE, BLOCK_SIZE, EQUAL_BUCKET_THRESHOLD,
-        MAX_BUCKETS, OVERSAMPLING_FACTOR_PERCENT, SINGLE_LEVEL_THRESHOLD,
-    },
+    base_case::{base_case_sort, heapsort::heapsort},
+    bucket_pointers::{AtomicPointers, BucketPointer},
+    classifier::{Classifier, Classify},
+    config::Config,
+    constants::{ALLOW_EQUAL_BUCKETS, MAX_BUCKETS, MAX_PRESORTED_BUCKET_RUNS},
     is_less_to_compare,
+    merge::{find_sorted_runs, merge_buffer_len, merge_runs_with},
+    payload::{base_case_sort_rows, Payload},
     permute_blocks::permute_blocks,
-    storage::{BucketBoundaries, BucketBuffers, Ips4oRng, LocalStorage},
-    util::{test_block_permutation, test_cleanup_margins},
+    restore::{blocks_fit_buckets, holes_during_permutation, HoleFiller},
+    storage::{BucketBoundaries, BucketBuffers, BufferPool, Ips4oRng, LocalStorage},
+    util::{move_from_slice, test_block_permutation, test_cleanup_margins},
     Less, Sortable,
 };
 
-fn oversampling_factor(n: usize) -> usize {
-    max(
-        1,
-        (OVERSAMPLING_FACTOR_PERCENT / 100_f64 * n.ilog2() as f64) as usize,
-    )
+/// Maximum number of partitioning levels before falling back to heapsort.
+///
+/// Every level makes progress, see [get_splitters], and buckets usually shrink by a large factor.
+/// So this is only reached after many bad samples or if the comparison function is not a strict
+/// weak order, and guarantees termination in that case.
+pub(crate) fn recursion_depth_limit(n: usize) -> usize {
+    n.ilog2() as usize
 }
 
-pub(crate) fn sequential_ips4o<T, F>(v: &mut [T], is_less: &F)
-where
+pub(crate) fn sequential_ips4o<T, F>(
+    v: &mut [T],
+    is_less: &F,
+    config: &Config,
+    pool: &mut BufferPool<T>,
+) where
     T: Sortable,
     F: Less<T>,
 {
-    let mut ls = LocalStorage::<T, F>::new(is_less);
+    let mut ls = LocalStorage::<T, F>::new_in(is_less, config, pool);
     sequential(v, &mut ls, is_less);
+    pool.push(ls.into_buffers());
 }
 
-fn sequential<T, F>(v: &mut [T], ls: &mut LocalStorage<T, F>, is_less: &F)
+pub(crate) fn sequential<T, F>(v: &mut [T], ls: &mut LocalStorage<T, F>, is_less: &F)
 where
     T: Sortable,
     F: Less<T>,
 {
-    if v.len() <= 2 * BASE_CASE_SIZE {
+    if v.len() <= 2 * ls.config.base_case_size {
         base_case_sort(v, is_less);
         return;
     }
-    seq_recurse(v, ls, is_less);
+    seq_recurse(v, ls, is_less, recursion_depth_limit(v.len()));
 }
 
 /// Entry point for sequential recursion.
-pub(crate) fn seq_recurse<T, F>(v: &mut [T], ls: &mut LocalStorage<T, F>, is_less: &F)
-where
+pub(crate) fn seq_recurse<T, F>(
+    v: &mut [T],
+    ls: &mut LocalStorage<T, F>,
+    is_less: &F,
+    depth_limit: usize,
+) where
     T: Sortable,
     F: Less<T>,
 {
-    debug_assert!(v.len() > 2 * BASE_CASE_SIZE);
-    partition(v, ls, is_less);
+    debug_assert!(v.len() > 2 * ls.config.base_case_size);
+    if depth_limit == 0 || !partition(v, ls, is_less) {
+        heapsort(v, is_less);
+        return;
+    }
 
     let mut bucket_boundaries: [MaybeUninit<usize>; MAX_BUCKETS + 1] =
         [MaybeUninit::uninit(); MAX_BUCKETS + 1];
@@ -61,17 +80,20 @@ where
     );
 
     // Final base cases were executed in cleanup step, so we're done here
-    if v.len() <= SINGLE_LEVEL_THRESHOLD {
+    if v.len() <= ls.config.single_level_threshold() {
         debug_assert!(v.is_sorted_by(is_less_to_compare!(is_less)));
         return;
     }
     let equal_buckets = ls.classifier.equal_buckets;
     let num_buckets = ls.num_buckets;
+    let base_case_size = ls.config.base_case_size;
 
     let mut recurse = |bucket: usize| {
         let range = bucket_boundaries[bucket]..bucket_boundaries[bucket + 1];
-        if range.len() > 2 * BASE_CASE_SIZE {
-            seq_recurse(&mut v[range], ls, is_less);
+        if range.len() > 2 * base_case_size {
+            if !merge_presorted_bucket(&mut v[range.clone()], ls, is_less) {
+                seq_recurse(&mut v[range], ls, is_less, depth_limit - 1);
+            }
         } else {
             // should already be sorted in cleanup_margins()
             debug_assert!(v[range].is_sorted_by(is_less_to_compare!(is_less)));
@@ -88,21 +110,91 @@ where
     }
 }
 
-fn partition<T, F>(v: &mut [T], ls: &mut LocalStorage<T, F>, is_less: &F)
+/// Returns the buckets that need to be sorted after partitioning.
+///
+/// Equal buckets only hold elements equal to their splitter, so they are already sorted, except
+/// for the last one, which also holds all elements greater than the last splitter.
+pub(crate) fn buckets_to_sort(
+    num_buckets: usize,
+    equal_buckets: bool,
+) -> impl Iterator<Item = usize> {
+    (0..num_buckets).filter(move |&i| !equal_buckets || i % 2 == 0 || i == num_buckets - 1)
+}
+
+/// Sorts `bucket` by merging its sorted runs, if it has at most [MAX_PRESORTED_BUCKET_RUNS] of
+/// them and the left runs fit into the bucket buffers of `ls`. Buckets shorter than the
+/// sorted check threshold are not checked.
+///
+/// Returns false if the bucket still has to be partitioned. Partitioning sorted inputs leaves
+/// buckets made of a few runs behind, because whole blocks are moved around.
+pub(crate) fn merge_presorted_bucket<T, F>(
+    bucket: &mut [T],
+    ls: &mut LocalStorage<T, F>,
+    is_less: &F,
+) -> bool
+where
+    T: Sortable,
+    F: Less<T>,
+{
+    if bucket.len() < ls.config.sorted_check_threshold {
+        return false;
+    }
+    let runs = match find_sorted_runs(bucket, is_less, MAX_PRESORTED_BUCKET_RUNS) {
+        Some(runs) => runs,
+        None => return false,
+    };
+    // Between partitioning steps, the bucket buffers hold no elements
+    let scratch = ls.bucket_buffers.scratch();
+    if merge_buffer_len(&runs) > scratch.len() {
+        return false;
+    }
+    merge_runs_with(bucket, &runs, scratch, is_less);
+    true
+}
+
+/// Returns false if the blocks don't fit into their buckets after block permutation, see
+/// [blocks_fit_buckets]. `v` is left unpartitioned in that case.
+pub(crate) fn partition<T, F>(v: &mut [T], ls: &mut LocalStorage<T, F>, is_less: &F) -> bool
 where
     T: Sortable,
     F: Less<T>,
 {
     let mut sorting_callback =
         |v: &mut [T], ls: &mut LocalStorage<T, F>| sequential(v, ls, is_less);
-    get_splitters(v, ls, &mut sorting_callback, is_less);
+    get_splitters(v, (), ls, &mut sorting_callback, is_less);
 
     ls.classifier.build();
     debug_assert!(ls.classifier.test_classification(v, is_less));
 
+    let is_last_level = v.len() <= ls.config.single_level_threshold();
+    partition_blocks(v, (), ls, is_less, is_last_level)
+}
+
+/// Partitions `v` into the buckets of `ls.classifier` by classifying it into blocks, permuting
+/// the blocks and cleaning up the margins of the buckets. Buckets of the last level and small
+/// buckets are sorted during cleanup. The rows of `rows` are moved along with the elements,
+/// see [Payload].
+///
+/// Returns false if the blocks don't fit into their buckets, `v` is left unpartitioned in that
+/// case, see [partition].
+pub(crate) fn partition_blocks<T, F, C, L, P>(
+    v: &mut [T],
+    rows: P,
+    ls: &mut LocalStorage<T, F, C, P::Buffers>,
+    is_less: &L,
+    is_last_level: bool,
+) -> bool
+where
+    T: Sortable,
+    C: Classify<T>,
+    L: Less<T>,
+    P: Payload,
+{
     let total_elements_written_back = ls.classifier.classify_locally(
         v,
+        rows,
         &mut ls.bucket_buffers,
+        &mut ls.payload_buffers,
         &mut ls.elements_written_per_bucket,
         ls.num_buckets,
     );
@@ -118,90 +210,152 @@ where
         &ls.bucket_boundaries[..ls.num_buckets + 1],
         &mut ls.bucket_pointers[..ls.num_buckets],
         total_elements_written_back,
+        ls.config.block_size,
+        ls.config.atomic_pointers.resolve(v.len()),
     );
-    permute_blocks(
-        v,
-        &ls.classifier,
-        &mut ls.swap_buffers,
-        &mut ls.bucket_pointers[..ls.num_buckets],
-        0,
-    );
-    debug_assert!(test_block_permutation(v, ls));
+    ls.swap_buffers.clear();
+    P::clear_swap(&mut ls.payload_buffers, 0);
+    P::clear_swap(&mut ls.payload_buffers, 1);
+    let permutation = panic::catch_unwind(AssertUnwindSafe(|| {
+        permute_blocks(
+            v,
+            rows,
+            &ls.classifier,
+            &mut ls.swap_buffers,
+            &mut ls.payload_buffers,
+            &mut ls.bucket_pointers[..ls.num_buckets],
+            &ls.bucket_boundaries[..ls.num_buckets + 1],
+            0,
+        );
+        debug_assert!(test_block_permutation(v, ls));
+    }));
+    if let Err(payload) = permutation {
+        let holes = holes_during_permutation(
+            &ls.bucket_boundaries[..ls.num_buckets + 1],
+            &ls.bucket_pointers[..ls.num_buckets],
+            ls.config.block_size,
+        );
+        // SAFETY: see holes_during_permutation()
+        unsafe { rows.fill_holes(&holes, vec![&mut ls.payload_buffers], true) };
+        let mut filler = HoleFiller::new(v, holes);
+        // SAFETY: see holes_during_permutation()
+        unsafe {
+            filler.fill_from_swap_buffers(&mut ls.swap_buffers);
+            filler.fill_from_bucket_buffers(&mut ls.bucket_buffers);
+        }
+        debug_assert!(filler.is_filled());
+        panic::resume_unwind(payload);
+    }
+    let buffers = &ls.bucket_buffers;
+    if !blocks_fit_buckets(
+        &ls.bucket_boundaries[..ls.num_buckets + 1],
+        &ls.bucket_pointers[..ls.num_buckets],
+        ls.config.block_size,
+        |i| buffers.len(i),
+    ) {
+        let holes = holes_during_permutation(
+            &ls.bucket_boundaries[..ls.num_buckets + 1],
+            &ls.bucket_pointers[..ls.num_buckets],
+            ls.config.block_size,
+        );
+        // SAFETY: see holes_during_permutation(), the swap buffers are empty
+        unsafe { rows.fill_holes(&holes, vec![&mut ls.payload_buffers], false) };
+        let mut filler = HoleFiller::new(v, holes);
+        // SAFETY: see holes_during_permutation(), the swap buffers are empty
+        unsafe { filler.fill_from_bucket_buffers(&mut ls.bucket_buffers) };
+        debug_assert!(filler.is_filled());
+        return false;
+    }
     cleanup_margins(
         v,
-        &ls.bucket_buffers,
+        rows,
+        &mut ls.bucket_buffers,
+        &mut ls.payload_buffers,
         &ls.bucket_boundaries[..ls.num_buckets + 1],
         &mut ls.bucket_pointers[..ls.num_buckets],
         is_less,
+        is_last_level,
+        ls.config.base_case_size,
     );
-    debug_assert!(test_cleanup_margins(v, ls))
+    debug_assert!(test_cleanup_margins(v, ls));
+    true
 }
 
-pub(crate) fn select_sample<T>(v: &mut [T], sample_size: usize, rng: &mut Ips4oRng)
+/// Moves a random sample to the start of `v`, the rows of `rows` are swapped along
+pub(crate) fn select_sample<T, P>(v: &mut [T], rows: P, sample_size: usize, rng: &mut Ips4oRng)
 where
     T: Sortable,
+    P: Payload,
 {
     debug_assert!(sample_size <= v.len());
     for i in 0..sample_size {
-        v.swap(i, rng.rng.gen_range(i..v.len()));
+        let j = rng.rng.gen_range(i..v.len());
+        v.swap(i, j);
+        // SAFETY: the rows have as many positions as v
+        unsafe { rows.swap(i, j) };
     }
 }
 
-pub(crate) fn get_splitters<T, F, S>(
+/// Chooses the splitters of `ls.classifier` from a sample of `v`, which is sorted by
+/// `sorting_callback` together with its rows in `rows`.
+pub(crate) fn get_splitters<'a, T, F, S, P>(
     v: &mut [T],
-    ls: &mut LocalStorage<T, F>,
+    rows: P,
+    ls: &mut LocalStorage<'a, T, F, Classifier<'a, T, F>, P::Buffers>,
     sorting_callback: &mut S,
     is_less: &F,
 ) where
     T: Sortable,
     F: Less<T>,
-    S: FnMut(&mut [T], &mut LocalStorage<T, F>),
+    S: FnMut(&mut [T], &mut LocalStorage<'a, T, F, Classifier<'a, T, F>, P::Buffers>),
+    P: Payload,
 {
     let n = v.len();
-    let num_buckets = 1usize << log_buckets(n);
-    let step = oversampling_factor(n);
+    let num_buckets = 1usize << ls.config.log_buckets(n);
+    let step = ls.config.oversampling_factor(n);
     let sample_size = (step * num_buckets - 1).min(n / 2);
 
     // Select the sample
-    select_sample(v, sample_size, &mut ls.rng);
+    select_sample(v, rows, sample_size, &mut ls.rng);
     // Sort the sample
     sorting_callback(&mut v[0..sample_size], ls);
     // Choose the splitters
     let mut current = step - 1;
-    let mut current_idx = 1;
-    let set = ls.classifier.get_all_splitters_mut();
-    set[0] = v[current].clone();
+    let classifier = &mut ls.classifier;
+    classifier.clear();
+    classifier.push_splitter(&v[current]);
     for _ in 2..num_buckets {
         current += step;
         // Skip duplicates
-        if is_less(&set[current_idx - 1], &v[current]) {
-            set[current_idx] = v[current].clone();
-            current_idx += 1;
+        if is_less(classifier.get_splitters().last().unwrap(), &v[current]) {
+            classifier.push_splitter(&v[current]);
         }
     }
-    // QUESTION: what happens if only one splitter is chosen, may not terminate?
-    // => this should not happen if constants are set correctly
 
-    let splitter_count = current_idx;
+    let splitter_count = classifier.get_splitters().len();
     let max_splitters = num_buckets - 1;
     debug_assert!(num_buckets <= MAX_BUCKETS);
-    let use_equal_buckets =
-        ALLOW_EQUAL_BUCKETS && max_splitters - splitter_count >= EQUAL_BUCKET_THRESHOLD;
+    // If only one splitter is chosen and it is the largest element, all elements end up in the
+    // first bucket. With more splitters, some element is larger than the first one. So a single
+    // splitter always gets an equal bucket, which takes at least the splitter itself and makes
+    // sure every level makes progress.
+    let use_equal_buckets = ALLOW_EQUAL_BUCKETS
+        && (splitter_count == 1
+            || max_splitters - splitter_count >= ls.config.equal_bucket_threshold);
 
     // Fill vec to the next power of 2
     let log_buckets = splitter_count.ilog2() + 1;
     let num_buckets = 1usize << log_buckets;
 
-    for i in current_idx..num_buckets {
-        set[i] = set[current_idx - 1].clone();
-    }
+    classifier.fill_splitters(num_buckets);
 
-    ls.classifier.set_splitter_len(num_buckets);
     ls.classifier.equal_buckets = use_equal_buckets;
     ls.num_buckets = num_buckets << use_equal_buckets as usize;
 
     debug_assert!(splitter_count < num_buckets);
-    debug_assert!(ls.classifier.get_all_splitters_mut()[..num_buckets]
+    debug_assert!(ls
+        .classifier
+        .get_splitters()
         .is_sorted_by(is_less_to_compare!(is_less)));
 }
 
@@ -228,35 +382,43 @@ fn calculate_bucket_pointers(
     bucket_boundaries: &[usize],
     bucket_pointers: &mut [BucketPointer],
     first_empty_block: usize,
+    block_size: usize,
+    kind: AtomicPointers,
 ) {
     // Writing index, starts as bucket delimiters rounded down to previous block
     // Looks like [0, start_of_1st_bucket, start_of_2nd_bucket, ..., start_of_last_bucket]
-    // Each time something is written to the bucket, is incremented by 1 * BLOCK_SIZE (w += BLOCK_SIZE)
+    // Each time something is written to the bucket, is incremented by 1 * block_size (w += block_size)
     let w = bucket_boundaries[..bucket_boundaries.len() - 1]
         .iter()
-        .map(|&boundary| boundary - boundary % BLOCK_SIZE);
+        .map(|&boundary| boundary - boundary % block_size);
 
     // Reading index, starts as bucket delimiters of next block rounded down
-    // Each time something is written to the bucket, is decremented by 1 * BLOCK_SIZE (r -= BLOCK_SIZE)
+    // Each time something is written to the bucket, is decremented by 1 * block_size (r -= block_size)
     let r = bucket_boundaries[1..]
         .iter()
-        .map(|&boundary| boundary - boundary % BLOCK_SIZE)
+        .map(|&boundary| boundary - boundary % block_size)
         .map(|item| item.min(first_empty_block));
 
     for ((bp, write), read) in bucket_pointers.iter_mut().zip(w).zip(r) {
-        *bp = BucketPointer::new(write, read);
+        *bp = BucketPointer::new(write, read, block_size, kind);
     }
 }
 
-fn cleanup_margins<T, F>(
+#[allow(clippy::too_many_arguments)]
+fn cleanup_margins<T, F, P>(
     v: &mut [T],
-    bucket_buffers: &BucketBuffers<T>,
+    rows: P,
+    bucket_buffers: &mut BucketBuffers<T>,
+    payload_buffers: &mut P::Buffers,
     bucket_boundaries: &[usize],
     bucket_pointers: &mut [BucketPointer],
     is_less: &F,
+    is_last_level: bool,
+    base_case_size: usize,
 ) where
     T: Sortable,
     F: Less<T>,
+    P: Payload,
 {
     //        head                 tail
     //        <-->                 <--->
@@ -286,24 +448,30 @@ fn cleanup_margins<T, F>(
     // ------|------|------|------|------|------|------|------
     //    ...        ][  bucket i  ][    ...
 
-    let is_last_level = v.len() <= SINGLE_LEVEL_THRESHOLD;
+    let block_size = bucket_buffers.block_size();
     for i in (0..bucket_pointers.len()).rev() {
         let start = bucket_boundaries[i];
         let end = bucket_boundaries[i + 1];
         let (write, _read) = bucket_pointers[i].fetch();
-        let head_range = (start - start % BLOCK_SIZE)..start;
+        let head_range = (start - start % block_size)..start;
 
         let tail_beginning;
-        debug_assert!(head_range.len() < BLOCK_SIZE);
+        debug_assert!(head_range.len() < block_size);
         if write == end {
             // end is block aligned and block was written
             // write only increases when block is written back => if no block was written back it would be smaller than end
             tail_beginning = write;
         } else if start < write {
             // first block was written back into v => head is filled
-            // workaround, as slice::clone_within(&mut self, R, usize) doesn't exit
             let (head_slice, write_slice) = v.split_at_mut(write);
-            write_slice[..head_range.len()].clone_from_slice(&head_slice[head_range.clone()]);
+            // SAFETY: the head is overwritten with the tail of the previous bucket below
+            unsafe {
+                move_from_slice(
+                    &mut write_slice[..head_range.len()],
+                    &head_slice[head_range.clone()],
+                );
+                rows.copy(head_range.start, write, head_range.len());
+            }
 
             tail_beginning = write + head_range.len();
         } else {
@@ -318,10 +486,21 @@ fn cleanup_margins<T, F>(
         let tail_range = tail_beginning..end;
         debug_assert_eq!(bucket_buffers.len(i), tail_range.len());
         let tail = &mut v[tail_range];
-        tail.clone_from_slice(bucket_buffers.get(i));
+        // SAFETY: the tail only holds elements which were moved into the buffers or head
+        // elements which were moved above
+        unsafe {
+            move_from_slice(tail, bucket_buffers.get(i));
+            rows.write_bucket(payload_buffers, i, tail_beginning);
+        }
+        bucket_buffers.clear(i);
+        P::clear_bucket(payload_buffers, i);
+    }
 
-        if is_last_level || end - start <= 2 * BASE_CASE_SIZE {
-            base_case_sort(&mut v[start..end], is_less);
+    // All elements are back in place, so the comparison function may panic from here on
+    for i in 0..bucket_pointers.len() {
+        let (start, end) = (bucket_boundaries[i], bucket_boundaries[i + 1]);
+        if is_last_level || end - start <= 2 * base_case_size {
+            base_case_sort_rows(&mut v[start..end], rows.skip(start), is_less);
         }
     }
 }
@@ -0,0 +1,183 @@
+use std::cmp::Ordering;
+
+use crate::{ips4o, ips4o_par, is_less_to_compare, storage::BufferPool, Ips4o};
+
+/// Sorts many slices with the same buffers.
+///
+/// Every call of a free function like [crate::sort] allocates the buffers of the algorithm, one
+/// set per thread for parallel sorts. A `Sorter` keeps them after a sort returns, so sorting many
+/// medium-sized slices does not allocate over and over again.
+///
+/// ```
+/// let mut sorter = ips4o_rs::Sorter::new();
+/// for len in [10_000, 20_000] {
+///     let mut v: Vec<u32> = (0..len).rev().collect();
+///     sorter.sort(&mut v);
+///     assert!(v.windows(2).all(|w| w[0] <= w[1]));
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Sorter<T> {
+    params: Ips4o,
+    pool: BufferPool<T>,
+}
+
+impl<T> Default for Sorter<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Sorter<T> {
+    pub fn new() -> Self {
+        Self::with_params(Ips4o::new())
+    }
+
+    /// Sorts with the parameters of `params`, see [Ips4o::sorter].
+    pub fn with_params(params: Ips4o) -> Self {
+        Self {
+            params,
+            pool: Vec::new(),
+        }
+    }
+
+    /// Frees the buffers kept from previous sorts.
+    pub fn clear(&mut self) {
+        self.pool = Vec::new();
+    }
+
+    #[inline]
+    pub fn sort(&mut self, v: &mut [T])
+    where
+        T: Ord,
+    {
+        ips4o(v, T::lt, &self.params.config::<T>(), &mut self.pool);
+        debug_assert!(v.is_sorted());
+    }
+
+    #[inline]
+    pub fn sort_by<F>(&mut self, v: &mut [T], compare: F)
+    where
+        F: Fn(&T, &T) -> Ordering,
+    {
+        ips4o(
+            v,
+            |a, b| compare(a, b) == Ordering::Less,
+            &self.params.config::<T>(),
+            &mut self.pool,
+        );
+        debug_assert!(v.is_sorted_by(|a, b| Some(compare(a, b))));
+    }
+
+    #[inline]
+    pub fn sort_by_key<K, F>(&mut self, v: &mut [T], f: F)
+    where
+        F: Fn(&T) -> K,
+        K: Ord,
+    {
+        ips4o(
+            v,
+            |a, b| f(a).lt(&f(b)),
+            &self.params.config::<T>(),
+            &mut self.pool,
+        );
+        let is_less = |a, b| f(a).lt(&f(b));
+        debug_assert!(v.is_sorted_by(is_less_to_compare!(is_less)));
+    }
+
+    #[inline]
+    pub fn sort_par(&mut self, v: &mut [T])
+    where
+        T: Ord + Send + Sync,
+    {
+        ips4o_par(v, T::lt, &self.params.config::<T>(), &mut self.pool);
+        debug_assert!(v.is_sorted());
+    }
+
+    #[inline]
+    pub fn sort_par_by<F>(&mut self, v: &mut [T], compare: F)
+    where
+        T: Send + Sync,
+        F: Fn(&T, &T) -> Ordering + Sync,
+    {
+        ips4o_par(
+            v,
+            |a, b| compare(a, b) == Ordering::Less,
+            &self.params.config::<T>(),
+            &mut self.pool,
+        );
+        debug_assert!(v.is_sorted_by(|a, b| Some(compare(a, b))));
+    }
+
+    #[inline]
+    pub fn sort_par_by_key<K, F>(&mut self, v: &mut [T], f: F)
+    where
+        T: Send + Sync,
+        F: Fn(&T) -> K + Sync,
+        K: Ord,
+    {
+        ips4o_par(
+            v,
+            |a, b| f(a).lt(&f(b)),
+            &self.params.config::<T>(),
+            &mut self.pool,
+        );
+        let is_less = |a, b| f(a).lt(&f(b));
+        debug_assert!(v.is_sorted_by(is_less_to_compare!(is_less)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rayon::ThreadPoolBuilder;
+
+    use super::Sorter;
+    use crate::Ips4o;
+
+    #[test]
+    fn reuses_buffers() {
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let mut sorter = Sorter::new();
+        for len in [100_000, 10, 50_000, 100_000] {
+            let mut v: Vec<u64> = (0..len).map(|i| i * 7919 % 1000).collect();
+            let mut expected = v.clone();
+            expected.sort();
+            sorter.sort(&mut v);
+            assert_eq!(v, expected);
+            assert!(sorter.pool.len() <= 1);
+        }
+        assert_eq!(sorter.pool.len(), 1);
+
+        for len in [1_000_000, 200_000] {
+            let mut v: Vec<u64> = (0..len).rev().map(|i| i % 1000).collect();
+            let mut expected = v.clone();
+            expected.sort();
+            pool.install(|| sorter.sort_par(&mut v));
+            assert_eq!(v, expected);
+            // At least one buffer per thread and one for the global storage
+            assert!(sorter.pool.len() >= 5);
+        }
+
+        let mut v: Vec<u8> = (0..10_000).map(|i| (i * 31 % 256) as u8).collect();
+        let mut bytes = Sorter::with_params(Ips4o::new().block_size(4));
+        bytes.sort_by(&mut v, |a, b| b.cmp(a));
+        assert!(v.windows(2).all(|w| w[0] >= w[1]));
+
+        sorter.clear();
+        assert!(sorter.pool.is_empty());
+    }
+
+    #[test]
+    fn pool_size_stays_the_same() {
+        let pool = ThreadPoolBuilder::new().num_threads(8).build().unwrap();
+        // Few buckets per step, so thread groups need extra storages
+        let mut sorter = Ips4o::new().log_buckets(1).sorter();
+        let input: Vec<u64> = (0..2_000_000u64).map(|i| i * 7919 % 1_000_003).collect();
+        for _ in 0..8 {
+            let mut v = input.clone();
+            pool.install(|| sorter.sort_par(&mut v));
+            assert!(v.windows(2).all(|w| w[0] <= w[1]));
+            assert_eq!(sorter.pool.len(), 9);
+        }
+    }
+}
@@ -0,0 +1,216 @@
+//! Stable variant of IPS4o.
+//!
+//! Elements are classified with the same [Classifier] as in the unstable sort, but instead of
+//! permuting blocks in place, every element is moved into a buffer of the same length, in the
+//! order of the input. That way each bucket keeps the relative order of its elements, and the
+//! stable base case does the same for the last level.
+//!
+//! The sample is chosen from and sorted in a bitwise copy of the input, so the comparison
+//! function is only ever called while the input still owns all of its elements.
+//!
+//! [Classifier]: crate::classifier::Classifier
+
+pub(crate) mod parallel;
+
+use std::{mem::MaybeUninit, ptr, slice};
+
+use crate::{
+    base_case::stable_base_case_sort,
+    config::Config,
+    constants::MAX_BUCKETS,
+    merge::merge_runs_with,
+    sequential::{
+        buckets_to_sort, calculate_bucket_boundaries, get_splitters, recursion_depth_limit,
+        sequential,
+    },
+    storage::LocalStorage,
+    Less, Sortable,
+};
+
+// The oracle stores bucket indices as u8
+const _: () = assert!(MAX_BUCKETS <= 1 << u8::BITS);
+
+pub(crate) fn stable_sequential_ips4o<T, F>(v: &mut [T], is_less: &F, config: &Config)
+where
+    T: Sortable,
+    F: Less<T>,
+{
+    if v.len() <= 2 * config.base_case_size {
+        stable_base_case_sort(v, is_less);
+        return;
+    }
+    let mut ls = LocalStorage::<T, F>::new(is_less, config);
+    let mut buffer = Box::new_uninit_slice(v.len());
+    let mut oracle = vec![0; v.len()];
+    stable_seq_recurse(
+        v,
+        &mut buffer,
+        &mut oracle,
+        &mut ls,
+        is_less,
+        recursion_depth_limit(v.len()),
+    );
+}
+
+/// Entry point for sequential recursion.
+///
+/// `buffer` and `oracle` are scratch space of the same length as `v`.
+pub(crate) fn stable_seq_recurse<T, F>(
+    v: &mut [T],
+    buffer: &mut [MaybeUninit<T>],
+    oracle: &mut [u8],
+    ls: &mut LocalStorage<T, F>,
+    is_less: &F,
+    depth_limit: usize,
+) where
+    T: Sortable,
+    F: Less<T>,
+{
+    debug_assert!(v.len() > 2 * ls.config.base_case_size);
+    if depth_limit == 0 {
+        stable_merge_sort(v, buffer, is_less, ls.config.base_case_size);
+        return;
+    }
+    partition(v, buffer, oracle, ls, is_less);
+
+    let bucket_boundaries = ls.bucket_boundaries;
+    let is_last_level = v.len() <= ls.config.single_level_threshold();
+    let base_case_size = ls.config.base_case_size;
+    for i in buckets_to_sort(ls.num_buckets, ls.classifier.equal_buckets) {
+        let range = bucket_boundaries[i]..bucket_boundaries[i + 1];
+        if is_last_level || range.len() <= 2 * base_case_size {
+            stable_base_case_sort(&mut v[range], is_less);
+        } else {
+            stable_seq_recurse(
+                &mut v[range.clone()],
+                &mut buffer[range.clone()],
+                &mut oracle[range],
+                ls,
+                is_less,
+                depth_limit - 1,
+            );
+        }
+    }
+}
+
+/// Sorts `v` stably by sorting chunks with the base case and merging them through `buffer`.
+///
+/// The fallback for recursions that reach [recursion_depth_limit], which unlike heapsort keeps
+/// equal elements in order.
+pub(crate) fn stable_merge_sort<T, F>(
+    v: &mut [T],
+    buffer: &mut [MaybeUninit<T>],
+    is_less: &F,
+    base_case_size: usize,
+) where
+    T: Sortable,
+    F: Less<T>,
+{
+    let mut runs: Vec<usize> = (0..v.len()).step_by(2 * base_case_size).collect();
+    runs.push(v.len());
+    for run in runs.windows(2) {
+        stable_base_case_sort(&mut v[run[0]..run[1]], is_less);
+    }
+    merge_runs_with(v, &runs, buffer, is_less);
+}
+
+/// Copies the elements of `v` bitwise into `buffer` and returns the copies.
+///
+/// # Safety
+/// The copies are not owned, they must not be dropped and are invalidated by moving the
+/// elements of `v`.
+pub(crate) unsafe fn copy_to_buffer<'a, T>(
+    v: &[T],
+    buffer: &'a mut [MaybeUninit<T>],
+) -> &'a mut [T] {
+    debug_assert_eq!(v.len(), buffer.len());
+    let copy = buffer.as_mut_ptr() as *mut T;
+    ptr::copy_nonoverlapping(v.as_ptr(), copy, v.len());
+    slice::from_raw_parts_mut(copy, v.len())
+}
+
+/// Moves every element of `v` to `buffer[positions[bucket]]`, where `bucket` is the entry of
+/// the element in `oracle`, and increments that position.
+///
+/// # Safety
+/// The positions must stay inside of `buffer` and must not be written by anyone else. The
+/// elements of `v` are moved out, so they must be overwritten before `v` is used again.
+pub(crate) unsafe fn scatter<T>(
+    v: &[T],
+    oracle: &[u8],
+    buffer: *mut MaybeUninit<T>,
+    positions: &mut [usize],
+) {
+    for (elem, &bucket) in v.iter().zip(oracle) {
+        let position = &mut positions[bucket as usize];
+        ptr::copy_nonoverlapping(elem, buffer.add(*position) as *mut T, 1);
+        *position += 1;
+    }
+}
+
+/// Partitions `v` stably, the bucket boundaries are stored in `ls`.
+fn partition<T, F>(
+    v: &mut [T],
+    buffer: &mut [MaybeUninit<T>],
+    oracle: &mut [u8],
+    ls: &mut LocalStorage<T, F>,
+    is_less: &F,
+) where
+    T: Sortable,
+    F: Less<T>,
+{
+    classify(v, buffer, oracle, ls, is_less);
+    // SAFETY: the bucket boundaries were just computed from the oracle
+    unsafe { move_to_buckets(v, buffer, oracle, &ls.bucket_boundaries[..ls.num_buckets]) };
+}
+
+/// Stores the bucket of every element of `v` in `oracle` and the bucket boundaries in `ls`,
+/// without moving any element.
+pub(crate) fn classify<T, F>(
+    v: &[T],
+    buffer: &mut [MaybeUninit<T>],
+    oracle: &mut [u8],
+    ls: &mut LocalStorage<T, F>,
+    is_less: &F,
+) where
+    T: Sortable,
+    F: Less<T>,
+{
+    let mut sorting_callback =
+        |v: &mut [T], ls: &mut LocalStorage<T, F>| sequential(v, ls, is_less);
+    // SAFETY: the copies are only used to choose the splitters, which are bitwise copies
+    // themselves, and are overwritten in move_to_buckets()
+    let copy = unsafe { copy_to_buffer(v, buffer) };
+    get_splitters(copy, (), ls, &mut sorting_callback, is_less);
+
+    ls.classifier.build();
+    debug_assert!(ls.classifier.test_classification(v, is_less));
+
+    let num_buckets = ls.num_buckets;
+    ls.classifier.classify_oracle(
+        v,
+        oracle,
+        &mut ls.elements_written_per_bucket[..num_buckets],
+    );
+    let elements_per_bucket = ls.elements_written_per_bucket;
+    calculate_bucket_boundaries(&mut ls.bucket_boundaries, num_buckets, &elements_per_bucket);
+}
+
+/// Moves every element of `v` into the bucket given by `oracle`, keeping the order within each
+/// bucket.
+///
+/// # Safety
+/// `bucket_starts` must be the bucket boundaries computed from the number of elements per
+/// bucket in `oracle`.
+pub(crate) unsafe fn move_to_buckets<T>(
+    v: &mut [T],
+    buffer: &mut [MaybeUninit<T>],
+    oracle: &[u8],
+    bucket_starts: &[usize],
+) {
+    let mut positions = [0; MAX_BUCKETS];
+    positions[..bucket_starts.len()].copy_from_slice(bucket_starts);
+    // Every element is moved back into `v` below
+    scatter(v, oracle, buffer.as_mut_ptr(), &mut positions);
+    ptr::copy_nonoverlapping(buffer.as_ptr() as *const T, v.as_mut_ptr(), v.len());
+}
@@ -0,0 +1,254 @@
+use std::{mem::MaybeUninit, ptr};
+
+use rayon::prelude::*;
+
+use crate::{
+    base_case::stable_base_case_sort,
+    config::Config,
+    constants::MAX_BUCKETS,
+    parallel::{
+        scheduler::{par_recurse, ParallelSort, StoragePool},
+        split_at_bounds,
+    },
+    sequential::{
+        buckets_to_sort, calculate_bucket_boundaries, get_splitters, recursion_depth_limit,
+        sequential,
+    },
+    stable::{copy_to_buffer, scatter, stable_merge_sort, stable_seq_recurse},
+    storage::{GlobalStorage, LocalStorage},
+    PLess, PSortable,
+};
+
+/// Lets every thread write its elements into a disjoint part of the buffer
+struct BufferPtr<T>(*mut MaybeUninit<T>);
+
+impl<T> Clone for BufferPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for BufferPtr<T> {}
+
+// SAFETY: the threads only write to disjoint positions, see partition()
+unsafe impl<T: Send> Send for BufferPtr<T> {}
+unsafe impl<T: Send> Sync for BufferPtr<T> {}
+
+impl<T> BufferPtr<T> {
+    fn get(self) -> *mut MaybeUninit<T> {
+        self.0
+    }
+}
+
+pub(crate) fn stable_parallel_ips4o<T, F>(
+    v: &mut [T],
+    is_less: &F,
+    config: &Config,
+    num_threads: usize,
+) where
+    T: PSortable,
+    F: PLess<T>,
+{
+    // initialize storage
+    let new_storage = || LocalStorage::new(is_less, config);
+    let storages = StoragePool::new(Vec::new(), &new_storage);
+    let mut buffer = Box::new_uninit_slice(v.len());
+    let mut oracle = vec![0; v.len()];
+
+    let depth_limit = recursion_depth_limit(v.len());
+    let task = (v, &mut buffer[..], &mut oracle[..], depth_limit);
+    par_recurse(&Stable { is_less }, task, &storages, num_threads);
+}
+
+/// A bucket with the parts of the buffer and the oracle at its positions and its remaining
+/// recursion depth
+type StableTask<'v, T> = (&'v mut [T], &'v mut [MaybeUninit<T>], &'v mut [u8], usize);
+
+/// The stable sort, see [StableTask]
+struct Stable<'a, F> {
+    is_less: &'a F,
+}
+
+impl<'a, 'v, T, F> ParallelSort<StableTask<'v, T>> for Stable<'a, F>
+where
+    T: PSortable,
+    F: PLess<T>,
+{
+    type Storage = LocalStorage<'a, T, F>;
+
+    fn len(&self, task: &StableTask<'v, T>) -> usize {
+        task.0.len()
+    }
+
+    /// See [stable_seq_recurse]
+    fn partition(
+        &self,
+        (v, buffer, oracle, depth_limit): StableTask<'v, T>,
+        lss: &mut [Self::Storage],
+        gs: &mut Self::Storage,
+    ) -> Vec<(usize, StableTask<'v, T>)> {
+        let is_less = self.is_less;
+        debug_assert!(v.len() > 2 * gs.config.base_case_size);
+        if depth_limit == 0 {
+            stable_merge_sort(v, buffer, is_less, gs.config.base_case_size);
+            return Vec::new();
+        }
+        partition(v, buffer, oracle, gs, is_less, lss.len());
+
+        let is_last_level = v.len() <= gs.config.single_level_threshold();
+        let bounds = gs.bucket_boundaries;
+        let buckets = split_at_bounds(v, &bounds[..gs.num_buckets])
+            .into_iter()
+            .zip(split_at_bounds(buffer, &bounds[..gs.num_buckets]))
+            .zip(split_at_bounds(oracle, &bounds[..gs.num_buckets]));
+        let mut to_sort = buckets_to_sort(gs.num_buckets, gs.classifier.equal_buckets).peekable();
+        let mut tasks = Vec::new();
+        for (i, ((v, buffer), oracle)) in buckets.enumerate() {
+            if to_sort.next_if_eq(&i).is_none() {
+                continue;
+            }
+            if is_last_level {
+                stable_base_case_sort(v, is_less);
+            } else {
+                tasks.push((bounds[i], (v, buffer, oracle, depth_limit - 1)));
+            }
+        }
+        tasks
+    }
+
+    fn sort_sequential(
+        &self,
+        (v, buffer, oracle, depth_limit): StableTask<'v, T>,
+        ls: &mut Self::Storage,
+    ) {
+        if v.len() <= 2 * ls.config.base_case_size {
+            stable_base_case_sort(v, self.is_less);
+        } else {
+            stable_seq_recurse(v, buffer, oracle, ls, self.is_less, depth_limit);
+        }
+    }
+}
+
+/// Partitions `v` stably in parallel with `num_threads` stripes, the bucket boundaries are stored
+/// in `gs`.
+fn partition<T, F>(
+    v: &mut [T],
+    buffer: &mut [MaybeUninit<T>],
+    oracle: &mut [u8],
+    gs: &mut GlobalStorage<T, F>,
+    is_less: &F,
+    num_threads: usize,
+) where
+    T: PSortable,
+    F: PLess<T>,
+{
+    let stripe_len = (v.len() + num_threads - 1) / num_threads;
+    let positions = classify(v, buffer, oracle, gs, is_less, stripe_len);
+    // SAFETY: the positions were just computed from the oracle
+    unsafe { move_to_buckets(v, buffer, oracle, &positions, stripe_len) };
+}
+
+/// Parallel version of [super::classify] with stripes of `stripe_len` elements.
+///
+/// Returns the position of the first element of each bucket for every stripe, each stripe
+/// writes the elements of a bucket behind the ones of all previous stripes.
+pub(crate) fn classify<T, F>(
+    v: &[T],
+    buffer: &mut [MaybeUninit<T>],
+    oracle: &mut [u8],
+    gs: &mut GlobalStorage<T, F>,
+    is_less: &F,
+    stripe_len: usize,
+) -> Vec<[usize; MAX_BUCKETS]>
+where
+    T: PSortable,
+    F: PLess<T>,
+{
+    // The sample is much smaller than the input, so it is sorted sequentially
+    let mut sorting_callback =
+        |v: &mut [T], gs: &mut GlobalStorage<T, F>| sequential(v, gs, is_less);
+    // SAFETY: see stable::classify(), the copies are overwritten in move_to_buckets()
+    v.par_chunks(stripe_len)
+        .zip(buffer.par_chunks_mut(stripe_len))
+        .for_each(|(stripe, buffer)| unsafe {
+            copy_to_buffer(stripe, buffer);
+        });
+    // SAFETY: the buffer was initialized above
+    let copy = unsafe { &mut *(buffer as *mut [MaybeUninit<T>] as *mut [T]) };
+    get_splitters(copy, (), gs, &mut sorting_callback, is_less);
+
+    gs.classifier.build();
+    debug_assert!(gs.classifier.test_classification(v, is_less));
+
+    let num_buckets = gs.num_buckets;
+    let classifier = &gs.classifier;
+    let elements_per_stripe: Vec<[usize; MAX_BUCKETS]> = v
+        .par_chunks(stripe_len)
+        .zip(oracle.par_chunks_mut(stripe_len))
+        .map(|(stripe, oracle)| {
+            let mut elements_per_bucket = [0; MAX_BUCKETS];
+            classifier.classify_oracle(stripe, oracle, &mut elements_per_bucket[..num_buckets]);
+            elements_per_bucket
+        })
+        .collect();
+
+    let elements_per_bucket =
+        elements_per_stripe
+            .iter()
+            .fold([0usize; MAX_BUCKETS], |mut acc, elements| {
+                for i in 0..num_buckets {
+                    acc[i] += elements[i];
+                }
+                acc
+            });
+    calculate_bucket_boundaries(&mut gs.bucket_boundaries, num_buckets, &elements_per_bucket);
+
+    let mut positions = Vec::with_capacity(elements_per_stripe.len());
+    let mut next_positions = [0; MAX_BUCKETS];
+    next_positions[..num_buckets].copy_from_slice(&gs.bucket_boundaries[..num_buckets]);
+    for elements in &elements_per_stripe {
+        positions.push(next_positions);
+        for i in 0..num_buckets {
+            next_positions[i] += elements[i];
+        }
+    }
+    positions
+}
+
+/// Parallel version of [super::move_to_buckets] with stripes of `stripe_len` elements.
+///
+/// # Safety
+/// `positions` must be computed from `oracle` like in [classify].
+pub(crate) unsafe fn move_to_buckets<T>(
+    v: &mut [T],
+    buffer: &mut [MaybeUninit<T>],
+    oracle: &[u8],
+    positions: &[[usize; MAX_BUCKETS]],
+    stripe_len: usize,
+) where
+    T: Send,
+{
+    let buffer_ptr = BufferPtr(buffer.as_mut_ptr());
+    v.par_chunks_mut(stripe_len)
+        .zip(oracle.par_chunks(stripe_len))
+        .zip(positions.par_iter())
+        .for_each(|((stripe, oracle), &positions)| {
+            let mut positions = positions;
+            // SAFETY: the positions of a stripe end where the ones of the next stripe start, so
+            // every position is written exactly once
+            unsafe { scatter(stripe, oracle, buffer_ptr.get(), &mut positions) };
+        });
+    v.par_chunks_mut(stripe_len)
+        .zip(buffer.par_chunks_mut(stripe_len))
+        .for_each(|(stripe, buffer)| {
+            // SAFETY: every position of the buffer was written above, the elements of `v` were
+            // moved into the buffer, so they are overwritten without being dropped
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    buffer.as_ptr() as *const T,
+                    stripe.as_mut_ptr(),
+                    stripe.len(),
+                )
+            };
+        });
+}
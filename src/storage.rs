@@ -1,61 +1,106 @@
-use std::{fmt::Debug, mem::MaybeUninit};
+use std::{fmt::Debug, marker::PhantomData, mem::MaybeUninit, ptr};
 
 use rand::{rngs::StdRng, SeedableRng};
 
 use crate::{
-    bucket_pointers::BucketPointers,
-    classifier::Classifier,
-    constants::{BLOCK_SIZE, MAX_BUCKETS},
-    Less, Sortable,
+    bucket_pointers::BucketPointers, classifier::Classifier, config::Config,
+    constants::MAX_BUCKETS, util::move_from_slice, Less, Sortable,
 };
 
+/// Buffers elements that were moved out of the slice during local classification.
+///
+/// The buffers never own their elements: every element is moved back into the slice before the
+/// sort returns, so nothing is dropped here.
 #[derive(Debug)]
-pub struct BucketBuffers<T: Clone> {
-    buckets: Box<[[MaybeUninit<T>; BLOCK_SIZE]; MAX_BUCKETS]>,
+pub struct BucketBuffers<T> {
+    /// `MAX_BUCKETS` buffers of `block_size` elements each
+    buckets: Box<[MaybeUninit<T>]>,
     len: [usize; MAX_BUCKETS],
+    block_size: usize,
 }
 
+/// Holds the blocks that are currently moved around during block permutation.
+///
+/// Like [BucketBuffers], the swap buffers never own their elements. `len` is the number of
+/// elements that were moved in and not moved out again yet.
 #[derive(Debug)]
 pub(crate) struct SwapBuffers<T> {
-    swap: Box<[[MaybeUninit<T>; BLOCK_SIZE]; 2]>,
+    /// Two buffers of `block_size` elements each
+    swap: Box<[MaybeUninit<T>]>,
     len: [usize; 2],
-}
-
-impl<T: Sortable> Default for SwapBuffers<T> {
-    fn default() -> Self {
-        // SAFETY: array of unit data does no need initialization
-        let swap = unsafe { Box::new_uninit().assume_init() };
-        let len = [0; 2];
-        Self { swap, len }
-    }
+    block_size: usize,
 }
 
 impl<T: Sortable> SwapBuffers<T> {
-    pub fn fill_with(&mut self, index: usize, slice: &[T]) {
-        for (a, b) in self.swap[index].iter_mut().zip(slice) {
-            a.write(b.clone());
+    pub(crate) fn new(block_size: usize) -> Self {
+        Self {
+            swap: Box::new_uninit_slice(2 * block_size),
+            len: [0; 2],
+            block_size,
         }
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Moves the elements of `slice` into swap buffer `index`.
+    ///
+    /// # Safety
+    /// Afterwards `slice` holds bitwise copies, which must be overwritten before the slice is
+    /// used again.
+    pub unsafe fn fill_with(&mut self, index: usize, slice: &[T]) {
+        debug_assert!(slice.len() <= self.block_size);
+        ptr::copy_nonoverlapping(
+            slice.as_ptr(),
+            self.swap[index * self.block_size..].as_mut_ptr() as *mut T,
+            slice.len(),
+        );
         self.len[index] = slice.len();
     }
 
+    /// Moves the contents of swap buffer `index` into `dest`, leaving the swap buffer empty.
+    ///
+    /// # Safety
+    /// The previous elements of `dest` are overwritten without being dropped.
+    pub unsafe fn write_to(&mut self, index: usize, dest: &mut [T]) {
+        move_from_slice(dest, self.get(index));
+        self.len[index] = 0;
+    }
+
+    /// Forgets the contents of both swap buffers, they must have been moved out before
+    pub fn clear(&mut self) {
+        self.len = [0; 2];
+    }
+
+    /// Forgets the contents of swap buffer `index`, they must have been moved out before
+    pub fn clear_one(&mut self, index: usize) {
+        self.len[index] = 0;
+    }
+
     pub fn get(&self, index: usize) -> &[T] {
-        // SAFETY: len must be set correctly in fill_with()
+        let start = index * self.block_size;
+        // SAFETY: len must be set correctly in fill_with() and write_to()
         unsafe {
-            &*(&self.swap[index][0..self.len[index]] as *const [MaybeUninit<T>] as *const [T])
+            &*(&self.swap[start..start + self.len[index]] as *const [MaybeUninit<T>]
+                as *const [T])
         }
     }
 }
 
-impl<T: Sortable> Default for BucketBuffers<T> {
-    fn default() -> Self {
-        // SAFETY: array of unit data does no need initialization
-        let buckets = unsafe { Box::new_uninit().assume_init() };
-        let len = [0; MAX_BUCKETS];
-        Self { buckets, len }
+impl<T> BucketBuffers<T> {
+    pub(crate) fn new(block_size: usize) -> Self {
+        Self {
+            buckets: Box::new_uninit_slice(MAX_BUCKETS * block_size),
+            len: [0; MAX_BUCKETS],
+            block_size,
+        }
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
     }
-}
 
-impl<T: Clone> BucketBuffers<T> {
     pub(crate) fn clear_buckets(&mut self) {
         for i in self.len.iter_mut() {
             *i = 0;
@@ -64,7 +109,8 @@ impl<T: Clone> BucketBuffers<T> {
 
     #[allow(unused)]
     pub fn push(&mut self, index: usize, elem: T) {
-        self.buckets[index][self.len[index]].write(elem);
+        assert!(self.len[index] < self.block_size);
+        self.buckets[index * self.block_size + self.len[index]].write(elem);
         self.len[index] += 1;
     }
 
@@ -72,29 +118,39 @@ impl<T: Clone> BucketBuffers<T> {
         self.len[index]
     }
 
+    /// The memory of all buckets, to be used as scratch space while no element is buffered,
+    /// i.e. between partitioning steps.
+    pub fn scratch(&mut self) -> &mut [MaybeUninit<T>] {
+        &mut self.buckets
+    }
+
+    /// Forgets the contents of bucket `index`, they must have been moved out before
     pub fn clear(&mut self, index: usize) {
-        for i in 0..self.len[index] {
-            unsafe { self.buckets[index][i].assume_init_drop() };
-        }
         self.len[index] = 0;
     }
 
     pub fn get(&self, index: usize) -> &[T] {
+        let start = index * self.block_size;
         // SAFETY: len must be set correctly in clear() and unchecked_push()
         unsafe {
-            &*(&self.buckets[index][0..self.len[index]] as *const [MaybeUninit<T>] as *const [T])
+            &*(&self.buckets[start..start + self.len[index]] as *const [MaybeUninit<T>]
+                as *const [T])
         }
     }
 
-    pub unsafe fn unchecked_push(&mut self, index: usize, elem: T) -> usize {
-        // SAFETY: idx < MAX_BUCKETS && elem_idx <= BLOCK_SIZE
-        // => unchecked_push(idx) may only be called BLOCK_SIZE
+    /// Moves `elem` into bucket `index`, the caller must treat `elem` as moved out afterwards.
+    pub unsafe fn unchecked_push(&mut self, index: usize, elem: *const T) -> usize {
+        // SAFETY: idx < MAX_BUCKETS && elem_idx <= block_size
+        // => unchecked_push(idx) may only be called block_size
         // times before clear(idx) must be called
         let mut elem_idx = *self.len.get_unchecked(index);
-        self.buckets
-            .get_unchecked_mut(index)
-            .get_unchecked_mut(elem_idx)
-            .write(elem);
+        ptr::copy_nonoverlapping(
+            elem,
+            self.buckets
+                .get_unchecked_mut(index * self.block_size + elem_idx)
+                .as_mut_ptr(),
+            1,
+        );
 
         elem_idx += 1;
         *self.len.get_unchecked_mut(index) = elem_idx;
@@ -102,30 +158,59 @@ impl<T: Clone> BucketBuffers<T> {
     }
 }
 
-impl<T: Clone> Drop for BucketBuffers<T> {
-    fn drop(&mut self) {
-        for i in 0..MAX_BUCKETS {
-            self.clear(i);
+/// The allocations of a [LocalStorage], which are kept between sorts by a [crate::Sorter].
+#[derive(Debug)]
+pub(crate) struct Buffers<T> {
+    bucket_buffers: BucketBuffers<T>,
+    swap_buffers: SwapBuffers<T>,
+    rng: Ips4oRng,
+}
+
+impl<T: Sortable> Buffers<T> {
+    pub(crate) fn new(block_size: usize) -> Self {
+        Self {
+            bucket_buffers: BucketBuffers::new(block_size),
+            swap_buffers: SwapBuffers::new(block_size),
+            rng: Default::default(),
         }
     }
 }
 
+/// Buffers that are not used by a running sort
+pub(crate) type BufferPool<T> = Vec<Buffers<T>>;
+
+/// Takes buffers with the given block size out of `pool`, or allocates new ones.
+fn take_buffers<T: Sortable>(pool: &mut BufferPool<T>, block_size: usize) -> Buffers<T> {
+    match pool
+        .iter()
+        .rposition(|b| b.bucket_buffers.block_size() == block_size)
+    {
+        Some(i) => pool.swap_remove(i),
+        None => Buffers::new(block_size),
+    }
+}
+
+/// `C` classifies the elements during partitioning, it is a [Classifier] with splitters
+/// for sorting by comparisons. `F` is the function the classifier uses. `B` buffers the rows
+/// that are moved along with the elements, see [crate::payload::Payload].
 #[derive(Debug)]
-pub(crate) struct LocalStorage<'a, T, F>
+pub(crate) struct LocalStorage<'a, T, F, C = Classifier<'a, T, F>, B = ()>
 where
     T: Sortable,
-    F: Less<T>,
 {
     pub bucket_buffers: BucketBuffers<T>,
     pub swap_buffers: SwapBuffers<T>,
+    pub payload_buffers: B,
 
-    pub classifier: Classifier<'a, T, F>,
+    pub classifier: C,
     pub bucket_pointers: BucketPointers,
     pub bucket_boundaries: BucketBoundaries,
     pub elements_written_per_bucket: [usize; MAX_BUCKETS],
     /// Number of buckets, with equal buckets; "length" of bucket_pointers and bucket_boundaries[1..]
     pub num_buckets: usize,
     pub rng: Ips4oRng,
+    pub config: Config,
+    function: PhantomData<fn() -> &'a F>,
 }
 
 impl<'a, T, F> LocalStorage<'a, T, F>
@@ -133,22 +218,80 @@ where
     T: Sortable,
     F: Less<T>,
 {
-    pub(crate) fn new(is_less: &'a F) -> Self {
+    pub(crate) fn new(is_less: &'a F, config: &Config) -> Self {
+        Self::with_classifier(Classifier::new(is_less), config)
+    }
+
+    /// Like [Self::new], but reuses buffers from `pool`, see [Self::into_buffers].
+    pub(crate) fn new_in(is_less: &'a F, config: &Config, pool: &mut BufferPool<T>) -> Self {
+        let buffers = take_buffers(pool, config.block_size);
+        Self::with_buffers(Classifier::new(is_less), buffers, (), config)
+    }
+}
+
+impl<'a, T, F, B> LocalStorage<'a, T, F, Classifier<'a, T, F>, B>
+where
+    T: Sortable,
+    F: Less<T>,
+{
+    /// Like [LocalStorage::new], with buffers for the rows of a payload
+    pub(crate) fn with_payload(is_less: &'a F, payload_buffers: B, config: &Config) -> Self {
+        let buffers = Buffers::new(config.block_size);
+        Self::with_buffers(Classifier::new(is_less), buffers, payload_buffers, config)
+    }
+}
+
+impl<'a, T, F, C> LocalStorage<'a, T, F, C>
+where
+    T: Sortable,
+{
+    pub(crate) fn with_classifier(classifier: C, config: &Config) -> Self {
+        Self::with_buffers(classifier, Buffers::new(config.block_size), (), config)
+    }
+}
+
+impl<'a, T, F, C, B> LocalStorage<'a, T, F, C, B>
+where
+    T: Sortable,
+{
+    fn with_buffers(
+        classifier: C,
+        buffers: Buffers<T>,
+        payload_buffers: B,
+        config: &Config,
+    ) -> Self {
+        debug_assert_eq!(buffers.bucket_buffers.block_size(), config.block_size);
         Self {
-            classifier: Classifier::new(is_less),
+            classifier,
             bucket_pointers: core::array::from_fn(|_| Default::default()),
             bucket_boundaries: [0; MAX_BUCKETS + 1],
             elements_written_per_bucket: [0; MAX_BUCKETS],
-            bucket_buffers: Default::default(),
-            swap_buffers: Default::default(),
+            bucket_buffers: buffers.bucket_buffers,
+            swap_buffers: buffers.swap_buffers,
+            payload_buffers,
             num_buckets: Default::default(),
-            rng: Default::default(),
+            rng: buffers.rng,
+            config: *config,
+            function: PhantomData,
+        }
+    }
+
+    /// Returns the buffers, so that a later sort can reuse them
+    pub(crate) fn into_buffers(mut self) -> Buffers<T> {
+        // Partitioning moves all elements back into the slice, even if it panics
+        self.bucket_buffers.clear_buckets();
+        self.swap_buffers.clear();
+        Buffers {
+            bucket_buffers: self.bucket_buffers,
+            swap_buffers: self.swap_buffers,
+            rng: self.rng,
         }
     }
 }
 
 pub(crate) type BucketBoundaries = [usize; MAX_BUCKETS + 1];
-pub(crate) type GlobalStorage<'a, T, F> = LocalStorage<'a, T, F>;
+pub(crate) type GlobalStorage<'a, T, F, C = Classifier<'a, T, F>, B = ()> =
+    LocalStorage<'a, T, F, C, B>;
 
 #[derive(Debug)]
 pub(crate) struct Ips4oRng {
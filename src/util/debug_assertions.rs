@@ -1,6 +1,6 @@
 use crate::{
-    classifier::Classifier,
-    constants::{BLOCK_SIZE, MAX_BUCKETS},
+    classifier::{Classifier, Classify},
+    constants::MAX_BUCKETS,
     Less, Sortable,
 };
 
@@ -34,41 +34,45 @@ where
         }
         true
     }
+}
 
-    /// After the local classification phase a stripe should consist of correctly classified blocks followed by empty blocks
-    /// In the parallel case, the empty blocks must be swapped to ends of buckets, see [parallel::empty_block_movement::move_empty_blocks]
-    /// Assumes that [Self::classify_single_element] works correctly, as that should be tested seperately, see [Self::test_classification]
-    pub(crate) fn test_stripe_classification(
-        &self,
-        stripe: &[T],
-        elements_per_bucket: &[usize],
-        elements_written: usize,
-    ) -> bool {
-        let blocks = stripe.chunks(BLOCK_SIZE);
-        let mut elements_tested_per_bucket = [0; MAX_BUCKETS];
-        let mut total_elements_tested = 0;
-        for block in blocks {
-            if elements_written == total_elements_tested {
-                break;
-            }
-            let bucket_index = self.classify_single_element(&block[0]);
-            let elem_classified_correctly = |v| self.classify_single_element(v) == bucket_index;
-            let block_classified_correctly = block.iter().all(elem_classified_correctly);
+/// After the local classification phase a stripe should consist of correctly classified blocks followed by empty blocks
+/// In the parallel case, the empty blocks must be swapped to ends of buckets, see [parallel::empty_block_movement::move_empty_blocks]
+/// Assumes that [Classify::classify_single_element] works correctly, as that should be tested seperately, see [Classifier::test_classification]
+pub(crate) fn test_stripe_classification<T, C>(
+    classifier: &C,
+    stripe: &[T],
+    elements_per_bucket: &[usize],
+    elements_written: usize,
+    block_size: usize,
+) -> bool
+where
+    C: Classify<T>,
+{
+    let blocks = stripe.chunks(block_size);
+    let mut elements_tested_per_bucket = [0; MAX_BUCKETS];
+    let mut total_elements_tested = 0;
+    for block in blocks {
+        if elements_written == total_elements_tested {
+            break;
+        }
+        let bucket_index = classifier.classify_single_element(&block[0]);
+        let elem_classified_correctly = |v| classifier.classify_single_element(v) == bucket_index;
+        let block_classified_correctly = block.iter().all(elem_classified_correctly);
 
-            if !block_classified_correctly {
-                return false;
-            }
-            elements_tested_per_bucket[bucket_index] += BLOCK_SIZE;
-            total_elements_tested += BLOCK_SIZE;
+        if !block_classified_correctly {
+            return false;
         }
-        for (elements, elements_tested) in elements_per_bucket
-            .iter()
-            .zip(elements_tested_per_bucket.iter())
-        {
-            if *elements != elements_tested + elements % BLOCK_SIZE {
-                return false;
-            }
+        elements_tested_per_bucket[bucket_index] += block_size;
+        total_elements_tested += block_size;
+    }
+    for (elements, elements_tested) in elements_per_bucket
+        .iter()
+        .zip(elements_tested_per_bucket.iter())
+    {
+        if *elements != elements_tested + elements % block_size {
+            return false;
         }
-        true
     }
+    true
 }
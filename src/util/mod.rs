@@ -1,6 +1,8 @@
-mod debug_assertions;
+pub(crate) mod debug_assertions;
 
-use crate::{constants::BLOCK_SIZE, storage::LocalStorage, Less, Sortable};
+use std::ptr;
+
+use crate::{classifier::Classify, storage::LocalStorage, Sortable};
 
 #[macro_export]
 macro_rules! is_less_to_compare {
@@ -30,13 +32,13 @@ macro_rules! debug {
     };
 }
 
-pub(crate) fn test_block_permutation<T, F>(v: &[T], ls: &LocalStorage<T, F>) -> bool
+pub(crate) fn test_block_permutation<T, F, C, B>(v: &[T], ls: &LocalStorage<T, F, C, B>) -> bool
 where
     T: Sortable,
-    F: Less<T>,
+    C: Classify<T>,
 {
     for i in 0..ls.num_buckets {
-        let bucket_start = ls.bucket_boundaries[i] - ls.bucket_boundaries[i] % BLOCK_SIZE;
+        let bucket_start = ls.bucket_boundaries[i] - ls.bucket_boundaries[i] % ls.config.block_size;
         let (write, _read) = ls.bucket_pointers[i].fetch();
         for val in v.iter().take(write).skip(bucket_start) {
             let bucket = ls.classifier.classify_single_element(val);
@@ -49,10 +51,10 @@ where
     true
 }
 
-pub(crate) fn test_cleanup_margins<T, F>(v: &[T], ls: &LocalStorage<T, F>) -> bool
+pub(crate) fn test_cleanup_margins<T, F, C, B>(v: &[T], ls: &LocalStorage<T, F, C, B>) -> bool
 where
     T: Sortable,
-    F: Less<T>,
+    C: Classify<T>,
 {
     for i in 0..ls.num_buckets {
         for (j, val) in v
@@ -63,7 +65,7 @@ where
         {
             let bucket = ls.classifier.classify_single_element(val);
             if bucket != i {
-                println!("cleanup margins failed in bucket {i} at index {j} with element {:?} which gets classified as bucket {bucket}", v[j]);
+                println!("cleanup margins failed in bucket {i} at index {j} with an element which gets classified as bucket {bucket}");
                 return false;
             }
         }
@@ -71,8 +73,33 @@ where
     true
 }
 
-pub(crate) fn round_up_to_block_size(x: usize) -> usize {
-    ((x + BLOCK_SIZE - 1) / BLOCK_SIZE) * BLOCK_SIZE
+/// Moves the elements of `src` into `dest`, like `clone_from_slice` but without cloning.
+///
+/// # Safety
+/// The previous elements of `dest` are overwritten without being dropped and `src` holds bitwise
+/// copies afterwards, so the caller has to make sure that every element ends up exactly once
+/// in the sorted slice.
+pub(crate) unsafe fn move_from_slice<T>(dest: &mut [T], src: &[T]) {
+    assert_eq!(dest.len(), src.len());
+    ptr::copy_nonoverlapping(src.as_ptr(), dest.as_mut_ptr(), dest.len());
+}
+
+/// Turns `indices`, which moves the element at `indices[i]` to position `i`, into swaps: swapping
+/// position `i` with `indices[i]` for every `i` in ascending order applies the permutation.
+///
+/// Follows the cycles of the permutation like `slice::sort_by_cached_key` does.
+pub(crate) fn permutation_to_swaps(indices: &mut [usize]) {
+    for i in 0..indices.len() {
+        let mut index = indices[i];
+        while index < i {
+            index = indices[index];
+        }
+        indices[i] = index;
+    }
+}
+
+pub(crate) fn round_up_to_block_size(x: usize, block_size: usize) -> usize {
+    ((x + block_size - 1) / block_size) * block_size
 }
 
 #[cfg(test)]
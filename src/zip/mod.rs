@@ -0,0 +1,144 @@
+//! Sorts a slice of keys and moves the rows of payload columns along.
+//!
+//! The keys are partitioned in place like by [crate::sort]. Whenever the classification, the
+//! block permutation or the cleanup of the margins moves a key, the rows of the payload columns
+//! are moved the same way through bucket and swap buffers of their own, see [Payload]. So the
+//! rows stay together without building pairs, and every column only needs the buffers of a
+//! block per bucket.
+
+pub(crate) mod parallel;
+
+use crate::{
+    base_case::heapsort::heapsort_by_index,
+    classifier::Classifier,
+    config::Config,
+    is_less_to_compare,
+    payload::{base_case_sort_rows, IntoPayload, Payload},
+    sequential::{buckets_to_sort, get_splitters, partition_blocks, recursion_depth_limit},
+    storage::LocalStorage,
+    Less, Sortable,
+};
+
+/// Payload columns that are moved along with the keys by [crate::sort_zip_columns].
+///
+/// Implemented for mutable slices and for tuples of up to four columns, which can be nested for
+/// more.
+pub trait Columns: IntoPayload {}
+
+impl<V> Columns for &mut [V] {}
+
+macro_rules! impl_columns_for_tuple {
+    ($($column:ident),+) => {
+        impl<$($column: Columns),+> Columns for ($($column,)+) {}
+    };
+}
+
+impl_columns_for_tuple!(A);
+impl_columns_for_tuple!(A, B);
+impl_columns_for_tuple!(A, B, C);
+impl_columns_for_tuple!(A, B, C, D);
+
+/// A local storage with buffers for the rows of `P`
+pub(crate) type ZipStorage<'a, K, F, P> =
+    LocalStorage<'a, K, F, Classifier<'a, K, F>, <P as Payload>::Buffers>;
+
+pub(crate) fn zip_sequential_ips4o<K, P, F>(keys: &mut [K], rows: P, is_less: &F, config: &Config)
+where
+    K: Sortable,
+    P: Payload,
+    F: Less<K>,
+{
+    let payload_buffers = P::new_buffers(config.block_size);
+    let mut ls = LocalStorage::with_payload(is_less, payload_buffers, config);
+    zip_sequential(keys, rows, &mut ls, is_less);
+}
+
+pub(crate) fn zip_sequential<K, P, F>(
+    keys: &mut [K],
+    rows: P,
+    ls: &mut ZipStorage<K, F, P>,
+    is_less: &F,
+) where
+    K: Sortable,
+    P: Payload,
+    F: Less<K>,
+{
+    if keys.len() <= 2 * ls.config.base_case_size {
+        base_case_sort_rows(keys, rows, is_less);
+        return;
+    }
+    zip_seq_recurse(keys, rows, ls, is_less, recursion_depth_limit(keys.len()));
+}
+
+/// Entry point for sequential recursion, see [crate::sequential::seq_recurse]
+pub(crate) fn zip_seq_recurse<K, P, F>(
+    keys: &mut [K],
+    rows: P,
+    ls: &mut ZipStorage<K, F, P>,
+    is_less: &F,
+    depth_limit: usize,
+) where
+    K: Sortable,
+    P: Payload,
+    F: Less<K>,
+{
+    debug_assert!(keys.len() > 2 * ls.config.base_case_size);
+    if depth_limit == 0 || !partition(keys, rows, ls, is_less) {
+        zip_heapsort(keys, rows, is_less);
+        return;
+    }
+
+    // Final base cases were executed in cleanup step, so we're done here
+    if keys.len() <= ls.config.single_level_threshold() {
+        debug_assert!(keys.is_sorted_by(is_less_to_compare!(is_less)));
+        return;
+    }
+    let bucket_boundaries = ls.bucket_boundaries;
+    let base_case_size = ls.config.base_case_size;
+    for i in buckets_to_sort(ls.num_buckets, ls.classifier.equal_buckets) {
+        let range = bucket_boundaries[i]..bucket_boundaries[i + 1];
+        // Smaller buckets were sorted in cleanup_margins()
+        if range.len() > 2 * base_case_size {
+            let bucket_rows = rows.skip(range.start);
+            zip_seq_recurse(&mut keys[range], bucket_rows, ls, is_less, depth_limit - 1);
+        }
+    }
+}
+
+/// Like [crate::sequential::partition], but the rows are moved along with the keys
+fn partition<K, P, F>(keys: &mut [K], rows: P, ls: &mut ZipStorage<K, F, P>, is_less: &F) -> bool
+where
+    K: Sortable,
+    P: Payload,
+    F: Less<K>,
+{
+    let mut sorting_callback =
+        |sample: &mut [K], ls: &mut ZipStorage<K, F, P>| zip_sequential(sample, rows, ls, is_less);
+    get_splitters(keys, rows, ls, &mut sorting_callback, is_less);
+
+    ls.classifier.build();
+    debug_assert!(ls.classifier.test_classification(keys, is_less));
+
+    let is_last_level = keys.len() <= ls.config.single_level_threshold();
+    partition_blocks(keys, rows, ls, is_less, is_last_level)
+}
+
+/// Sorts the keys with heapsort and swaps the rows along, the fallback if partitioning makes no
+/// progress, see [crate::base_case::heapsort::heapsort].
+pub(crate) fn zip_heapsort<K, P, F>(keys: &mut [K], rows: P, is_less: &F)
+where
+    P: Payload,
+    F: Less<K>,
+{
+    let len = keys.len();
+    heapsort_by_index(
+        keys,
+        len,
+        |keys, a, b| is_less(&keys[a], &keys[b]),
+        |keys, a, b| {
+            keys.swap(a, b);
+            // SAFETY: the rows have as many positions as the keys
+            unsafe { rows.swap(a, b) };
+        },
+    );
+}
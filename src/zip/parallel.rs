@@ -0,0 +1,118 @@
+use crate::{
+    config::Config,
+    parallel::{
+        partition_blocks,
+        scheduler::{buckets_left, par_recurse, ParallelSort, StoragePool},
+        split_at_bounds,
+    },
+    payload::Payload,
+    sequential::{get_splitters, recursion_depth_limit},
+    storage::LocalStorage,
+    zip::{zip_heapsort, zip_seq_recurse, zip_sequential, ZipStorage},
+    PLess, PSortable,
+};
+
+pub(crate) fn zip_parallel_ips4o<K, P, F>(
+    keys: &mut [K],
+    rows: P,
+    is_less: &F,
+    config: &Config,
+    num_threads: usize,
+) where
+    K: PSortable,
+    P: Payload,
+    F: PLess<K>,
+{
+    // initialize storage
+    let new_storage = || {
+        let payload_buffers = P::new_buffers(config.block_size);
+        LocalStorage::with_payload(is_less, payload_buffers, config)
+    };
+    let storages = StoragePool::new(Vec::new(), &new_storage);
+
+    let depth_limit = recursion_depth_limit(keys.len());
+    let task = (keys, rows, depth_limit);
+    par_recurse(&Zip { is_less }, task, &storages, num_threads);
+}
+
+/// The sort of keys with payload rows, its tasks are buckets with their rows and remaining
+/// recursion depth
+struct Zip<'a, F> {
+    is_less: &'a F,
+}
+
+impl<'a, 'v, K, P, F> ParallelSort<(&'v mut [K], P, usize)> for Zip<'a, F>
+where
+    K: PSortable,
+    P: Payload,
+    F: PLess<K>,
+{
+    type Storage = ZipStorage<'a, K, F, P>;
+
+    fn len(&self, task: &(&'v mut [K], P, usize)) -> usize {
+        task.0.len()
+    }
+
+    /// See [zip_seq_recurse]
+    fn partition(
+        &self,
+        (keys, rows, depth_limit): (&'v mut [K], P, usize),
+        lss: &mut [Self::Storage],
+        gs: &mut Self::Storage,
+    ) -> Vec<(usize, (&'v mut [K], P, usize))> {
+        debug_assert!(keys.len() > 2 * gs.config.base_case_size);
+        if depth_limit == 0 || !partition(keys, rows, lss, gs, self.is_less) {
+            zip_heapsort(keys, rows, self.is_less);
+            return Vec::new();
+        }
+
+        // Final base cases were executed in cleanup step, so we're done here
+        if keys.len() <= gs.config.single_level_threshold() {
+            return Vec::new();
+        }
+        let bounds = gs.bucket_boundaries;
+        let mut left = buckets_left(gs, gs.classifier.equal_buckets)
+            .into_iter()
+            .peekable();
+        split_at_bounds(keys, &bounds[..gs.num_buckets])
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| left.next_if_eq(i).is_some())
+            .map(|(i, bucket)| (bounds[i], (bucket, rows.skip(bounds[i]), depth_limit - 1)))
+            .collect()
+    }
+
+    fn sort_sequential(
+        &self,
+        (keys, rows, depth_limit): (&'v mut [K], P, usize),
+        ls: &mut Self::Storage,
+    ) {
+        zip_seq_recurse(keys, rows, ls, self.is_less, depth_limit);
+    }
+}
+
+/// Like [crate::parallel::partition], but the rows are moved along with the keys
+fn partition<'a, K, P, F>(
+    keys: &mut [K],
+    rows: P,
+    lss: &mut [ZipStorage<'a, K, F, P>],
+    gs: &mut ZipStorage<'a, K, F, P>,
+    is_less: &F,
+) -> bool
+where
+    K: PSortable,
+    P: Payload,
+    F: PLess<K>,
+{
+    // The sample is much smaller than the input, so it is sorted sequentially
+    let mut sorting_callback = |sample: &mut [K], gs: &mut ZipStorage<'a, K, F, P>| {
+        zip_sequential(sample, rows, gs, is_less)
+    };
+    get_splitters(keys, rows, gs, &mut sorting_callback, is_less);
+
+    gs.classifier.build();
+    debug_assert!(gs.classifier.test_classification(keys, is_less));
+
+    let is_last_level = keys.len() <= gs.config.single_level_threshold();
+    partition_blocks(keys, rows, lss, gs, is_less, is_last_level)
+}